@@ -0,0 +1,152 @@
+use chrono::DateTime;
+use rocket::{
+    http::Status,
+    serde::json::{json, Value},
+    State,
+};
+
+use crate::{
+    db::MongodbBackend,
+    error::Error,
+    models::{
+        audit::{AuditEntry, AuditQuery},
+        revocation::NewRevocation,
+    },
+    secure::{
+        guards::{AdminGuard, SignedJson},
+        revocation::RevocationList,
+    },
+    server::{
+        config::UsageConfig,
+        reload::{ReloadStatus, Reloader},
+    },
+};
+
+/// Parse an RFC 3339 time of the audit query into a unix timestamp
+fn parse_time(time: Option<&str>) -> Result<Option<i64>, Error> {
+    time.map(|time| {
+        DateTime::parse_from_rfc3339(time)
+            .map(|time| time.timestamp())
+            .map_err(|_| Error::BadRequest(format!("invalid RFC 3339 time: {}", time)))
+    })
+    .transpose()
+}
+
+#[get("/cache")]
+pub async fn cache_stats(_guard: AdminGuard, backend: &State<MongodbBackend>) -> (Status, Value) {
+    // hit/miss counters of the authenticated user cache
+    super::generic_response(Ok(backend.cache_stats()))
+}
+
+#[post("/revocations", format = "json", data = "<revocation>")]
+pub async fn revoke_key(
+    guard: AdminGuard,
+    revocation: SignedJson<NewRevocation>,
+    backend: &State<MongodbBackend>,
+    revocations: &State<RevocationList>,
+) -> (Status, Value) {
+    // effective at once here, on the other instances at their next poll
+    let revoked = backend
+        .insert_revocation(revocation.0, guard.email.clone())
+        .await
+        .inspect(|revocation| revocations.insert(revocation.clone()));
+    if let Ok(revocation) = &revoked {
+        let entry = AuditEntry::new(
+            "revoke_key",
+            &revocation.key_hash,
+            &guard.email,
+            &guard.ip.to_string(),
+        );
+        super::audit(backend, entry.diff(None, Some(revocation))).await;
+    }
+    super::generic_response(revoked)
+}
+
+#[get("/revocations")]
+pub async fn get_all_revocations(
+    _guard: AdminGuard,
+    backend: &State<MongodbBackend>,
+) -> (Status, Value) {
+    super::generic_response(backend.get_all_revocations().await)
+}
+
+#[get("/keys/usage")]
+pub async fn key_usage(
+    _guard: AdminGuard,
+    backend: &State<MongodbBackend>,
+    config: &State<UsageConfig>,
+) -> (Status, Value) {
+    // last use & requests over the rolling window of every key
+    super::generic_response(
+        backend
+            .get_key_usage_summaries(config.window_days as i64)
+            .await,
+    )
+}
+
+#[get("/keys/stale?<days>")]
+pub async fn stale_keys(
+    _guard: AdminGuard,
+    days: Option<u32>,
+    backend: &State<MongodbBackend>,
+    config: &State<UsageConfig>,
+) -> (Status, Value) {
+    let days = days.unwrap_or(config.stale_after_days);
+    super::generic_response(backend.get_stale_keys(days as i64).await)
+}
+
+#[get("/audit?<actor>&<target>&<since>&<until>")]
+pub async fn get_audit_log(
+    _guard: AdminGuard,
+    actor: Option<String>,
+    target: Option<String>,
+    since: Option<&str>,
+    until: Option<&str>,
+    backend: &State<MongodbBackend>,
+) -> (Status, Value) {
+    // who changed what & when, latest first
+    let query = match (parse_time(since), parse_time(until)) {
+        (Ok(since), Ok(until)) => AuditQuery {
+            actor,
+            target,
+            since,
+            until,
+        },
+        (Err(e), _) | (_, Err(e)) => return super::generic_response::<()>(Err(e)),
+    };
+    super::generic_response(backend.get_audit_entries(query).await)
+}
+
+#[get("/reload")]
+pub async fn last_reload(_guard: AdminGuard, reloader: &State<Reloader>) -> (Status, Value) {
+    // null until the settings are reloaded
+    super::generic_response(Ok(reloader.last_result()))
+}
+
+#[post("/reload")]
+pub async fn reload(
+    guard: AdminGuard,
+    reloader: &State<Reloader>,
+    backend: &State<MongodbBackend>,
+) -> (Status, Value) {
+    // the config files are read & the certificates parsed off the async workers
+    let reloader = reloader.inner().clone();
+    let result =
+        match rocket::tokio::task::spawn_blocking(move || reloader.reload("admin request")).await {
+            Ok(result) => result,
+            Err(_) => return super::generic_response::<()>(Err(Error::InternalError)),
+        };
+
+    let entry = AuditEntry::new(
+        "reload_settings",
+        "settings",
+        &guard.email,
+        &guard.ip.to_string(),
+    );
+    super::audit(backend, entry).await;
+
+    match result.status {
+        ReloadStatus::Rejected => (Status::UnprocessableEntity, json!(result)),
+        _ => json_response!(result),
+    }
+}
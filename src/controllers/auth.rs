@@ -0,0 +1,9 @@
+use rocket::{http::Status, serde::json::Value, State};
+
+use crate::secure::{guards::CredentialsGuard, jwt::TokenIssuer};
+
+#[post("/token")]
+pub async fn issue_token(guard: CredentialsGuard, issuer: &State<TokenIssuer>) -> (Status, Value) {
+    // exchange the api key (or signature/certificate) for a short lived access token
+    super::generic_response(issuer.issue(&guard.0.inner))
+}
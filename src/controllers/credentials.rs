@@ -0,0 +1,75 @@
+use rocket::{http::Status, serde::json::Value, State};
+
+use crate::{
+    db::MongodbBackend,
+    models::credentials::{NewPassword, OtpCode},
+    secure::{
+        attempts::FailedAttempts,
+        guards::{SignedJson, UserGuard},
+        totp::MfaPolicy,
+    },
+};
+
+#[put("/my/password", format = "json", data = "<new_password>")]
+pub async fn set_password(
+    guard: UserGuard,
+    new_password: SignedJson<NewPassword>,
+    backend: &State<MongodbBackend>,
+    attempts: &State<FailedAttempts>,
+) -> (Status, Value) {
+    let ip = guard.ip.to_string();
+    super::generic_response(
+        attempts
+            .check(
+                &guard.email,
+                &ip,
+                backend.set_password(&guard.email, new_password.0),
+            )
+            .await,
+    )
+}
+
+#[post("/my/totp")]
+pub async fn enrol_totp(
+    guard: UserGuard,
+    backend: &State<MongodbBackend>,
+    policy: &State<MfaPolicy>,
+) -> (Status, Value) {
+    super::generic_response(
+        backend
+            .start_totp_enrolment(&guard.email, &policy.issuer)
+            .await,
+    )
+}
+
+#[post("/my/totp/confirm", format = "json", data = "<otp>")]
+pub async fn confirm_totp(
+    guard: UserGuard,
+    otp: SignedJson<OtpCode>,
+    backend: &State<MongodbBackend>,
+    attempts: &State<FailedAttempts>,
+) -> (Status, Value) {
+    let ip = guard.ip.to_string();
+    let confirmed = backend.confirm_totp(&guard.email, &otp.0.code);
+    match attempts.check(&guard.email, &ip, confirmed).await {
+        Ok(recovery_codes) => json_response!("recovery_codes" => recovery_codes),
+        Err(e) => json_response!(e.to_status().code, e.to_string()),
+    }
+}
+
+#[post("/my/totp/disable", format = "json", data = "<otp>")]
+pub async fn disable_totp(
+    guard: UserGuard,
+    otp: SignedJson<OtpCode>,
+    backend: &State<MongodbBackend>,
+    attempts: &State<FailedAttempts>,
+) -> (Status, Value) {
+    // the second factor is needed to remove itself
+    let ip = guard.ip.to_string();
+    let verified = backend.verify_second_factor(&guard.email, &otp.0.code);
+    let result = match attempts.check(&guard.email, &ip, verified).await {
+        Ok(_) => backend.disable_totp(&guard.email).await,
+        Err(e) => Err(e),
+    };
+    super::generic_response(result)
+}
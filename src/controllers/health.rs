@@ -0,0 +1,38 @@
+use rocket::{
+    http::Status,
+    serde::json::{json, Value},
+    State,
+};
+
+use crate::{
+    db::MongodbBackend,
+    secure::revocation::RevocationList,
+    server::{health::Health, shutdown::GracefulShutdown},
+};
+
+#[get("/live")]
+pub fn live() -> (Status, Value) {
+    //! The server process answers; nothing else is checked
+    json_response!("status" => "alive")
+}
+
+#[get("/ready")]
+pub async fn ready(
+    health: &State<Health>,
+    shutdown: &State<GracefulShutdown>,
+    backend: &State<MongodbBackend>,
+    revocations: &State<RevocationList>,
+) -> (Status, Value) {
+    //! The server can serve requests: 200 when ready, 503 otherwise
+    if shutdown.is_draining() {
+        // no new requests should be routed to a server shutting down
+        return (Status::ServiceUnavailable, json!({"status": "draining"}));
+    }
+    let readiness = health.readiness(backend, revocations).await;
+    let status = if readiness.is_ready() {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, json!(readiness))
+}
@@ -0,0 +1,21 @@
+use rocket::{
+    http::{ContentType, Status},
+    State,
+};
+
+use crate::{
+    models::endpoint::EndpointHandler, secure::guards::MetricsGuard, server::metrics::Metrics,
+};
+
+#[get("/metrics")]
+pub fn metrics(
+    _guard: MetricsGuard,
+    metrics: &State<Metrics>,
+    handler: &State<EndpointHandler>,
+) -> (Status, (ContentType, String)) {
+    // Prometheus text exposition format
+    match metrics.render(handler.throttle_count()) {
+        Ok(body) => (Status::Ok, (ContentType::Plain, body)),
+        Err(e) => (e.to_status(), (ContentType::Plain, e.to_string())),
+    }
+}
@@ -1,11 +1,19 @@
+pub mod admin;
+pub mod auth;
+pub mod credentials;
+pub mod health;
 pub mod hellow;
 pub mod index;
+pub mod metrics;
+pub mod oauth;
+pub mod session;
+pub mod signup;
 pub mod users;
 
 use rocket::{http::Status, serde::json::Value};
 use serde::Serialize;
 
-use crate::error::Error;
+use crate::{db::MongodbBackend, error::Error, models::audit::AuditEntry};
 
 fn generic_response<T: Serialize>(result: Result<T, Error>) -> (Status, Value) {
     match result {
@@ -13,3 +21,13 @@ fn generic_response<T: Serialize>(result: Result<T, Error>) -> (Status, Value) {
         Err(e) => json_response!(e.to_status().code, e.to_string()),
     }
 }
+
+/// Append an admin change to the audit log.
+///
+/// The change is already done; a failed write is logged, not returned to the admin.
+async fn audit(backend: &MongodbBackend, entry: AuditEntry) {
+    let (action, target) = (entry.action.clone(), entry.target.clone());
+    if let Err(e) = backend.insert_audit_entry(entry).await {
+        log::error!("could not audit {} of {}: {}", action, target, e);
+    }
+}
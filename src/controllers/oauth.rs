@@ -0,0 +1,242 @@
+use rocket::{
+    form::Form,
+    http::Status,
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    serde::json::{json, Value},
+    State,
+};
+
+use crate::{
+    db::MongodbBackend,
+    models::{
+        audit::AuditEntry,
+        oauth::{NewOAuthClient, OAuthClient},
+    },
+    secure::{
+        guards::{client::ClientInfo, AdminGuard, SignedJson},
+        jwt::TokenIssuer,
+    },
+};
+
+/// OAuth2 client credentials sent in the `Authorization: Basic` header
+pub struct BasicCredentials(Option<(String, String)>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BasicCredentials {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let credentials = request
+            .headers()
+            .get_one("authorization")
+            .and_then(|header| header.trim().strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                decoded
+                    .split_once(':')
+                    .map(|(id, secret)| (id.to_string(), secret.to_string()))
+            });
+
+        Outcome::Success(Self(credentials))
+    }
+}
+
+pub use self::forms::{TokenForm, TokenRequest};
+
+// The FromForm derive of rocket 0.5.0-rc.2 allows the `private_in_public`
+// lint, a name the recent compilers warn about
+#[allow(renamed_and_removed_lints)]
+mod forms {
+    /// Token request of the client credentials grant (RFC 6749 section 4.4.2)
+    #[derive(FromForm)]
+    pub struct TokenRequest {
+        pub grant_type: String,
+        pub client_id: Option<String>,
+        pub client_secret: Option<String>,
+        pub scope: Option<String>,
+    }
+
+    /// Token introspection (RFC 7662) & revocation (RFC 7009) request
+    #[derive(FromForm)]
+    pub struct TokenForm {
+        pub token: String,
+        pub client_id: Option<String>,
+        pub client_secret: Option<String>,
+    }
+}
+
+/// OAuth2 error response (RFC 6749 section 5.2)
+fn oauth_error(status: Status, error: &str) -> (Status, Value) {
+    (status, json!({ "error": error }))
+}
+
+/// Authenticate the client from the Basic header, or else the form fields
+async fn authenticate_client(
+    backend: &MongodbBackend,
+    basic: BasicCredentials,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+) -> Result<OAuthClient, (Status, Value)> {
+    let (client_id, client_secret) = match (basic.0, client_id, client_secret) {
+        (Some(credentials), _, _) => credentials,
+        (None, Some(id), Some(secret)) => (id, secret),
+        _ => return Err(oauth_error(Status::Unauthorized, "invalid_client")),
+    };
+
+    match backend.get_oauth_client(&client_id).await {
+        Ok(client) if client.verify_secret(&client_secret) => Ok(client),
+        _ => Err(oauth_error(Status::Unauthorized, "invalid_client")),
+    }
+}
+
+#[post("/token", data = "<form>")]
+pub async fn token(
+    form: Form<TokenRequest>,
+    basic: BasicCredentials,
+    client_info: ClientInfo,
+    backend: &State<MongodbBackend>,
+    issuer: &State<TokenIssuer>,
+) -> (Status, Value) {
+    let form = form.into_inner();
+    if form.grant_type != "client_credentials" {
+        return oauth_error(Status::BadRequest, "unsupported_grant_type");
+    }
+
+    let client = match authenticate_client(backend, basic, form.client_id, form.client_secret).await
+    {
+        Ok(client) => client,
+        Err(e) => return e,
+    };
+    let scopes = match client.grant_scopes(form.scope.as_deref()) {
+        Some(scopes) => scopes,
+        None => return oauth_error(Status::BadRequest, "invalid_scope"),
+    };
+
+    // the client acts as its user, so the user's ip ACL applies
+    let user = match backend.get_user_from_email(&client.email).await {
+        Ok(user) if user.is_ip_allowed(&client_info.ip) => user,
+        Ok(_) => return oauth_error(Status::Forbidden, "access_denied"),
+        Err(_) => return oauth_error(Status::Unauthorized, "invalid_client"),
+    };
+
+    match issuer.issue_for_client(&user, &client.client_id, &scopes) {
+        Ok(token) => json_response!(
+            "access_token" => token.access_token,
+            "token_type" => token.token_type,
+            "expires_in" => token.expires_in,
+            "scope" => scopes.join(" ")
+        ),
+        Err(e) => json_response!(e.to_status().code, e.to_string()),
+    }
+}
+
+#[post("/introspect", data = "<form>")]
+pub async fn introspect(
+    form: Form<TokenForm>,
+    basic: BasicCredentials,
+    backend: &State<MongodbBackend>,
+    issuer: &State<TokenIssuer>,
+) -> (Status, Value) {
+    let form = form.into_inner();
+    if let Err(e) = authenticate_client(backend, basic, form.client_id, form.client_secret).await {
+        return e;
+    }
+
+    match issuer.validate(&form.token) {
+        Ok(claims) => json_response!(
+            "active" => true,
+            "sub" => claims.sub,
+            "client_id" => claims.client_id,
+            "scope" => claims.scope,
+            "token_type" => "Bearer",
+            "iat" => claims.iat,
+            "exp" => claims.exp,
+            "acl_digest" => claims.acl_digest
+        ),
+        Err(_) => json_response!("active" => false),
+    }
+}
+
+#[post("/revoke", data = "<form>")]
+pub async fn revoke(
+    form: Form<TokenForm>,
+    basic: BasicCredentials,
+    backend: &State<MongodbBackend>,
+    issuer: &State<TokenIssuer>,
+) -> (Status, Value) {
+    let form = form.into_inner();
+    let client = match authenticate_client(backend, basic, form.client_id, form.client_secret).await
+    {
+        Ok(client) => client,
+        Err(e) => return e,
+    };
+
+    // invalid tokens or tokens of other clients are ignored (RFC 7009 section 2.2)
+    if let Ok(claims) = issuer.validate(&form.token) {
+        if claims.client_id.as_deref() == Some(client.client_id.as_str()) {
+            issuer.revoke(&claims.jti, claims.exp);
+            if let Err(e) = backend.insert_revoked_token(claims.jti, claims.exp).await {
+                return json_response!(e.to_status().code, e.to_string());
+            }
+        }
+    }
+    json_response!({})
+}
+
+#[post("/clients", format = "json", data = "<new_client>")]
+pub async fn create_client(
+    guard: AdminGuard,
+    new_client: SignedJson<NewOAuthClient>,
+    backend: &State<MongodbBackend>,
+) -> (Status, Value) {
+    let created = backend
+        .insert_oauth_client(new_client.0, guard.ip.to_string(), guard.email.clone())
+        .await;
+    if let Ok(registered) = &created {
+        let client = &registered.client;
+        let entry = AuditEntry::new(
+            "create_oauth_client",
+            &client.client_id,
+            &guard.email,
+            &guard.ip.to_string(),
+        );
+        super::audit(backend, entry.diff(None, Some(client))).await;
+    }
+    super::generic_response(created)
+}
+
+#[get("/clients")]
+pub async fn get_all_clients(
+    _guard: AdminGuard,
+    backend: &State<MongodbBackend>,
+) -> (Status, Value) {
+    super::generic_response(backend.get_all_oauth_clients().await)
+}
+
+#[delete("/clients/<client_id>")]
+pub async fn delete_client(
+    guard: AdminGuard,
+    client_id: String,
+    backend: &State<MongodbBackend>,
+    issuer: &State<TokenIssuer>,
+) -> (Status, Value) {
+    let before = backend.get_oauth_client(&client_id).await.ok();
+    let entry = AuditEntry::new(
+        "delete_oauth_client",
+        &client_id,
+        &guard.email,
+        &guard.ip.to_string(),
+    );
+    let deleted = backend.delete_oauth_client(client_id.clone()).await;
+    if deleted.is_ok() {
+        // the tokens already issued to the client die with it
+        let exp = issuer.revoke_client(&client_id);
+        if let Err(e) = backend.insert_revoked_client(client_id, exp).await {
+            return json_response!(e.to_status().code, e.to_string());
+        }
+        super::audit(backend, entry.diff(before.as_ref(), None)).await;
+    }
+    super::generic_response(deleted)
+}
@@ -0,0 +1,152 @@
+use chrono::offset::Utc;
+use rocket::{
+    http::{CookieJar, Status},
+    serde::json::{Json, Value},
+    State,
+};
+use serde::Deserialize;
+
+use crate::{
+    db::MongodbBackend,
+    error::Error,
+    models::user::User,
+    secure::{
+        attempts::FailedAttempts,
+        guards::client::ClientInfo,
+        session::{LoginLimiter, Session, SessionManager},
+    },
+};
+
+/// Admin credentials: an api key, or an email & password;
+/// plus a TOTP or recovery code once a second factor is enrolled
+#[derive(Deserialize)]
+pub struct Login {
+    api_key: Option<String>,
+    email: Option<String>,
+    password: Option<String>,
+    otp: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct StepUp {
+    otp: String,
+}
+
+fn session_response(session: Session) -> (Status, Value) {
+    json_response!(
+        "email" => session.email,
+        "csrf_token" => session.csrf_token,
+        "expires_at" => session.expires_at,
+        "mfa_at" => session.mfa_at
+    )
+}
+
+/// Identify the user of the login, and when the second factor was given
+async fn authenticate_login(
+    backend: &MongodbBackend,
+    attempts: &FailedAttempts,
+    ip: &str,
+    login: Login,
+) -> Result<(User, Option<i64>), Error> {
+    let user = match (login.api_key, login.email, login.password) {
+        (Some(api_key), _, _) => backend
+            .get_user_from_api_key(api_key.trim())
+            .await
+            .map_err(|_| Error::UnauthenticatedUser)?,
+        (None, Some(email), Some(password)) => {
+            let user = backend.get_user_from_password(&email, &password);
+            attempts.check(&email, ip, user).await?
+        }
+        _ => return Err(Error::UnauthenticatedUser),
+    };
+
+    if !backend
+        .get_credentials(&user.email)
+        .await?
+        .has_second_factor()
+    {
+        return Ok((user, None));
+    }
+    let otp = login.otp.ok_or(Error::UnauthenticatedUser)?;
+    let verified = backend.verify_second_factor(&user.email, &otp);
+    attempts.check(&user.email, ip, verified).await?;
+    Ok((user, Some(Utc::now().timestamp())))
+}
+
+#[post("/login", format = "json", data = "<login>")]
+pub async fn login(
+    login: Json<Login>,
+    client_info: ClientInfo,
+    cookies: &CookieJar<'_>,
+    backend: &State<MongodbBackend>,
+    sessions: &State<SessionManager>,
+    limiter: &State<LoginLimiter>,
+    attempts: &State<FailedAttempts>,
+) -> (Status, Value) {
+    if let Err(e) = limiter.acquire(&client_info.ip) {
+        return json_response!(e.to_status().code, e.to_string());
+    }
+
+    // exchange the admin's credentials for a session cookie
+    let result =
+        match authenticate_login(backend, attempts, &client_info.ip, login.into_inner()).await {
+            Ok((user, mfa_at)) if user.is_admin() && user.is_ip_allowed(&client_info.ip) => {
+                sessions.start(cookies, &user.email, mfa_at)
+            }
+            Ok(_) => Err(Error::ForbiddenAccess),
+            Err(e) => Err(e),
+        };
+
+    match result {
+        Ok(session) => session_response(session),
+        Err(e) => json_response!(e.to_status().code, e.to_string()),
+    }
+}
+
+#[post("/mfa", format = "json", data = "<step_up>")]
+pub async fn step_up(
+    session: Session,
+    step_up: Json<StepUp>,
+    client_info: ClientInfo,
+    cookies: &CookieJar<'_>,
+    backend: &State<MongodbBackend>,
+    sessions: &State<SessionManager>,
+    attempts: &State<FailedAttempts>,
+) -> (Status, Value) {
+    // renew the session with a fresh second factor
+    let verified = backend.verify_second_factor(&session.email, &step_up.otp);
+    let result = match attempts
+        .check(&session.email, &client_info.ip, verified)
+        .await
+    {
+        Ok(_) => match sessions.end(cookies, &session, backend).await {
+            Ok(_) => sessions.start(cookies, &session.email, Some(Utc::now().timestamp())),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(session) => session_response(session),
+        Err(e) => json_response!(e.to_status().code, e.to_string()),
+    }
+}
+
+#[get("/")]
+pub async fn current_session(session: Session) -> (Status, Value) {
+    // the dashboard reads back its csrf token from here
+    session_response(session)
+}
+
+#[post("/logout")]
+pub async fn logout(
+    session: Session,
+    cookies: &CookieJar<'_>,
+    backend: &State<MongodbBackend>,
+    sessions: &State<SessionManager>,
+) -> (Status, Value) {
+    match sessions.end(cookies, &session, backend).await {
+        Ok(_) => json_response!({}),
+        Err(e) => json_response!(e.to_status().code, e.to_string()),
+    }
+}
@@ -0,0 +1,86 @@
+use rocket::{
+    http::Status,
+    serde::json::{Json, Value},
+    State,
+};
+
+use crate::{
+    db::MongodbBackend,
+    mailer::{Mail, Mailer},
+    models::{
+        signup::{NewSignup, SignupToken},
+        user::NewUser,
+    },
+    secure::{
+        guards::client::ClientInfo,
+        signup::{SignupLimiter, SignupTokens},
+    },
+    server::config::SignupConfig,
+};
+
+#[post("/", format = "json", data = "<signup>")]
+pub async fn signup(
+    signup: Json<NewSignup>,
+    client_info: ClientInfo,
+    backend: &State<MongodbBackend>,
+    mailer: &State<Box<dyn Mailer>>,
+    config: &State<SignupConfig>,
+    limiter: &State<SignupLimiter>,
+    tokens: &State<SignupTokens>,
+) -> (Status, Value) {
+    let signup = signup.into_inner();
+    if !signup.is_valid() {
+        return json_response!(400, "invalid email");
+    }
+    if let Err(e) = limiter.acquire(&client_info.ip) {
+        return json_response!(e.to_status().code, e.to_string());
+    }
+
+    let email = signup.email.clone();
+    match backend
+        .insert_signup(
+            signup,
+            client_info.ip,
+            config.token_ttl_secs,
+            config.resend_after_secs,
+            tokens,
+        )
+        .await
+    {
+        Ok(Some(token)) => {
+            let body = match &config.verify_url {
+                Some(url) => format!("Verify your account at {}?token={}", url, token),
+                None => format!("Your verification token: {}", token),
+            };
+            let mail = Mail {
+                to: email,
+                subject: "Verify your account".to_string(),
+                body,
+            };
+            if let Err(e) = mailer.send(&mail).await {
+                return json_response!(e.to_status().code, e.to_string());
+            }
+        }
+        // the reply does not tell whether the email already has an account,
+        // nor whether its token was just mailed
+        Ok(None) => (),
+        Err(e) => return json_response!(e.to_status().code, e.to_string()),
+    }
+
+    json_response!("message" => "check your email to verify the account")
+}
+
+#[post("/verify", format = "json", data = "<token>")]
+pub async fn verify(
+    token: Json<SignupToken>,
+    backend: &State<MongodbBackend>,
+    config: &State<SignupConfig>,
+) -> (Status, Value) {
+    // the verified account gets the ACL template of the config
+    let template = NewUser {
+        acl_allow_ips: config.acl_allow_ips.clone(),
+        acl_allow_endpoints: config.acl_allow_endpoints.clone(),
+        ..NewUser::default()
+    };
+    super::generic_response(backend.redeem_signup(&token.token, template).await)
+}
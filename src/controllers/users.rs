@@ -1,13 +1,12 @@
-use rocket::{
-    http::Status,
-    serde::json::{Json, Value},
-    State,
-};
+use rocket::{http::Status, serde::json::Value, State};
 
 use crate::{
     db::MongodbBackend,
-    models::user::NewUser,
-    secure::guards::auth::{AdminGuard, UserGuard},
+    models::{audit::AuditEntry, user::NewUser},
+    secure::guards::{
+        auth::{AdminGuard, UserGuard},
+        SecondFactorGuard, SignedJson,
+    },
 };
 
 #[get("/my")]
@@ -19,18 +18,23 @@ pub async fn get_current_user(guard: UserGuard) -> (Status, Value) {
 #[post("/", format = "json", data = "<new_user>")]
 pub async fn create_user(
     guard: AdminGuard,
-    new_user: Json<NewUser>,
+    _mfa: SecondFactorGuard,
+    new_user: SignedJson<NewUser>,
     backend: &State<MongodbBackend>,
 ) -> (Status, Value) {
-    super::generic_response(
-        backend
-            .insert_user(
-                new_user.0,
-                guard.ip.to_string(),
-                guard.email.clone(),
-            )
-            .await,
-    )
+    let created = backend
+        .insert_user(new_user.0, guard.ip.to_string(), guard.email.clone())
+        .await;
+    if let Ok(user) = &created {
+        let entry = AuditEntry::new(
+            "create_user",
+            &user.email,
+            &guard.email,
+            &guard.ip.to_string(),
+        );
+        super::audit(backend, entry.diff(None, Some(user))).await;
+    }
+    super::generic_response(created)
 }
 
 #[get("/")]
@@ -40,18 +44,54 @@ pub async fn get_all_users(_guard: AdminGuard, backend: &State<MongodbBackend>)
 
 #[put("/", format = "json", data = "<user>")]
 pub async fn update_user(
-    _guard: AdminGuard,
-    user: Json<NewUser>,
+    guard: AdminGuard,
+    _mfa: SecondFactorGuard,
+    user: SignedJson<NewUser>,
     backend: &State<MongodbBackend>,
 ) -> (Status, Value) {
-    super::generic_response(backend.update_user(user.0).await)
+    let updated = backend.update_user(user.0).await;
+    if let Ok((before, after)) = &updated {
+        let entry = AuditEntry::new(
+            "update_user",
+            &after.email,
+            &guard.email,
+            &guard.ip.to_string(),
+        );
+        super::audit(backend, entry.diff(Some(before), Some(after))).await;
+    }
+    super::generic_response(updated.map(|(_, after)| after))
 }
 
 #[delete("/<email>")]
 pub async fn delete_user(
-    _guard: AdminGuard,
+    guard: AdminGuard,
+    _mfa: SecondFactorGuard,
+    email: String,
+    backend: &State<MongodbBackend>,
+) -> (Status, Value) {
+    let entry = AuditEntry::new("delete_user", &email, &guard.email, &guard.ip.to_string());
+    let deleted = backend.delete_user(email).await;
+    if let Ok(before) = &deleted {
+        super::audit(backend, entry.diff(Some(before), None)).await;
+    }
+    super::generic_response(deleted.map(|_| ()))
+}
+
+#[put("/<email>/signing_secret")]
+pub async fn rotate_signing_secret(
+    guard: AdminGuard,
     email: String,
     backend: &State<MongodbBackend>,
 ) -> (Status, Value) {
-    super::generic_response(backend.delete_user(email).await)
+    let rotated = backend.rotate_signing_secret(email).await;
+    if let Ok((before, after)) = &rotated {
+        let entry = AuditEntry::new(
+            "rotate_signing_secret",
+            &after.email,
+            &guard.email,
+            &guard.ip.to_string(),
+        );
+        super::audit(backend, entry.diff(Some(before), Some(after))).await;
+    }
+    super::generic_response(rotated.map(|(_, after)| after))
 }
@@ -0,0 +1,71 @@
+use mongodb::{
+    bson::{doc, Document},
+    options::FindOptions,
+    Collection,
+};
+
+use super::MongodbBackend;
+use crate::{
+    error::Error,
+    models::audit::{AuditEntry, AuditQuery},
+};
+
+/// Most entries returned by one audit log query
+const AUDIT_QUERY_LIMIT: i64 = 1000;
+
+impl MongodbBackend {
+    fn audit_collection(&self) -> Result<Collection<AuditEntry>, Error> {
+        self.collection("audit_collection", "audit_log")
+    }
+
+    /// Append an entry to the audit log; entries are never updated nor deleted
+    pub async fn insert_audit_entry(&self, entry: AuditEntry) -> Result<(), Error> {
+        self.timed("insert_audit_entry", async {
+            self.audit_collection()?
+                .insert_one(entry, None)
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// Get the matching audit entries, latest first
+    pub async fn get_audit_entries(&self, query: AuditQuery) -> Result<Vec<AuditEntry>, Error> {
+        self.timed("get_audit_entries", async {
+            use futures::stream::TryStreamExt;
+
+            let mut filter = Document::new();
+            if let Some(actor) = query.actor {
+                filter.insert("actor", actor);
+            }
+            if let Some(target) = query.target {
+                filter.insert("target", target);
+            }
+            let mut range = Document::new();
+            if let Some(since) = query.since {
+                range.insert("$gte", since);
+            }
+            if let Some(until) = query.until {
+                range.insert("$lte", until);
+            }
+            if !range.is_empty() {
+                filter.insert("timestamp", range);
+            }
+
+            self.audit_collection()?
+                .find(
+                    filter,
+                    FindOptions::builder()
+                        .sort(doc! {"timestamp": -1})
+                        .limit(AUDIT_QUERY_LIMIT)
+                        .build(),
+                )
+                .await?
+                .try_collect()
+                .await
+                .map_err(Into::into)
+        })
+        .await
+    }
+}
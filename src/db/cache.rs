@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{models::user::User, secure::token::hash_api_key};
+
+/// Hit/miss counters of the user cache
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub capacity: usize,
+    pub ttl_secs: u64,
+}
+
+/// Cached users, with their insertion order to evict the oldest first
+#[derive(Default)]
+struct Entries {
+    users: HashMap<String, (Instant, User)>,
+    /// Insertion time & key of the cached users, oldest first; an entry
+    /// whose time differs from the cached user's is left over from a removal
+    order: VecDeque<(Instant, String)>,
+    /// Invalidation count
+    generation: u64,
+    /// Generation of the last invalidation of each user updated while reads were in flight
+    invalidated: HashMap<String, u64>,
+    /// Number of reads in flight, by the generation they started at
+    reads: BTreeMap<u64, usize>,
+}
+
+impl Entries {
+    /// Drop the order entries left over & the expired users at the front
+    fn drop_stale(&mut self, ttl: Duration) {
+        while let Some((inserted_at, key)) = self.order.front() {
+            let current = matches!(self.users.get(key), Some((at, _)) if at == inserted_at);
+            if current && inserted_at.elapsed() < ttl {
+                break;
+            }
+            if current {
+                self.users.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    /// Forget the invalidations no read in flight can predate
+    fn prune_invalidated(&mut self) {
+        match self.reads.keys().next().copied() {
+            Some(oldest) => self
+                .invalidated
+                .retain(|_, generation| *generation > oldest),
+            None => self.invalidated.clear(),
+        }
+    }
+
+    /// Evict the oldest cached user
+    fn evict_oldest(&mut self) {
+        while let Some((inserted_at, key)) = self.order.pop_front() {
+            if matches!(self.users.get(&key), Some((at, _)) if *at == inserted_at) {
+                self.users.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+/// A read of a user to be cached, holding back the pruning of the invalidations
+/// that happen meanwhile; ends when dropped
+pub struct CacheRead<'a> {
+    cache: &'a UserCache,
+    generation: u64,
+}
+
+impl Drop for CacheRead<'_> {
+    fn drop(&mut self) {
+        let mut entries = self
+            .cache
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = entries.reads.get_mut(&self.generation) {
+            *count -= 1;
+            if *count == 0 {
+                entries.reads.remove(&self.generation);
+            }
+        }
+        entries.prune_invalidated();
+    }
+}
+
+/// In-process cache of authenticated users, keyed by the hash of their api key
+pub struct UserCache {
+    entries: Mutex<Entries>,
+    ttl: Duration,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl UserCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        //! Create a cache holding at most `capacity` users for `ttl`.
+        //! A capacity of 0 disables the cache.
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! UserCache::new(Duration::from_secs(60), 10_000);
+        //! ```
+        Self {
+            entries: Mutex::new(Entries::default()),
+            ttl,
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.capacity > 0 && !self.ttl.is_zero()
+    }
+
+    /// Get the cached user for the given api key, if still fresh
+    pub fn get(&self, api_key: &str) -> Option<User> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let key_hash = hash_api_key(api_key);
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let user = match entries.users.get(&key_hash) {
+            Some((inserted_at, user)) if inserted_at.elapsed() < self.ttl => Some(user.clone()),
+            Some(_) => {
+                entries.users.remove(&key_hash);
+                None
+            }
+            None => None,
+        };
+
+        match user {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        user
+    }
+
+    /// Start reading the user to be cached, at the current invalidation count
+    pub fn start_read(&self) -> CacheRead<'_> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let generation = entries.generation;
+        *entries.reads.entry(generation).or_default() += 1;
+        CacheRead {
+            cache: self,
+            generation,
+        }
+    }
+
+    /// Cache the user read under the given api key, unless the user was invalidated
+    /// since the read started: the user read may then predate the update
+    pub fn insert(&self, api_key: &str, user: User, read: CacheRead<'_>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if entries
+            .invalidated
+            .get(&user.email)
+            .is_some_and(|invalidated| *invalidated > read.generation)
+        {
+            return;
+        }
+
+        // drop the stale entries first, then the oldest one if still full
+        entries.drop_stale(self.ttl);
+        let key_hash = hash_api_key(api_key);
+        if !entries.users.contains_key(&key_hash) && entries.users.len() >= self.capacity {
+            entries.evict_oldest();
+        }
+        let inserted_at = Instant::now();
+        entries.order.push_back((inserted_at, key_hash.clone()));
+        entries.users.insert(key_hash, (inserted_at, user));
+    }
+
+    /// Remove every entry belonging to the given user, and keep the reads
+    /// started before from being cached
+    pub fn invalidate_email(&self, email: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.users.retain(|_, (_, user)| user.email != email);
+        entries.generation += 1;
+        // only the reads in flight may predate the update
+        if !entries.reads.is_empty() {
+            let generation = entries.generation;
+            entries.invalidated.insert(email.to_string(), generation);
+        }
+    }
+
+    /// Get the current hit/miss counters
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self
+                .entries
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .users
+                .len(),
+            capacity: self.capacity,
+            ttl_secs: self.ttl.as_secs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn user(email: &str) -> User {
+        User {
+            created_ip: "127.0.0.1".into(),
+            created_by: "admin@example.com".into(),
+            created_at: Utc::now(),
+            email: email.into(),
+            description: String::new(),
+            api_key: format!("key-{}", email),
+            is_admin: false,
+            acl_allow_ips: vec![],
+            acl_allow_endpoints: vec![],
+            signing_secret: None,
+            cert_fingerprints: vec![],
+        }
+    }
+
+    fn cache(capacity: usize) -> UserCache {
+        UserCache::new(Duration::from_secs(60), capacity)
+    }
+
+    #[test]
+    fn does_not_cache_a_read_predating_an_invalidation() {
+        let cache = cache(10);
+        let read = cache.start_read();
+        cache.invalidate_email("a@example.com");
+        cache.insert("key-a", user("a@example.com"), read);
+        assert!(cache.get("key-a").is_none());
+
+        // a read started after the invalidation is cached
+        let read = cache.start_read();
+        cache.insert("key-a", user("a@example.com"), read);
+        assert!(cache.get("key-a").is_some());
+    }
+
+    #[test]
+    fn forgets_the_invalidations_once_the_reads_end() {
+        let cache = cache(10);
+        // no read in flight: nothing to remember
+        cache.invalidate_email("a@example.com");
+        assert!(cache.entries.lock().unwrap().invalidated.is_empty());
+
+        let read = cache.start_read();
+        cache.invalidate_email("a@example.com");
+        assert_eq!(cache.entries.lock().unwrap().invalidated.len(), 1);
+        drop(read);
+        let entries = cache.entries.lock().unwrap();
+        assert!(entries.invalidated.is_empty());
+        assert!(entries.reads.is_empty());
+    }
+
+    #[test]
+    fn evicts_the_oldest_user_when_full() {
+        let cache = cache(2);
+        for email in ["a@example.com", "b@example.com", "c@example.com"] {
+            cache.insert(&format!("key-{}", email), user(email), cache.start_read());
+        }
+        assert!(cache.get("key-a@example.com").is_none());
+        assert!(cache.get("key-b@example.com").is_some());
+        assert!(cache.get("key-c@example.com").is_some());
+        assert_eq!(cache.stats().entries, 2);
+
+        // a removed user leaves no slot taken
+        cache.invalidate_email("b@example.com");
+        cache.insert(
+            "key-d@example.com",
+            user("d@example.com"),
+            cache.start_read(),
+        );
+        assert!(cache.get("key-c@example.com").is_some());
+        assert!(cache.get("key-d@example.com").is_some());
+    }
+
+    #[test]
+    fn a_zero_capacity_disables_the_cache() {
+        let cache = cache(0);
+        cache.insert("key-a", user("a@example.com"), cache.start_read());
+        assert!(cache.get("key-a").is_none());
+    }
+}
@@ -0,0 +1,208 @@
+use chrono::offset::Utc;
+use mongodb::{bson::doc, options::ReplaceOptions, Collection};
+
+use super::MongodbBackend;
+use crate::{
+    error::Error,
+    models::{
+        credentials::{Credentials, NewPassword, TotpEnrolment},
+        user::User,
+    },
+    secure::{
+        password::{hash_password, verify_password},
+        token::hash_api_key,
+        totp::{generate_recovery_codes, generate_totp_secret, provisioning_uri, verify_totp},
+    },
+};
+
+/// Run the argon2 work on the blocking thread pool, it is slow by design
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Result<T, Error> {
+    rocket::tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| Error::InternalError)
+}
+
+impl MongodbBackend {
+    fn credentials_collection(&self) -> Result<Collection<Credentials>, Error> {
+        self.collection("credentials_collection", "credentials")
+    }
+
+    /// Get the credentials of the user, empty if none were set
+    pub async fn get_credentials(&self, email: &str) -> Result<Credentials, Error> {
+        self.timed("get_credentials", async {
+            Ok(self
+                .credentials_collection()?
+                .find_one(doc! {"email": email}, None)
+                .await?
+                .unwrap_or_else(|| Credentials::new(email)))
+        })
+        .await
+    }
+
+    async fn save_credentials(&self, mut credentials: Credentials) -> Result<(), Error> {
+        credentials.updated_at = Utc::now();
+        self.credentials_collection()?
+            .replace_one(
+                doc! {"email": &credentials.email},
+                &credentials,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
+    pub(super) async fn delete_credentials(&self, email: &str) -> Result<(), Error> {
+        self.timed("delete_credentials", async {
+            self.credentials_collection()?
+                .delete_one(doc! {"email": email}, None)
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    pub async fn set_password(&self, email: &str, new_password: NewPassword) -> Result<(), Error> {
+        self.timed("set_password", async {
+            let mut credentials = self.get_credentials(email).await?;
+
+            // changing a password needs the current one
+            if let Some(password_hash) = credentials.password_hash.clone() {
+                let verified = match new_password.current_password {
+                    Some(current) => {
+                        blocking(move || verify_password(&current, &password_hash)).await?
+                    }
+                    None => false,
+                };
+                if !verified {
+                    return Err(Error::ForbiddenAccess);
+                }
+            }
+
+            let password = new_password.password;
+            credentials.password_hash = Some(blocking(move || hash_password(&password)).await??);
+            self.save_credentials(credentials).await
+        })
+        .await
+    }
+
+    /// Get the user owning the email & password
+    pub async fn get_user_from_password(&self, email: &str, password: &str) -> Result<User, Error> {
+        self.timed("get_user_from_password", async {
+            let credentials = self.get_credentials(email).await?;
+            let verified = match credentials.password_hash {
+                Some(password_hash) => {
+                    let password = password.to_string();
+                    blocking(move || verify_password(&password, &password_hash)).await?
+                }
+                None => false,
+            };
+            if !verified {
+                return Err(Error::UnauthenticatedUser);
+            }
+            self.get_user_from_email(email)
+                .await
+                .map_err(|_| Error::UnauthenticatedUser)
+        })
+        .await
+    }
+
+    /// Start a TOTP enrolment, to be confirmed with a first code
+    pub async fn start_totp_enrolment(
+        &self,
+        email: &str,
+        issuer: &str,
+    ) -> Result<TotpEnrolment, Error> {
+        self.timed("start_totp_enrolment", async {
+            let mut credentials = self.get_credentials(email).await?;
+            if credentials.totp_enabled {
+                return Err(Error::BadRequest(
+                    "a second factor is already enrolled".into(),
+                ));
+            }
+
+            let secret = generate_totp_secret()?;
+            credentials.totp_secret = Some(secret.clone());
+            self.save_credentials(credentials).await?;
+
+            Ok(TotpEnrolment {
+                provisioning_uri: provisioning_uri(issuer, email, &secret),
+                secret,
+            })
+        })
+        .await
+    }
+
+    /// Enable the pending TOTP secret once a code is verified.
+    ///
+    /// Returns the recovery codes, only shown this once.
+    pub async fn confirm_totp(&self, email: &str, code: &str) -> Result<Vec<String>, Error> {
+        self.timed("confirm_totp", async {
+            let mut credentials = self.get_credentials(email).await?;
+            let step = match (&credentials.totp_secret, credentials.totp_enabled) {
+                (Some(secret), false) => verify_totp(secret, code.trim(), Utc::now().timestamp())
+                    .ok_or(Error::UnauthenticatedUser)?,
+                _ => return Err(Error::BadRequest("no pending enrolment".into())),
+            };
+
+            let (codes, hashes) = generate_recovery_codes()?;
+            credentials.totp_enabled = true;
+            credentials.totp_last_step = step;
+            credentials.recovery_codes = hashes;
+            self.save_credentials(credentials).await?;
+
+            Ok(codes)
+        })
+        .await
+    }
+
+    pub async fn disable_totp(&self, email: &str) -> Result<(), Error> {
+        self.timed("disable_totp", async {
+            let mut credentials = self.get_credentials(email).await?;
+            credentials.totp_secret = None;
+            credentials.totp_enabled = false;
+            credentials.recovery_codes = vec![];
+            self.save_credentials(credentials).await
+        })
+        .await
+    }
+
+    /// Check a TOTP code or a recovery code of the user; either is only accepted once
+    pub async fn verify_second_factor(&self, email: &str, code: &str) -> Result<(), Error> {
+        self.timed("verify_second_factor", async {
+            let credentials = self.get_credentials(email).await?;
+            let secret = match (&credentials.totp_secret, credentials.totp_enabled) {
+                (Some(secret), true) => secret,
+                _ => return Err(Error::ForbiddenAccess),
+            };
+            let code = code.trim();
+
+            // consumed by a conditional update, so that concurrent requests can not both use it
+            let (filter, update) = match verify_totp(secret, code, Utc::now().timestamp()) {
+                Some(step) => (
+                    doc! {"email": email, "totp_last_step": {"$lt": step}},
+                    doc! {"$set": {"totp_last_step": step}},
+                ),
+                None => {
+                    let hash = hash_api_key(code);
+                    (
+                        doc! {"email": email, "recovery_codes": &hash},
+                        doc! {"$pull": {"recovery_codes": &hash}},
+                    )
+                }
+            };
+            let consumed = self
+                .credentials_collection()?
+                .update_one(filter, update, None)
+                .await?;
+
+            if consumed.modified_count > 0 {
+                Ok(())
+            } else {
+                Err(Error::UnauthenticatedUser)
+            }
+        })
+        .await
+    }
+}
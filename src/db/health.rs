@@ -0,0 +1,33 @@
+use mongodb::bson::doc;
+
+use super::MongodbBackend;
+use crate::error::Error;
+
+impl MongodbBackend {
+    /// Round trip to the MongoDB server
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.timed("ping", async {
+            self.client
+                .database("admin")
+                .run_command(doc! {"ping": 1}, None)
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// Whether the configured `user_db` holds the configured `user_collection`
+    pub async fn user_collection_exists(&self) -> Result<bool, Error> {
+        self.timed("user_collection_exists", async {
+            let collection = self.user_collection()?;
+            let names = self
+                .client
+                .database(collection.namespace().db.as_str())
+                .list_collection_names(doc! {"name": collection.name()})
+                .await?;
+            Ok(!names.is_empty())
+        })
+        .await
+    }
+}
@@ -1,27 +1,112 @@
 use chrono::offset::Utc;
-use mongodb::{bson::doc, results::DeleteResult, Client, Collection};
-use std::collections::HashMap;
+use mongodb::{bson::doc, options::IndexOptions, Client, Collection, IndexModel};
+use opentelemetry::{trace::FutureExt, KeyValue};
+use prometheus::HistogramVec;
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+/// Append-only audit log of the admin changes
+mod audit;
+
+/// In-process user cache
+pub mod cache;
+use self::cache::{CacheStats, UserCache};
+
+/// Passwords & second factors
+mod credentials;
+
+/// Readiness checks of the backend
+mod health;
+
+/// Nonces of the signed requests
+mod nonce;
+
+/// OAuth clients & revoked tokens
+mod oauth;
+
+/// Emergency api key revocations
+mod revocation;
+
+/// Ended admin sessions
+mod session;
+
+/// Pending self-service signups
+mod signup;
+
+/// Api key last-used tracking
+pub mod usage;
 
 use crate::{
     error::Error,
     models::user::{NewUser, User},
-    secure::token::generate_api_string,
+    secure::{mtls::CertIdentity, signature::generate_signing_secret, token::generate_api_string},
+    server::telemetry::{end_span, start_span},
 };
 
 #[derive(Clone)]
 pub struct MongodbBackend {
     client: Client,
     config: HashMap<String, String>,
+    cache: Arc<UserCache>,
+    query_latency: Option<HistogramVec>,
 }
 
 impl MongodbBackend {
-    pub async fn connect(url: String, config: HashMap<String, String>) -> Result<Self, Error> {
+    pub async fn connect(
+        url: String,
+        config: HashMap<String, String>,
+        cache: UserCache,
+    ) -> Result<Self, Error> {
         Ok(Self {
             client: Client::with_uri_str(url.as_str()).await?,
             config,
+            cache: Arc::new(cache),
+            query_latency: None,
         })
     }
 
+    pub fn with_query_latency(mut self, histogram: HistogramVec) -> Self {
+        //! Observe the duration of the backend methods, labelled by method name
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! backend.with_query_latency(metrics.backend_latency());
+        //! ```
+        self.query_latency = Some(histogram);
+        self
+    }
+
+    /// Run the query of the backend method within its span,
+    /// timing it when the latency is observed
+    async fn timed<T>(
+        &self,
+        method: &'static str,
+        query: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let context = start_span(method, vec![KeyValue::new("db.system", "mongodb")]);
+        let query = query.with_context(context.clone());
+        let result = match &self.query_latency {
+            Some(histogram) => {
+                let timer = histogram.with_label_values(&[method]).start_timer();
+                let result = query.await;
+                timer.observe_duration();
+                result
+            }
+            None => query.await,
+        };
+        end_span(&context, &result);
+        result
+    }
+
+    /// Close the connections to MongoDB; the backend can't be queried anymore
+    pub async fn close(&self) {
+        self.client.clone().shutdown().await
+    }
+
+    /// Hit/miss counters of the user cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
     fn user_collection(&self) -> Result<Collection<User>, Error> {
         let database_name = self
             .config
@@ -39,6 +124,43 @@ impl MongodbBackend {
             .collection(collection_name))
     }
 
+    /// Get a collection of the user database, named by `key` in the db config or else `default`
+    fn collection<T>(&self, key: &str, default: &str) -> Result<Collection<T>, Error> {
+        let database_name = self
+            .config
+            .get("user_db")
+            .ok_or(Error::DatabaseNotConfigured)?;
+
+        let collection_name = self.config.get(key).map(String::as_str).unwrap_or(default);
+
+        Ok(self
+            .client
+            .database(database_name)
+            .collection(collection_name))
+    }
+
+    /// Create the TTL index removing the documents of the collection once
+    /// the date in `field` is past
+    async fn create_expiry_index<T>(
+        &self,
+        collection: Collection<T>,
+        field: &str,
+    ) -> Result<(), Error> {
+        let index = IndexModel::builder()
+            .keys(doc! {field: 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Some(Duration::ZERO))
+                    .build(),
+            )
+            .build();
+        collection
+            .create_index(index, None)
+            .await
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
     fn create_api_key(&self, salt: &str) -> String {
         generate_api_string(salt)
     }
@@ -49,84 +171,195 @@ impl MongodbBackend {
         ip: String,
         creator: String,
     ) -> Result<User, Error> {
-        let datetime = Utc::now();
-
-        let user = User {
-            created_ip: ip,
-            created_by: creator,
-            created_at: datetime,
-            email: user.email,
-            description: user.description,
-            api_key: self.create_api_key(&datetime.to_string()),
-            is_admin: user.is_admin,
-            acl_allow_ips: user.acl_allow_ips,
-            acl_allow_endpoints: user.acl_allow_endpoints,
-        };
+        self.timed("insert_user", async {
+            let datetime = Utc::now();
 
-        let user_collection = self.user_collection()?;
+            let user = User {
+                created_ip: ip,
+                created_by: creator,
+                created_at: datetime,
+                email: user.email,
+                description: user.description,
+                api_key: self.create_api_key(&datetime.to_string()),
+                is_admin: user.is_admin,
+                acl_allow_ips: user.acl_allow_ips,
+                acl_allow_endpoints: user.acl_allow_endpoints,
+                signing_secret: Some(generate_signing_secret()),
+                cert_fingerprints: user.cert_fingerprints,
+            };
 
-        if user_collection
-            .find_one(doc! { "email": &user.email }, None)
-            .await?
-            .is_some()
-        {
-            return Err(Error::UserConflict);
-        }
+            let user_collection = self.user_collection()?;
 
-        user_collection
-            .insert_one(user.clone(), None)
-            .await
-            .map(|_| user)
-            .map_err(Into::into)
+            if user_collection
+                .find_one(doc! { "email": &user.email }, None)
+                .await?
+                .is_some()
+            {
+                return Err(Error::UserConflict);
+            }
+
+            user_collection
+                .insert_one(user.clone(), None)
+                .await
+                .map(|_| user)
+                .map_err(Into::into)
+        })
+        .await
     }
 
-    pub async fn update_user(&self, user: NewUser) -> Result<User, Error> {
-        use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
-
-        self.user_collection()?
-            .find_one_and_update(
-                doc! { "email": &user.email},
-                doc! {
-                "$set": {
-                    "is_admin": user.is_admin,
-                    "acl_allow_endpoints": user.acl_allow_endpoints,
-                    "acl_allow_ips": user.acl_allow_ips,
-                    "description": user.description
-                }},
-                FindOneAndUpdateOptions::builder()
-                    .return_document(Some(ReturnDocument::After))
-                    .build(),
-            )
-            .await?
-            .ok_or(Error::NotFound)
+    /// Update the user, returning it as it was before & as it is after the update,
+    /// both from the write itself
+    pub async fn update_user(&self, user: NewUser) -> Result<(User, User), Error> {
+        self.timed("update_user", async {
+            use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+
+            let before = self
+                .user_collection()?
+                .find_one_and_update(
+                    doc! { "email": &user.email},
+                    doc! {
+                    "$set": {
+                        "is_admin": user.is_admin,
+                        "acl_allow_endpoints": user.acl_allow_endpoints.clone(),
+                        "acl_allow_ips": &user.acl_allow_ips,
+                        "cert_fingerprints": &user.cert_fingerprints,
+                        "description": &user.description
+                    }},
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(Some(ReturnDocument::Before))
+                        .build(),
+                )
+                .await?
+                .ok_or(Error::NotFound);
+
+            // drop the cached user once the write is done; the reads started
+            // before it are not cached, as they may predate the write
+            self.cache.invalidate_email(&user.email);
+            before.map(|before| {
+                let after = User {
+                    is_admin: user.is_admin,
+                    acl_allow_endpoints: user.acl_allow_endpoints,
+                    acl_allow_ips: user.acl_allow_ips,
+                    cert_fingerprints: user.cert_fingerprints,
+                    description: user.description,
+                    ..before.clone()
+                };
+                (before, after)
+            })
+        })
+        .await
     }
 
     pub async fn get_user_from_api_key(&self, api_key: &str) -> Result<User, Error> {
-        self.user_collection()?
-            .find_one(doc! {"api_key": api_key}, None)
-            .await?
-            .ok_or(Error::NotFound)
+        if let Some(user) = self.cache.get(api_key) {
+            return Ok(user);
+        }
+
+        // only the cache misses query the database
+        let read = self.cache.start_read();
+        let user = self
+            .timed("get_user_from_api_key", async {
+                self.user_collection()?
+                    .find_one(doc! {"api_key": api_key}, None)
+                    .await?
+                    .ok_or(Error::NotFound)
+            })
+            .await?;
+
+        self.cache.insert(api_key, user.clone(), read);
+        Ok(user)
     }
 
-    pub async fn get_all_users(&self) -> Result<Vec<User>, Error> {
-        use futures::stream::TryStreamExt;
+    pub async fn get_user_from_email(&self, email: &str) -> Result<User, Error> {
+        self.timed("get_user_from_email", async {
+            self.user_collection()?
+                .find_one(doc! {"email": email}, None)
+                .await?
+                .ok_or(Error::NotFound)
+        })
+        .await
+    }
 
-        self.user_collection()?
-            .find(None, None)
-            .await?
-            .try_collect()
-            .await
-            .map_err(Into::into)
+    pub async fn get_user_from_certificate(&self, identity: &CertIdentity) -> Result<User, Error> {
+        self.timed("get_user_from_certificate", async {
+            let user_collection = self.user_collection()?;
+
+            // a pinned public key wins over the names carried by the certificate
+            if let Some(user) = user_collection
+                .find_one(doc! {"cert_fingerprints": &identity.fingerprint}, None)
+                .await?
+            {
+                return Ok(user);
+            }
+
+            user_collection
+                .find_one(doc! {"email": {"$in": &identity.names}}, None)
+                .await?
+                .ok_or(Error::NotFound)
+        })
+        .await
     }
 
-    pub async fn delete_user(&self, email: String) -> Result<(), Error> {
-        self.user_collection()?
-            .delete_one(doc! {"email": email}, None)
-            .await
-            .map_err(Into::into)
-            .and_then(|count| match count {
-                DeleteResult { deleted_count, .. } if deleted_count > 0 => Ok(()),
-                _ => Err(Error::NotFound),
+    /// Give the user a new signing secret, returning the user before & after
+    pub async fn rotate_signing_secret(&self, email: String) -> Result<(User, User), Error> {
+        self.timed("rotate_signing_secret", async {
+            use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+
+            let signing_secret = generate_signing_secret();
+            let before = self
+                .user_collection()?
+                .find_one_and_update(
+                    doc! { "email": &email },
+                    doc! { "$set": { "signing_secret": &signing_secret } },
+                    FindOneAndUpdateOptions::builder()
+                        .return_document(Some(ReturnDocument::Before))
+                        .build(),
+                )
+                .await?
+                .ok_or(Error::NotFound);
+
+            self.cache.invalidate_email(&email);
+            before.map(|before| {
+                let after = User {
+                    signing_secret: Some(signing_secret),
+                    ..before.clone()
+                };
+                (before, after)
             })
+        })
+        .await
+    }
+
+    pub async fn get_all_users(&self) -> Result<Vec<User>, Error> {
+        self.timed("get_all_users", async {
+            use futures::stream::TryStreamExt;
+
+            self.user_collection()?
+                .find(None, None)
+                .await?
+                .try_collect()
+                .await
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// Delete the user, returning it as it was deleted
+    pub async fn delete_user(&self, email: String) -> Result<User, Error> {
+        self.timed("delete_user", async {
+            let deleted = self
+                .user_collection()?
+                .find_one_and_delete(doc! {"email": &email}, None)
+                .await
+                .map_err(Into::into)
+                .and_then(|deleted| deleted.ok_or(Error::NotFound));
+
+            self.cache.invalidate_email(&email);
+            if deleted.is_ok() {
+                self.delete_credentials(&email).await?;
+            }
+            deleted
+        })
+        .await
     }
 }
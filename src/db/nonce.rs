@@ -0,0 +1,64 @@
+use mongodb::{
+    bson::DateTime,
+    error::{ErrorKind, WriteFailure},
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use super::MongodbBackend;
+use crate::error::Error;
+
+/// Duplicate key error code of MongoDB
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Nonce of a signed request, kept until the request can't be replayed anyway
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct UsedNonce {
+    /// `email:nonce`
+    #[serde(rename = "_id")]
+    id: String,
+    expires_at: DateTime,
+}
+
+impl MongodbBackend {
+    fn nonce_collection(&self) -> Result<Collection<UsedNonce>, Error> {
+        self.collection("nonce_collection", "signature_nonces")
+    }
+
+    /// Create the TTL index removing the nonces once expired
+    pub async fn create_nonce_index(&self) -> Result<(), Error> {
+        self.timed("create_nonce_index", async {
+            self.create_expiry_index(self.nonce_collection()?, "expires_at")
+                .await
+        })
+        .await
+    }
+
+    /// Burn the nonce of the user's signed request until `expires_at` (unix time);
+    /// fails when any instance already saw it
+    pub async fn use_signature_nonce(
+        &self,
+        email: &str,
+        nonce: &str,
+        expires_at: i64,
+    ) -> Result<(), Error> {
+        self.timed("use_signature_nonce", async {
+            let used = UsedNonce {
+                id: format!("{}:{}", email, nonce),
+                expires_at: DateTime::from_millis(expires_at.saturating_mul(1000)),
+            };
+            match self.nonce_collection()?.insert_one(used, None).await {
+                Ok(_) => Ok(()),
+                Err(e) => match e.kind.as_ref() {
+                    ErrorKind::Write(WriteFailure::WriteError(write_error))
+                        if write_error.code == DUPLICATE_KEY =>
+                    {
+                        Err(Error::UnauthenticatedUser)
+                    }
+                    _ => Err(e.into()),
+                },
+            }
+        })
+        .await
+    }
+}
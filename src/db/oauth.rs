@@ -0,0 +1,194 @@
+use chrono::offset::Utc;
+use mongodb::{
+    bson::{doc, DateTime},
+    results::DeleteResult,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use super::MongodbBackend;
+use crate::{
+    error::Error,
+    models::oauth::{NewOAuthClient, OAuthClient, RegisteredOAuthClient},
+    secure::{signature::generate_signing_secret, token::hash_api_key},
+};
+
+/// Token revoked before its expiry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevokedToken {
+    pub jti: String,
+    pub exp: i64,
+    /// `exp` as a date, for the TTL index
+    pub expires_at: DateTime,
+}
+
+/// Deleted OAuth client, whose tokens are revoked until the last one expires
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevokedClient {
+    pub client_id: String,
+    pub exp: i64,
+    /// `exp` as a date, for the TTL index
+    pub expires_at: DateTime,
+}
+
+impl MongodbBackend {
+    fn oauth_client_collection(&self) -> Result<Collection<OAuthClient>, Error> {
+        self.collection("oauth_client_collection", "oauth_clients")
+    }
+
+    fn revoked_token_collection(&self) -> Result<Collection<RevokedToken>, Error> {
+        self.collection("revoked_token_collection", "revoked_tokens")
+    }
+
+    fn revoked_client_collection(&self) -> Result<Collection<RevokedClient>, Error> {
+        self.collection("revoked_client_collection", "revoked_clients")
+    }
+
+    /// Create the TTL indexes removing the token revocations once the tokens expired
+    pub async fn create_revoked_token_index(&self) -> Result<(), Error> {
+        self.timed("create_revoked_token_index", async {
+            self.create_expiry_index(self.revoked_token_collection()?, "expires_at")
+                .await?;
+            self.create_expiry_index(self.revoked_client_collection()?, "expires_at")
+                .await
+        })
+        .await
+    }
+
+    pub async fn insert_oauth_client(
+        &self,
+        client: NewOAuthClient,
+        ip: String,
+        creator: String,
+    ) -> Result<RegisteredOAuthClient, Error> {
+        self.timed("insert_oauth_client", async {
+            // the client acts as an existing user
+            self.get_user_from_email(&client.email).await?;
+
+            let client_secret = generate_signing_secret();
+            let client = OAuthClient {
+                client_id: uuid::Uuid::new_v4().as_simple().to_string(),
+                secret_hash: hash_api_key(&client_secret),
+                email: client.email,
+                description: client.description,
+                scopes: client.scopes,
+                created_ip: ip,
+                created_by: creator,
+                created_at: Utc::now(),
+            };
+
+            self.oauth_client_collection()?
+                .insert_one(client.clone(), None)
+                .await
+                .map(|_| RegisteredOAuthClient {
+                    client,
+                    client_secret,
+                })
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    pub async fn get_oauth_client(&self, client_id: &str) -> Result<OAuthClient, Error> {
+        self.timed("get_oauth_client", async {
+            self.oauth_client_collection()?
+                .find_one(doc! {"client_id": client_id}, None)
+                .await?
+                .ok_or(Error::NotFound)
+        })
+        .await
+    }
+
+    pub async fn get_all_oauth_clients(&self) -> Result<Vec<OAuthClient>, Error> {
+        self.timed("get_all_oauth_clients", async {
+            use futures::stream::TryStreamExt;
+
+            self.oauth_client_collection()?
+                .find(None, None)
+                .await?
+                .try_collect()
+                .await
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    pub async fn delete_oauth_client(&self, client_id: String) -> Result<(), Error> {
+        self.timed("delete_oauth_client", async {
+            self.oauth_client_collection()?
+                .delete_one(doc! {"client_id": client_id}, None)
+                .await
+                .map_err(Into::into)
+                .and_then(|count| match count {
+                    DeleteResult { deleted_count, .. } if deleted_count > 0 => Ok(()),
+                    _ => Err(Error::NotFound),
+                })
+        })
+        .await
+    }
+
+    pub async fn insert_revoked_token(&self, jti: String, exp: i64) -> Result<(), Error> {
+        self.timed("insert_revoked_token", async {
+            self.revoked_token_collection()?
+                .insert_one(
+                    RevokedToken {
+                        jti,
+                        exp,
+                        expires_at: DateTime::from_millis(exp.saturating_mul(1000)),
+                    },
+                    None,
+                )
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    pub async fn get_revoked_tokens(&self) -> Result<Vec<RevokedToken>, Error> {
+        self.timed("get_revoked_tokens", async {
+            use futures::stream::TryStreamExt;
+
+            self.revoked_token_collection()?
+                .find(doc! {"exp": {"$gt": Utc::now().timestamp()}}, None)
+                .await?
+                .try_collect()
+                .await
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// Revoke the tokens issued to the deleted client until `exp` (unix time)
+    pub async fn insert_revoked_client(&self, client_id: String, exp: i64) -> Result<(), Error> {
+        self.timed("insert_revoked_client", async {
+            self.revoked_client_collection()?
+                .insert_one(
+                    RevokedClient {
+                        client_id,
+                        exp,
+                        expires_at: DateTime::from_millis(exp.saturating_mul(1000)),
+                    },
+                    None,
+                )
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    pub async fn get_revoked_clients(&self) -> Result<Vec<RevokedClient>, Error> {
+        self.timed("get_revoked_clients", async {
+            use futures::stream::TryStreamExt;
+
+            self.revoked_client_collection()?
+                .find(doc! {"exp": {"$gt": Utc::now().timestamp()}}, None)
+                .await?
+                .try_collect()
+                .await
+                .map_err(Into::into)
+        })
+        .await
+    }
+}
@@ -0,0 +1,65 @@
+use chrono::offset::Utc;
+use mongodb::{bson::doc, options::ReplaceOptions, Collection};
+
+use super::MongodbBackend;
+use crate::{
+    error::Error,
+    models::revocation::{NewRevocation, Revocation},
+    secure::token::hash_api_key,
+};
+
+impl MongodbBackend {
+    fn revocation_collection(&self) -> Result<Collection<Revocation>, Error> {
+        self.collection("revocation_collection", "revocations")
+    }
+
+    pub async fn insert_revocation(
+        &self,
+        revocation: NewRevocation,
+        revoker: String,
+    ) -> Result<Revocation, Error> {
+        self.timed("insert_revocation", async {
+            let key_hash = match (revocation.api_key, revocation.key_hash) {
+                (Some(api_key), _) => hash_api_key(api_key.trim()),
+                (None, Some(key_hash)) if key_hash.len() == 64 => key_hash.to_lowercase(),
+                _ => {
+                    return Err(Error::BadRequest(
+                        "api_key or a sha256 key_hash is required".into(),
+                    ))
+                }
+            };
+            let revocation = Revocation {
+                key_hash,
+                reason: revocation.reason,
+                revoked_by: revoker,
+                revoked_at: Utc::now(),
+            };
+
+            // revoking a key twice keeps the latest reason
+            self.revocation_collection()?
+                .replace_one(
+                    doc! {"key_hash": &revocation.key_hash},
+                    &revocation,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await
+                .map(|_| revocation)
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    pub async fn get_all_revocations(&self) -> Result<Vec<Revocation>, Error> {
+        self.timed("get_all_revocations", async {
+            use futures::stream::TryStreamExt;
+
+            self.revocation_collection()?
+                .find(None, None)
+                .await?
+                .try_collect()
+                .await
+                .map_err(Into::into)
+        })
+        .await
+    }
+}
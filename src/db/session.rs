@@ -0,0 +1,65 @@
+use mongodb::{
+    bson::{doc, DateTime},
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use super::MongodbBackend;
+use crate::error::Error;
+
+/// Admin session ended before its expiry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct EndedSession {
+    /// Session id
+    #[serde(rename = "_id")]
+    id: String,
+    expires_at: DateTime,
+}
+
+impl MongodbBackend {
+    fn ended_session_collection(&self) -> Result<Collection<EndedSession>, Error> {
+        self.collection("ended_session_collection", "ended_sessions")
+    }
+
+    /// Create the TTL index removing the ended sessions once expired
+    pub async fn create_ended_session_index(&self) -> Result<(), Error> {
+        self.timed("create_ended_session_index", async {
+            self.create_expiry_index(self.ended_session_collection()?, "expires_at")
+                .await
+        })
+        .await
+    }
+
+    /// Reject the session on every instance until it expires (unix time)
+    pub async fn insert_ended_session(&self, id: &str, expires_at: i64) -> Result<(), Error> {
+        self.timed("insert_ended_session", async {
+            let ended = EndedSession {
+                id: id.to_string(),
+                expires_at: DateTime::from_millis(expires_at.saturating_mul(1000)),
+            };
+            self.ended_session_collection()?
+                .replace_one(
+                    doc! {"_id": id},
+                    ended,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await
+                .map(|_| ())
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// Check if the session was ended on any instance
+    pub async fn is_session_ended(&self, id: &str) -> Result<bool, Error> {
+        self.timed("is_session_ended", async {
+            self.ended_session_collection()?
+                .find_one(doc! {"_id": id}, None)
+                .await
+                .map(|ended| ended.is_some())
+                .map_err(Into::into)
+        })
+        .await
+    }
+}
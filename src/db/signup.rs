@@ -0,0 +1,126 @@
+use chrono::offset::Utc;
+use mongodb::{bson::doc, options::ReplaceOptions, Collection};
+
+use super::MongodbBackend;
+use crate::{
+    error::Error,
+    models::{
+        signup::{NewSignup, PendingSignup},
+        user::{NewUser, User},
+    },
+    secure::{signature::generate_signing_secret, signup::SignupTokens, token::hash_api_key},
+};
+
+/// Creator recorded on the accounts opened by a signup
+const SIGNUP_CREATOR: &str = "signup";
+
+impl MongodbBackend {
+    fn signup_collection(&self) -> Result<Collection<PendingSignup>, Error> {
+        self.collection("signup_collection", "signups")
+    }
+
+    /// Register a pending signup, replacing any earlier expired one of the same email.
+    ///
+    /// Returns the verification token to mail, or None if the email already has an account
+    /// or its pending token was mailed less than `resend_after_secs` ago.
+    pub async fn insert_signup(
+        &self,
+        signup: NewSignup,
+        ip: String,
+        ttl_secs: u64,
+        resend_after_secs: u64,
+        tokens: &SignupTokens,
+    ) -> Result<Option<String>, Error> {
+        self.timed("insert_signup", async {
+            if self.get_user_from_email(&signup.email).await.is_ok() {
+                return Ok(None);
+            }
+
+            let signup_collection = self.signup_collection()?;
+            let now = Utc::now();
+            let pending = signup_collection
+                .find_one(
+                    doc! {
+                        "email": &signup.email,
+                        "expires_at": {"$gt": now.timestamp()}
+                    },
+                    None,
+                )
+                .await?;
+
+            // the pending token is mailed again, unless derived with another key
+            if let Some(pending) = pending {
+                let token = tokens.token(&pending.email, &pending.salt)?;
+                if hash_api_key(&token) == pending.token_hash {
+                    if now.timestamp() - pending.mailed_at < resend_after_secs as i64 {
+                        return Ok(None);
+                    }
+                    // only one instance mails it when the signup is posted twice at once
+                    let updated = signup_collection
+                        .update_one(
+                            doc! {"email": &pending.email, "mailed_at": pending.mailed_at},
+                            doc! {"$set": {"mailed_at": now.timestamp()}},
+                            None,
+                        )
+                        .await?;
+                    return Ok((updated.modified_count > 0).then_some(token));
+                }
+            }
+
+            let salt = generate_signing_secret();
+            let token = tokens.token(&signup.email, &salt)?;
+            let pending = PendingSignup {
+                email: signup.email,
+                description: signup.description,
+                token_hash: hash_api_key(&token),
+                salt,
+                mailed_at: now.timestamp(),
+                created_ip: ip,
+                created_at: now,
+                expires_at: now.timestamp() + ttl_secs as i64,
+            };
+
+            signup_collection
+                .replace_one(
+                    doc! {"email": &pending.email},
+                    &pending,
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await
+                .map(|_| Some(token))
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// Open the account of the pending signup the token belongs to,
+    /// with the ACL of the template
+    pub async fn redeem_signup(&self, token: &str, template: NewUser) -> Result<User, Error> {
+        self.timed("redeem_signup", async {
+            let pending = self
+                .signup_collection()?
+                .find_one_and_delete(
+                    doc! {
+                        "token_hash": hash_api_key(token.trim()),
+                        "expires_at": {"$gt": Utc::now().timestamp()}
+                    },
+                    None,
+                )
+                .await?
+                .ok_or(Error::NotFound)?;
+
+            self.insert_user(
+                NewUser {
+                    email: pending.email,
+                    description: pending.description,
+                    is_admin: false,
+                    ..template
+                },
+                pending.created_ip,
+                SIGNUP_CREATOR.to_string(),
+            )
+            .await
+        })
+        .await
+    }
+}
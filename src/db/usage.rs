@@ -0,0 +1,259 @@
+use chrono::{offset::Utc, TimeZone};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    options::UpdateOptions,
+    Collection,
+};
+use rocket::{fairing::AdHoc, tokio};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use super::MongodbBackend;
+use crate::{
+    error::Error,
+    models::usage::{KeyUsage, KeyUsageSummary, StaleKey, DAY_SECS},
+    secure::token::hash_api_key,
+};
+
+/// Days past the rolling window swept at each daily prune, in case an instance was down
+const PRUNE_SWEEP_DAYS: i64 = 7;
+
+/// Usage of a key gathered since the last flush
+#[derive(Debug, Clone)]
+struct PendingUsage {
+    email: String,
+    last_used_at: i64,
+    last_used_ip: String,
+    // day => requests
+    counts: HashMap<i64, i64>,
+}
+
+/// Buffers the key usage of the requests, written to the backend in batches
+#[derive(Clone, Default)]
+pub struct UsageTracker {
+    // key hash => usage since the last flush
+    pending: Arc<Mutex<HashMap<String, PendingUsage>>>,
+}
+
+impl UsageTracker {
+    /// Record a request made with the key, without touching the database
+    pub fn record(&self, key_hash: &str, email: &str, ip: &str) {
+        let now = Utc::now().timestamp();
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let usage = pending
+            .entry(key_hash.to_string())
+            .or_insert_with(|| PendingUsage {
+                email: email.to_string(),
+                last_used_at: now,
+                last_used_ip: ip.to_string(),
+                counts: HashMap::new(),
+            });
+        usage.last_used_at = now;
+        usage.last_used_ip = ip.to_string();
+        *usage.counts.entry(now / DAY_SECS).or_default() += 1;
+    }
+
+    /// Write the buffered usage to the backend.
+    ///
+    /// The usage is only dropped from the buffer once written: what could not be
+    /// written, or what was recorded meanwhile, is kept for the next flush.
+    pub async fn flush(&self, backend: &MongodbBackend) {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for (key_hash, usage) in pending {
+            match backend.record_key_usage(&key_hash, &usage).await {
+                Ok(_) => self.remove_written(&key_hash, &usage),
+                Err(e) => log::warn!(
+                    "could not record the usage of a key of {}: {}",
+                    usage.email,
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Drop the written counts of the key from the buffer, and the key itself
+    /// when no request was recorded since: its last use is then written too
+    fn remove_written(&self, key_hash: &str, written: &PendingUsage) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(usage) = pending.get_mut(key_hash) {
+            for (day, count) in &written.counts {
+                if let Some(left) = usage.counts.get_mut(day) {
+                    *left -= count;
+                    if *left <= 0 {
+                        usage.counts.remove(day);
+                    }
+                }
+            }
+            if usage.counts.is_empty() {
+                pending.remove(key_hash);
+            }
+        }
+    }
+
+    /// Number of keys whose usage is not written yet
+    pub fn pending_keys(&self) -> usize {
+        self.pending
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Fairing flushing the usage every `flush_interval`, and pruning the
+    /// daily counts older than `window_days` once a day
+    pub fn fairing(
+        self,
+        backend: MongodbBackend,
+        flush_interval: Duration,
+        window_days: i64,
+    ) -> AdHoc {
+        AdHoc::on_liftoff("Key usage tracking", move |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(flush_interval);
+                    let mut pruned_day = 0;
+                    loop {
+                        interval.tick().await;
+                        self.flush(&backend).await;
+
+                        let today = Utc::now().timestamp() / DAY_SECS;
+                        if today != pruned_day {
+                            pruned_day = today;
+                            if let Err(e) = backend.prune_key_usage(today - window_days).await {
+                                log::warn!("could not prune the key usage: {}", e);
+                            }
+                        }
+                    }
+                });
+            })
+        })
+    }
+}
+
+impl MongodbBackend {
+    fn key_usage_collection(&self) -> Result<Collection<KeyUsage>, Error> {
+        self.collection("key_usage_collection", "key_usage")
+    }
+
+    async fn record_key_usage(&self, key_hash: &str, usage: &PendingUsage) -> Result<(), Error> {
+        let mut increments = Document::new();
+        let mut total = 0;
+        for (day, count) in &usage.counts {
+            increments.insert(format!("daily_counts.{}", day), count);
+            total += count;
+        }
+        increments.insert("request_count", total);
+
+        self.key_usage_collection()?
+            .update_one(
+                doc! {"key_hash": key_hash},
+                doc! {
+                    "$set": {"email": &usage.email, "last_used_ip": &usage.last_used_ip},
+                    "$max": {"last_used_at": usage.last_used_at},
+                    "$inc": increments
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
+    /// Drop the daily counts of the days before `first_day`
+    async fn prune_key_usage(&self, first_day: i64) -> Result<(), Error> {
+        let days = (first_day - PRUNE_SWEEP_DAYS..first_day)
+            .map(|day| (format!("daily_counts.{}", day), Bson::from("")))
+            .collect::<Document>();
+
+        self.key_usage_collection()?
+            .update_many(doc! {}, doc! {"$unset": days}, None)
+            .await
+            .map(|_| ())
+            .map_err(Into::into)
+    }
+
+    async fn get_all_key_usage(&self) -> Result<Vec<KeyUsage>, Error> {
+        use futures::stream::TryStreamExt;
+
+        self.key_usage_collection()?
+            .find(None, None)
+            .await?
+            .try_collect()
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Get the usage of every key, with the requests of the last `window_days` days
+    pub async fn get_key_usage_summaries(
+        &self,
+        window_days: i64,
+    ) -> Result<Vec<KeyUsageSummary>, Error> {
+        self.timed("get_key_usage_summaries", async {
+            let now = Utc::now().timestamp();
+            Ok(self
+                .get_all_key_usage()
+                .await?
+                .into_iter()
+                .map(|usage| KeyUsageSummary {
+                    window_requests: usage.requests_within(window_days, now),
+                    last_used_at: Utc
+                        .timestamp_opt(usage.last_used_at, 0)
+                        .single()
+                        .unwrap_or_else(Utc::now),
+                    key_hash: usage.key_hash,
+                    email: usage.email,
+                    last_used_ip: usage.last_used_ip,
+                    request_count: usage.request_count,
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// List the keys of the users unused for the last `days` days
+    pub async fn get_stale_keys(&self, days: i64) -> Result<Vec<StaleKey>, Error> {
+        self.timed("get_stale_keys", async {
+            let usage = self
+                .get_all_key_usage()
+                .await?
+                .into_iter()
+                .map(|usage| (usage.key_hash.clone(), usage))
+                .collect::<HashMap<_, _>>();
+            let since = Utc::now().timestamp() - days * DAY_SECS;
+
+            Ok(self
+                .get_all_users()
+                .await?
+                .into_iter()
+                .filter_map(|user| {
+                    let key_hash = hash_api_key(&user.api_key);
+                    let usage = usage.get(&key_hash);
+                    // keys younger than the period are not stale yet
+                    if user.created_at.timestamp() >= since
+                        || usage.is_some_and(|usage| usage.last_used_at >= since)
+                    {
+                        return None;
+                    }
+
+                    Some(StaleKey {
+                        email: user.email,
+                        key_hash,
+                        is_admin: user.is_admin,
+                        created_at: user.created_at,
+                        last_used_at: usage
+                            .and_then(|usage| Utc.timestamp_opt(usage.last_used_at, 0).single()),
+                        last_used_ip: usage.map(|usage| usage.last_used_ip.clone()),
+                        request_count: usage.map_or(0, |usage| usage.request_count),
+                    })
+                })
+                .collect())
+        })
+        .await
+    }
+}
@@ -38,7 +38,7 @@ pub enum Error {
     #[error("Too many requests")]
     TooManyRequests,
 
-    #[error("Configuration Error")]
+    #[error("Configuration Error: {0}")]
     ConfigurationError(String),
     #[error("Config file not found")]
     ConfigFileNotFound,
@@ -78,9 +78,10 @@ impl Serialize for Error {
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Error", 2)?;
+        let mut state = serializer.serialize_struct("Error", 3)?;
         state.serialize_field("error", &self.to_string())?;
         state.serialize_field("code", &self.to_status().code)?;
+        state.serialize_field("request_id", &crate::server::request_id::current())?;
 
         state.end()
     }
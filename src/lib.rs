@@ -15,6 +15,9 @@ mod controllers;
 /// Database
 mod db;
 
+/// Outgoing emails
+pub mod mailer;
+
 /// Models
 pub mod models;
 
@@ -6,6 +6,8 @@
 /// ```ignore
 /// json_response!("msg" => "some message", "error_code" => 200, {k: v, ...})
 /// ```
+/// The responses with an error code carry the id of the request too.
+///
 /// - With default error code as 200
 /// ```ignore
 /// json_response!("msg" => "some message", {k: v, ...})
@@ -23,6 +25,7 @@ macro_rules! json_response {
         (rocket::http::Status::from_code($status_code).unwrap(), rocket::serde::json::json!({
                 "message": $msg,
                 "error_code": $status_code,
+                "request_id": $crate::server::request_id::current(),
                 $( $key: $val, )*
             }))
     };
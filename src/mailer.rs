@@ -0,0 +1,158 @@
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::error::Error;
+
+/// An outgoing email
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers the emails sent by the server
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: &Mail) -> Result<(), Error>;
+}
+
+/// Which mailer delivers the emails
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MailerKind {
+    /// Write the emails to the server log
+    #[default]
+    Log,
+    /// Append the emails to a file
+    File,
+    /// Send the emails through an SMTP relay
+    Smtp,
+}
+
+/// How the connection to the SMTP relay is secured
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Plain connection, for a local relay only
+    None,
+    /// Upgrade the connection with STARTTLS
+    #[default]
+    StartTls,
+    /// TLS from the start of the connection
+    Tls,
+}
+
+/// Writes the emails to the server log, for development & tests
+pub struct LogMailer;
+
+#[rocket::async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        log::info!("mail to {}: {}\n{}", mail.to, mail.subject, mail.body);
+        Ok(())
+    }
+}
+
+/// Appends the emails to a file, for development & tests
+pub struct FileMailer {
+    path: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        use rocket::tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        let entry = format!(
+            "To: {}\nSubject: {}\n\n{}\n\n",
+            mail.to, mail.subject, mail.body
+        );
+        file.write_all(entry.as_bytes()).await.map_err(Into::into)
+    }
+}
+
+/// Sends the emails through an SMTP relay
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(
+        host: &str,
+        port: u16,
+        tls: SmtpTls,
+        credentials: Option<(String, String)>,
+        from: &str,
+    ) -> Result<Self, Error> {
+        //! Create a mailer relaying through the given SMTP server
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! SmtpMailer::new("smtp.example.com", 587, SmtpTls::StartTls, None, "noreply@example.com");
+        //! ```
+        let smtp_error = |e: lettre::transport::smtp::Error| {
+            Error::ConfigurationError(format!("smtp relay: {}", e))
+        };
+        let builder = match tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            SmtpTls::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host).map_err(smtp_error)?
+            }
+            SmtpTls::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(host).map_err(smtp_error)?
+            }
+        }
+        .port(port);
+        let builder = match credentials {
+            Some((username, password)) => builder.credentials(Credentials::new(username, password)),
+            None => builder,
+        };
+
+        Ok(Self {
+            transport: builder.build(),
+            from: from
+                .parse()
+                .map_err(|_| Error::ConfigurationError(format!("invalid sender: {}", from)))?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: &Mail) -> Result<(), Error> {
+        let to: Mailbox = mail
+            .to
+            .parse()
+            .map_err(|_| Error::BadRequest(format!("invalid email: {}", mail.to)))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(mail.subject.as_str())
+            .header(ContentType::TEXT_PLAIN)
+            .body(mail.body.clone())
+            .map_err(|_| Error::InternalError)?;
+
+        self.transport.send(message).await.map(|_| ()).map_err(|e| {
+            log::error!("could not send the mail to {}: {}", mail.to, e);
+            Error::InternalError
+        })
+    }
+}
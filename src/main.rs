@@ -1,12 +1,17 @@
 use rocket::error::Error;
-use rocketapi::server::init_server;
+use rocketapi::server::{init_server, shutdown::GracefulShutdown};
 use std::process::exit;
 
 #[rocket::main]
 async fn main() -> Result<(), Error> {
     // start the server
     match init_server().await {
-        Ok(server) => server.launch().await.map(|_| ()),
+        Ok(server) => {
+            let server = server.launch().await?;
+            // the requests are drained, write what is still buffered
+            GracefulShutdown::flush(&server).await;
+            Ok(())
+        }
         Err(e) => {
             println!("{}", e);
             exit(1)
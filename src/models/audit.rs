@@ -0,0 +1,91 @@
+use chrono::offset::Utc;
+use mongodb::bson::{to_document, Bson};
+use serde::{Deserialize, Serialize};
+
+/// Fields never written to the audit log, only whether they changed
+const REDACTED_FIELDS: [&str; 3] = ["api_key", "signing_secret", "secret_hash"];
+const REDACTED: &str = "[redacted]";
+
+/// Change of one field of the audited record
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub before: Option<Bson>,
+    pub after: Option<Bson>,
+}
+
+/// Administrative action, as recorded in the append-only audit log
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Email of the admin who made the change
+    pub(crate) actor: String,
+    pub(crate) ip: String,
+    /// Unix timestamp of the change
+    pub(crate) timestamp: i64,
+    /// Route of the change, e.g. "update_user"
+    pub(crate) action: String,
+    /// Email, client id or key hash of the changed record
+    pub(crate) target: String,
+    pub(crate) changes: Vec<FieldChange>,
+}
+
+/// Filters of the audit log query, all optional
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    /// Unix timestamps of the time range, inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl AuditEntry {
+    pub fn new(action: &str, target: &str, actor: &str, ip: &str) -> Self {
+        Self {
+            actor: actor.to_string(),
+            ip: ip.to_string(),
+            timestamp: Utc::now().timestamp(),
+            action: action.to_string(),
+            target: target.to_string(),
+            changes: vec![],
+        }
+    }
+
+    /// Record the fields differing between the record before & after the change;
+    /// `None` for a record that was created or deleted.
+    pub fn diff<T: Serialize>(mut self, before: Option<&T>, after: Option<&T>) -> Self {
+        let before = before.and_then(|record| to_document(record).ok());
+        let after = after.and_then(|record| to_document(record).ok());
+
+        let mut fields: Vec<&String> = before
+            .iter()
+            .chain(after.iter())
+            .flat_map(|doc| doc.keys())
+            .collect();
+        fields.sort();
+        fields.dedup();
+
+        self.changes = fields
+            .into_iter()
+            .filter_map(|field| {
+                let old = before.as_ref().and_then(|doc| doc.get(field));
+                let new = after.as_ref().and_then(|doc| doc.get(field));
+                if old == new {
+                    return None;
+                }
+                let redact = |value: Option<&Bson>| {
+                    value.map(|value| match REDACTED_FIELDS.contains(&field.as_str()) {
+                        true => Bson::String(REDACTED.into()),
+                        false => value.clone(),
+                    })
+                };
+                Some(FieldChange {
+                    field: field.clone(),
+                    before: redact(old),
+                    after: redact(new),
+                })
+            })
+            .collect();
+        self
+    }
+}
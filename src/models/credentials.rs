@@ -0,0 +1,63 @@
+use chrono::{offset::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// Password & second factor of a user, kept apart from the user so that they
+/// never show up in the user listings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Credentials {
+    pub(crate) email: String,
+    /// argon2 PHC string of the password
+    #[serde(default)]
+    pub(crate) password_hash: Option<String>,
+    /// base32 TOTP secret, pending until `totp_enabled`
+    #[serde(default)]
+    pub(crate) totp_secret: Option<String>,
+    #[serde(default)]
+    pub(crate) totp_enabled: bool,
+    /// Last TOTP time step accepted, a code is only accepted once
+    #[serde(default)]
+    pub(crate) totp_last_step: i64,
+    /// sha256 of the unused recovery codes
+    #[serde(default)]
+    pub(crate) recovery_codes: Vec<String>,
+    pub(crate) updated_at: DateTime<Utc>,
+}
+
+impl Credentials {
+    pub fn new(email: &str) -> Self {
+        Self {
+            email: email.to_string(),
+            password_hash: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: 0,
+            recovery_codes: vec![],
+            updated_at: Utc::now(),
+        }
+    }
+
+    /// Check if a second factor is enrolled
+    pub fn has_second_factor(&self) -> bool {
+        self.totp_enabled
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewPassword {
+    pub password: String,
+    /// Needed when a password is already set
+    #[serde(default)]
+    pub current_password: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OtpCode {
+    pub code: String,
+}
+
+/// TOTP secret to enrol in an authenticator app
+#[derive(Clone, Debug, Serialize)]
+pub struct TotpEnrolment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
@@ -4,11 +4,16 @@ use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
     collections::HashMap,
     fmt,
-    sync::{Arc, Mutex, PoisonError},
+    sync::{Arc, Mutex, PoisonError, RwLock},
 };
-use throttle::Throttle;
 
-use crate::models::ratelimit::RateTime;
+use crate::{
+    error::Error,
+    models::ratelimit::{RateLimiter, RateTime},
+};
+
+/// Units consumed by a request when neither the route nor the ACL rule declares a cost
+const DEFAULT_COST: usize = 1;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Method {
@@ -60,6 +65,9 @@ pub struct Endpoint {
     pub name: String,
     pub method: Method,
     pub throttle: Option<RateTime>,
+    /// Units consumed from the throttle by one request, overrides the route cost
+    #[serde(default)]
+    pub cost: Option<usize>,
 }
 
 impl From<Endpoint> for Bson {
@@ -67,18 +75,120 @@ impl From<Endpoint> for Bson {
         Bson::Document(doc! {
             "name": endpoint.name,
             "method": endpoint.method.to_string(),
-            "rate_time": endpoint.throttle.map(|rate_time| Bson::String(rate_time.to_string())).unwrap_or(Bson::Null)
+            "rate_time": endpoint.throttle.map(|rate_time| Bson::String(rate_time.to_string())).unwrap_or(Bson::Null),
+            "cost": endpoint.cost.map(|cost| cost as i64)
         })
     }
 }
 
+/// Route costs & default throttle, replaced when the settings are reloaded
 #[derive(Default)]
+struct RateLimits {
+    costs: HashMap<(String, Method), usize>,
+    default_throttle: Option<RateTime>,
+}
+
+/// Cloning shares the throttles & rate limits, e.g. with the listener dedicated to `/metrics`.
+#[derive(Clone, Default)]
 pub struct EndpointHandler {
-    throttles: Arc<Mutex<HashMap<(String, String, Method), Option<Throttle>>>>,
+    throttles: Arc<Mutex<HashMap<(String, String, Method), Option<RateLimiter>>>>,
+    limits: Arc<RwLock<RateLimits>>,
+}
+
+fn route_name(name: &str) -> String {
+    name.strip_suffix('/').unwrap_or(name).to_string()
 }
 
 impl EndpointHandler {
+    pub fn with_cost(self, name: &str, method: Method, cost: usize) -> Self {
+        //! Declare the number of units a request to the route consumes
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! EndpointHandler::default().with_cost("/api/ping", Method::Any, 1);
+        //! ```
+        self.limits
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .costs
+            .insert((route_name(name), method), cost);
+        self
+    }
+
+    pub fn with_default_throttle(self, throttle: Option<RateTime>) -> Self {
+        //! Throttle the endpoints whose ACL rule sets none
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! EndpointHandler::default().with_default_throttle("100/min".parse().ok());
+        //! ```
+        self.limits
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .default_throttle = throttle;
+        self
+    }
+
+    /// Replace the route costs & the default throttle.
+    ///
+    /// The throttles start afresh, with the new limits.
+    pub fn set_rate_limits(&self, costs: Vec<(String, Method, usize)>, throttle: Option<RateTime>) {
+        *self.limits.write().unwrap_or_else(PoisonError::into_inner) = RateLimits {
+            costs: costs
+                .into_iter()
+                .map(|(name, method, cost)| ((route_name(&name), method), cost))
+                .collect(),
+            default_throttle: throttle,
+        };
+        self.throttles
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Get the cost of a request to the given endpoint.
+    ///
+    /// The cost set in the user's ACL rule wins over the one declared for the route.
+    pub fn cost_of(&self, endpoint: &Endpoint) -> usize {
+        if let Some(cost) = endpoint.cost {
+            return cost;
+        }
+        let name = route_name(&endpoint.name);
+
+        let limits = self.limits.read().unwrap_or_else(PoisonError::into_inner);
+        limits
+            .costs
+            .get(&(name.clone(), endpoint.method))
+            .or_else(|| limits.costs.get(&(name, Method::Any)))
+            .copied()
+            .unwrap_or(DEFAULT_COST)
+    }
+
     pub fn can_access(&self, api_key: String, endpoint: Endpoint) -> bool {
+        self.acquire(api_key, endpoint).is_ok()
+    }
+
+    /// Number of throttles held, one per user & endpoint seen
+    pub fn throttle_count(&self) -> usize {
+        self.throttles
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Consume the cost of the request from the user's throttle of the endpoint.
+    ///
+    /// Returns the units left in the throttle window (None if the endpoint is not throttled),
+    /// or Err(TooManyRequests) when the throttle cannot take the cost.
+    pub fn acquire(&self, api_key: String, endpoint: Endpoint) -> Result<Option<usize>, Error> {
+        let cost = self.cost_of(&endpoint);
+        let throttle = endpoint.throttle.clone().or_else(|| {
+            self.limits
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .default_throttle
+                .clone()
+        });
         let mut guarded_throttles = self
             .throttles
             .lock()
@@ -86,13 +196,83 @@ impl EndpointHandler {
 
         let throttle = guarded_throttles
             .entry((api_key, endpoint.name.to_string(), endpoint.method))
-            .or_insert_with(|| endpoint.throttle.clone().map(|rate| rate.into()));
+            .or_insert_with(|| throttle.map(|rate| rate.into()));
 
         if let Some(throttle) = throttle {
-            matches!(throttle.accept(), Ok(()))
+            if throttle.try_acquire(cost) {
+                Ok(Some(throttle.remaining()))
+            } else {
+                Err(Error::TooManyRequests)
+            }
         } else {
             // No throttle for the given endpoint
-            true
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(name: &str, method: Method, cost: Option<usize>) -> Endpoint {
+        Endpoint {
+            name: name.into(),
+            method,
+            throttle: "5/min".parse().ok(),
+            cost,
         }
     }
+
+    #[test]
+    fn resolves_the_cost_in_order() {
+        let get = Method::Http(http::Method::Get);
+        let post = Method::Http(http::Method::Post);
+        let handler = EndpointHandler::default()
+            .with_cost("/users/<email>", get, 3)
+            .with_cost("/users/<email>/", Method::Any, 2);
+
+        // the ACL rule wins
+        assert_eq!(
+            handler.cost_of(&endpoint("/users/<email>", get, Some(4))),
+            4
+        );
+        // then the cost of the method
+        assert_eq!(handler.cost_of(&endpoint("/users/<email>", get, None)), 3);
+        // then the cost of any method, the trailing slash ignored
+        assert_eq!(handler.cost_of(&endpoint("/users/<email>/", post, None)), 2);
+        // then the default
+        assert_eq!(
+            handler.cost_of(&endpoint("/users", get, None)),
+            DEFAULT_COST
+        );
+    }
+
+    #[test]
+    fn consumes_the_cost_from_the_throttle() {
+        let get = Method::Http(http::Method::Get);
+        let handler = EndpointHandler::default();
+        let key = || "key".to_string();
+
+        assert_eq!(
+            handler
+                .acquire(key(), endpoint("/users", get, Some(3)))
+                .unwrap(),
+            Some(2)
+        );
+        assert!(handler
+            .acquire(key(), endpoint("/users", get, Some(3)))
+            .is_err());
+        assert_eq!(
+            handler
+                .acquire(key(), endpoint("/users", get, Some(2)))
+                .unwrap(),
+            Some(0)
+        );
+        // each endpoint has its own throttle
+        assert_eq!(
+            handler.acquire(key(), endpoint("/api", get, None)).unwrap(),
+            Some(4)
+        );
+    }
 }
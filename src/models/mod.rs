@@ -1,3 +1,9 @@
+pub mod audit;
+pub mod credentials;
 pub mod endpoint;
+pub mod oauth;
 pub mod ratelimit;
+pub mod revocation;
+pub mod signup;
+pub mod usage;
 pub mod user;
@@ -0,0 +1,65 @@
+use chrono::{offset::Utc, DateTime};
+use openssl::memcmp;
+use serde::{Deserialize, Serialize};
+
+use crate::secure::token::hash_api_key;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NewOAuthClient {
+    /// Email of the user the client acts as
+    pub email: String,
+    pub description: String,
+    /// Scopes the client may request, empty for all the user's endpoints
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OAuthClient {
+    pub(crate) client_id: String,
+    /// sha256 of the client secret, the secret itself is only shown at creation
+    pub(crate) secret_hash: String,
+    pub(crate) email: String,
+    pub(crate) description: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) created_ip: String,
+    pub(crate) created_by: String,
+    pub(crate) created_at: DateTime<Utc>,
+}
+
+/// Client registered along with its secret
+#[derive(Clone, Debug, Serialize)]
+pub struct RegisteredOAuthClient {
+    #[serde(flatten)]
+    pub client: OAuthClient,
+    pub client_secret: String,
+}
+
+impl OAuthClient {
+    /// Check the secret presented by the client
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        let hash = hash_api_key(secret);
+        hash.len() == self.secret_hash.len()
+            && memcmp::eq(hash.as_bytes(), self.secret_hash.as_bytes())
+    }
+
+    /// Get the scopes granted for the requested ones.
+    ///
+    /// Returns None if any requested scope is not allowed for the client.
+    pub fn grant_scopes(&self, requested: Option<&str>) -> Option<Vec<String>> {
+        match requested.map(|scope| scope.split_whitespace().collect::<Vec<_>>()) {
+            Some(requested) if !requested.is_empty() => {
+                if self.scopes.is_empty()
+                    || requested
+                        .iter()
+                        .all(|scope| self.scopes.iter().any(|s| s == scope))
+                {
+                    Some(requested.into_iter().map(String::from).collect())
+                } else {
+                    None
+                }
+            }
+            _ => Some(self.scopes.clone()),
+        }
+    }
+}
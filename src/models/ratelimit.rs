@@ -1,5 +1,10 @@
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::{str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
 use strum::{Display, EnumString};
 use throttle::Throttle;
 
@@ -61,6 +66,82 @@ impl From<RateTime> for Throttle {
     }
 }
 
+/// Rate limiter built from a `RateTime` that can consume
+/// several units of the window at once.
+pub struct RateLimiter {
+    throttle: Throttle,
+    limit: usize,
+}
+
+impl RateLimiter {
+    /// Try to consume `cost` units from the current window.
+    ///
+    /// Returns true if the units were consumed, false (and consumes nothing) otherwise.
+    pub fn try_acquire(&mut self, cost: usize) -> bool {
+        if self.throttle.size() + cost > self.limit {
+            return false;
+        }
+        (0..cost).all(|_| self.throttle.accept().is_ok())
+    }
+
+    /// Number of units left in the current window.
+    pub fn remaining(&mut self) -> usize {
+        self.limit.saturating_sub(self.throttle.size())
+    }
+}
+
+impl From<RateTime> for RateLimiter {
+    fn from(rate: RateTime) -> Self {
+        Self {
+            limit: rate.frequency,
+            throttle: rate.into(),
+        }
+    }
+}
+
+/// Keys (client ips, emails) tracked before the idle ones are dropped
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// One throttle for each key (client ip, email), all built from the same `RateTime`
+pub struct KeyedLimiter {
+    throttle: RateTime,
+    // key => its throttle
+    limiters: Mutex<HashMap<String, RateLimiter>>,
+}
+
+impl KeyedLimiter {
+    pub fn new(throttle: RateTime) -> Self {
+        Self {
+            throttle,
+            limiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Consume a unit of the key's throttle, false (and nothing consumed) past it
+    pub fn try_acquire(&self, key: &str) -> bool {
+        let mut limiters = self.limiters.lock().unwrap_or_else(PoisonError::into_inner);
+        if limiters.len() >= MAX_TRACKED_KEYS && !limiters.contains_key(key) {
+            // the keys with a full throttle are the same as untracked ones
+            let limit = self.throttle.frequency;
+            limiters.retain(|_, limiter| limiter.remaining() < limit);
+        }
+
+        limiters
+            .entry(key.to_string())
+            .or_insert_with(|| self.throttle.clone().into())
+            .try_acquire(1)
+    }
+
+    /// Whether the key's throttle is used up, without consuming it
+    pub fn is_exhausted(&self, key: &str) -> bool {
+        self.limiters
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(key)
+            .is_some_and(|limiter| limiter.remaining() == 0)
+    }
+}
+
 impl FromStr for RateTime {
     type Err = RateTimeError;
 
@@ -74,3 +155,45 @@ impl FromStr for RateTime {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consumes_the_whole_cost_or_nothing() {
+        let mut limiter = RateLimiter::from("5/min".parse::<RateTime>().unwrap());
+        assert!(limiter.try_acquire(3));
+        assert_eq!(limiter.remaining(), 2);
+        // more than what is left: nothing is consumed
+        assert!(!limiter.try_acquire(3));
+        assert_eq!(limiter.remaining(), 2);
+        assert!(limiter.try_acquire(2));
+        assert_eq!(limiter.remaining(), 0);
+        assert!(!limiter.try_acquire(1));
+    }
+
+    #[test]
+    fn throttles_each_key() {
+        let limiter = KeyedLimiter::new("2/min".parse().unwrap());
+        assert!(limiter.try_acquire("10.0.0.1"));
+        assert!(!limiter.is_exhausted("10.0.0.1"));
+        assert!(limiter.try_acquire("10.0.0.1"));
+        assert!(limiter.is_exhausted("10.0.0.1"));
+        assert!(!limiter.try_acquire("10.0.0.1"));
+        // the other keys have their own throttle
+        assert!(!limiter.is_exhausted("10.0.0.2"));
+        assert!(limiter.try_acquire("10.0.0.2"));
+    }
+
+    #[test]
+    fn parses_the_rate_time() {
+        assert_eq!(
+            "10/Hour".parse::<RateTime>().unwrap().to_string(),
+            "10/hour"
+        );
+        assert!("10".parse::<RateTime>().is_err());
+        assert!("10/week".parse::<RateTime>().is_err());
+        assert!("ten/min".parse::<RateTime>().is_err());
+    }
+}
@@ -0,0 +1,22 @@
+use chrono::{offset::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// Revocation of a leaked api key, by the leaked key or its sha256
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NewRevocation {
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub key_hash: Option<String>,
+    pub reason: String,
+}
+
+/// Api key rejected on every request, whatever the credentials derived from it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Revocation {
+    /// sha256 of the revoked api key
+    pub(crate) key_hash: String,
+    pub(crate) reason: String,
+    pub(crate) revoked_by: String,
+    pub(crate) revoked_at: DateTime<Utc>,
+}
@@ -0,0 +1,50 @@
+use chrono::{offset::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NewSignup {
+    pub email: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Account waiting for its email to be verified
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingSignup {
+    pub(crate) email: String,
+    pub(crate) description: String,
+    /// sha256 of the verification token, the token itself is only mailed
+    pub(crate) token_hash: String,
+    /// Salt the verification token is derived from
+    #[serde(default)]
+    pub(crate) salt: String,
+    /// Unix timestamp the token was last mailed at
+    #[serde(default)]
+    pub(crate) mailed_at: i64,
+    pub(crate) created_ip: String,
+    pub(crate) created_at: DateTime<Utc>,
+    /// Unix timestamp the token expires at
+    pub(crate) expires_at: i64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SignupToken {
+    pub token: String,
+}
+
+impl NewSignup {
+    /// Check the email looks like an address: one `@`, no whitespace, a dotted domain
+    pub fn is_valid(&self) -> bool {
+        match self.email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+                    && !domain.contains('@')
+                    && !self.email.chars().any(char::is_whitespace)
+            }
+            None => false,
+        }
+    }
+}
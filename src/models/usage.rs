@@ -0,0 +1,62 @@
+use chrono::{offset::Utc, DateTime};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Seconds in a day, usage is counted per day
+pub const DAY_SECS: i64 = 24 * 3600;
+
+/// Usage of an api key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyUsage {
+    /// sha256 of the api key
+    pub(crate) key_hash: String,
+    pub(crate) email: String,
+    /// Unix timestamp of the last request
+    pub(crate) last_used_at: i64,
+    pub(crate) last_used_ip: String,
+    /// Requests since the key was first used
+    #[serde(default)]
+    pub(crate) request_count: i64,
+    /// Requests per day (days since the unix epoch) of the rolling window
+    #[serde(default)]
+    pub(crate) daily_counts: HashMap<String, i64>,
+}
+
+impl KeyUsage {
+    /// Requests of the last `days` days
+    pub fn requests_within(&self, days: i64, now: i64) -> i64 {
+        let today = now / DAY_SECS;
+        self.daily_counts
+            .iter()
+            .filter(|(day, _)| {
+                day.parse::<i64>()
+                    .is_ok_and(|day| day > today - days && day <= today)
+            })
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Usage of an api key, with its requests over the rolling window
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyUsageSummary {
+    pub key_hash: String,
+    pub email: String,
+    pub last_used_at: DateTime<Utc>,
+    pub last_used_ip: String,
+    pub request_count: i64,
+    pub window_requests: i64,
+}
+
+/// Api key unused for the requested number of days
+#[derive(Clone, Debug, Serialize)]
+pub struct StaleKey {
+    pub email: String,
+    pub key_hash: String,
+    pub is_admin: bool,
+    pub created_at: DateTime<Utc>,
+    /// None if the key was never used since the tracking started
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+    pub request_count: i64,
+}
@@ -10,6 +10,8 @@ pub struct NewUser {
     pub is_admin: bool,
     pub acl_allow_ips: Vec<String>,
     pub acl_allow_endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    pub cert_fingerprints: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -23,6 +25,10 @@ pub struct User {
     pub(crate) is_admin: bool,
     pub(crate) acl_allow_ips: Vec<String>,
     pub(crate) acl_allow_endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    pub(crate) signing_secret: Option<String>,
+    #[serde(default)]
+    pub(crate) cert_fingerprints: Vec<String>,
 }
 
 impl User {
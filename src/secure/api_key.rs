@@ -0,0 +1,173 @@
+use rocket::request::Request;
+use serde::{Deserialize, Serialize};
+
+/// Default name of the cookie & query parameter carrying the api key
+pub const API_KEY_PARAM: &str = "api_key";
+
+/// Where the api key of a request may be read from
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeySource {
+    /// `x-api-key: <key>`
+    Header,
+    /// `Authorization: Bearer <key>`
+    Bearer,
+    /// `Authorization: ApiKey <key>`
+    ApiKey,
+    /// A named cookie
+    Cookie,
+    /// A named query parameter, for clients that can not set headers
+    Query,
+}
+
+/// Credential sources of the api key, tried in order
+#[derive(Debug, Clone)]
+pub struct ApiKeySources {
+    sources: Vec<ApiKeySource>,
+    cookie_name: String,
+    query_param: String,
+}
+
+impl Default for ApiKeySources {
+    fn default() -> Self {
+        Self {
+            sources: vec![ApiKeySource::Header],
+            cookie_name: API_KEY_PARAM.to_string(),
+            query_param: API_KEY_PARAM.to_string(),
+        }
+    }
+}
+
+impl ApiKeySources {
+    pub fn new(sources: Vec<ApiKeySource>, cookie_name: String, query_param: String) -> Self {
+        //! Read the api key from the given sources, the first one present wins
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! ApiKeySources::new(
+        //!     vec![ApiKeySource::Header, ApiKeySource::Query],
+        //!     "api_key".to_string(),
+        //!     "api_key".to_string(),
+        //! );
+        //! ```
+        Self {
+            sources,
+            cookie_name,
+            query_param,
+        }
+    }
+
+    /// Name of the query parameter carrying the api key, when that source is enabled
+    pub fn query_param(&self) -> Option<&str> {
+        self.sources
+            .contains(&ApiKeySource::Query)
+            .then_some(self.query_param.as_str())
+    }
+
+    /// Get the api key of the request from the first source carrying one
+    pub fn api_key(&self, request: &Request<'_>) -> Option<String> {
+        self.sources
+            .iter()
+            .find_map(|source| self.read(*source, request))
+            .filter(|api_key| !api_key.is_empty())
+    }
+
+    fn read(&self, source: ApiKeySource, request: &Request<'_>) -> Option<String> {
+        match source {
+            ApiKeySource::Header => request.headers().get_one("x-api-key").map(str::to_string),
+            ApiKeySource::Bearer => authorization(request, "Bearer "),
+            ApiKeySource::ApiKey => authorization(request, "ApiKey "),
+            ApiKeySource::Cookie => request
+                .cookies()
+                .get(&self.cookie_name)
+                .map(|cookie| cookie.value().to_string()),
+            ApiKeySource::Query => request
+                .query_value::<String>(&self.query_param)
+                .and_then(Result::ok),
+        }
+        .map(|api_key| api_key.trim().to_string())
+    }
+}
+
+fn authorization(request: &Request<'_>, scheme: &str) -> Option<String> {
+    request
+        .headers()
+        .get_one("authorization")
+        .and_then(|header| header.trim().strip_prefix(scheme))
+        .map(str::to_string)
+}
+
+/// Replace the value of the given query parameter in a log line
+pub fn redact_query_param(line: &str, param: &str) -> String {
+    let needle = format!("{}=", param);
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(start) = rest.find(&needle) {
+        // only match a whole parameter name
+        let is_param = start == 0 || rest[..start].ends_with(['?', '&', ';']);
+        let value_start = start + needle.len();
+        redacted.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+
+        if is_param {
+            let value_end = rest
+                .find(|c: char| c == '&' || c == '#' || c.is_whitespace() || c.is_control())
+                .unwrap_or(rest.len());
+            if value_end > 0 {
+                redacted.push_str("REDACTED");
+            }
+            rest = &rest[value_end..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(line: &str) -> String {
+        redact_query_param(line, API_KEY_PARAM)
+    }
+
+    #[test]
+    fn redacts_the_value() {
+        assert_eq!(
+            redact("GET /api/ping?api_key=s3cr3t text/html:"),
+            "GET /api/ping?api_key=REDACTED text/html:"
+        );
+        assert_eq!(redact("api_key=s3cr3t"), "api_key=REDACTED");
+        assert_eq!(redact("/x?api_key=ключ\n"), "/x?api_key=REDACTED\n");
+    }
+
+    #[test]
+    fn redacts_every_occurrence() {
+        assert_eq!(
+            redact("/x?a=1&api_key=k1&b=2;api_key=k2#top"),
+            "/x?a=1&api_key=REDACTED&b=2;api_key=REDACTED#top"
+        );
+    }
+
+    #[test]
+    fn keeps_other_params() {
+        for line in [
+            "GET /api/ping?x=1",
+            "/x?my_api_key=k&api_keys=k",
+            "/x?api_key=&b=2",
+            "no query at all",
+            "",
+        ] {
+            assert_eq!(redact(line), line);
+        }
+    }
+
+    #[test]
+    fn custom_param() {
+        assert_eq!(
+            redact_query_param("/x?token=t&api_key=k", "token"),
+            "/x?token=REDACTED&api_key=k"
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::future::Future;
+
+use crate::{
+    error::Error,
+    models::ratelimit::{KeyedLimiter, RateTime},
+};
+
+/// Throttle of the failed password & second factor checks, for each user
+/// and for each client ip: past it, the credentials are not even checked.
+pub struct FailedAttempts {
+    users: KeyedLimiter,
+    ips: KeyedLimiter,
+}
+
+impl FailedAttempts {
+    pub fn new(throttle: RateTime) -> Self {
+        Self {
+            users: KeyedLimiter::new(throttle.clone()),
+            ips: KeyedLimiter::new(throttle),
+        }
+    }
+
+    /// Run the credential check of the user from the client ip, counting it when it fails.
+    ///
+    /// Err(TooManyRequests) without checking once the user or the ip used up its attempts.
+    pub async fn check<T>(
+        &self,
+        email: &str,
+        ip: &str,
+        check: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        if self.users.is_exhausted(email) || self.ips.is_exhausted(ip) {
+            return Err(Error::TooManyRequests);
+        }
+
+        let result = check.await;
+        if let Err(Error::UnauthenticatedUser | Error::ForbiddenAccess) = result {
+            self.users.try_acquire(email);
+            self.ips.try_acquire(ip);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rocket::async_test]
+    async fn throttles_the_failures_of_the_user_and_the_ip() {
+        let attempts = FailedAttempts::new("2/min".parse().unwrap());
+        let fail = || async { Err::<(), _>(Error::UnauthenticatedUser) };
+        let succeed = || async { Ok(()) };
+
+        // successes & other errors are not counted
+        assert!(attempts
+            .check("a@example.com", "10.0.0.1", succeed())
+            .await
+            .is_ok());
+        let bad_request = async { Err::<(), _>(Error::BadRequest("too short".into())) };
+        assert!(attempts
+            .check("a@example.com", "10.0.0.1", bad_request)
+            .await
+            .is_err());
+
+        for _ in 0..2 {
+            let failed = attempts.check("a@example.com", "10.0.0.1", fail()).await;
+            assert!(matches!(failed, Err(Error::UnauthenticatedUser)));
+        }
+        // the user is locked out from any ip, and the ip for any user
+        let locked = attempts.check("a@example.com", "10.0.0.2", succeed()).await;
+        assert!(matches!(locked, Err(Error::TooManyRequests)));
+        let locked = attempts.check("b@example.com", "10.0.0.1", succeed()).await;
+        assert!(matches!(locked, Err(Error::TooManyRequests)));
+
+        assert!(attempts
+            .check("b@example.com", "10.0.0.2", succeed())
+            .await
+            .is_ok());
+    }
+}
@@ -1,18 +1,18 @@
 use derive_more::Deref;
 use rocket::{
     http::Status,
-    outcome::try_outcome,
-    request::{FromRequest, Outcome, Request},
+    outcome::{try_outcome, Outcome},
+    request::{self, FromRequest, Request},
     State,
 };
-
 use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 
 use super::GuardedData;
 use crate::{
     db::MongodbBackend,
     error::Error,
-    models::{endpoint::EndpointHandler, user::User},
+    models::{endpoint::Endpoint, user::User},
 };
 
 #[derive(Serialize, Deserialize, Deref)]
@@ -21,25 +21,87 @@ pub struct UserGuard(pub GuardedData<User>);
 #[derive(Serialize, Deserialize, Deref)]
 pub struct AdminGuard(pub GuardedData<User>);
 
+/// User authenticated by its own credentials (not by an access token),
+/// connecting from an allowed ip; the endpoint ACL & throttles are not applied.
+#[derive(Serialize, Deserialize, Deref)]
+pub struct CredentialsGuard(pub GuardedData<User>);
+
+/// How the caller of the request proved its identity
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    /// Api key, from any of the configured sources
+    ApiKey,
+    /// HMAC signed request
+    Signature,
+    /// Client certificate alone
+    Certificate,
+    /// Short lived access token
+    Token,
+    /// Admin session cookie
+    Session,
+}
+
+/// Authenticated caller of the request
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthContext {
+    /// User owning the api key
+    pub user: User,
+    /// ACL rule of the user that matched the request
+    pub endpoint: Endpoint,
+    /// Client ip address the ACL was checked against
+    pub ip: IpAddr,
+    /// Units left in the throttle window, None if the endpoint is not throttled
+    pub remaining: Option<usize>,
+    /// How the user was authenticated
+    pub auth_method: AuthMethod,
+}
+
+impl AuthContext {
+    fn guarded_user(&self) -> GuardedData<User> {
+        GuardedData {
+            inner: self.user.clone(),
+            ip: self.ip,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthContext {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match super::get_auth_context(request).await {
+            Ok(context) => Outcome::Success(context.clone()),
+            Err(e) => Outcome::Failure((e.to_status(), e)),
+        }
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for UserGuard {
     type Error = Error;
 
-    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let backend = try_outcome!(request
-            .guard::<&State<MongodbBackend>>()
-            .await
-            .map_failure(|_| (Status::InternalServerError, Error::InternalError)));
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match super::get_auth_context(request).await {
+            Ok(context) => Outcome::Success(Self(context.guarded_user())),
+            Err(e) => Outcome::Failure((e.to_status(), e)),
+        }
+    }
+}
 
-        let handler = try_outcome!(request
-            .guard::<&State<EndpointHandler>>()
-            .await
-            .map_failure(|_| (Status::InternalServerError, Error::InternalError)));
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminGuard {
+    type Error = Error;
 
-        match super::get_user_from_request(request, backend, handler)
-            .await
-            .map(Self)
-        {
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match super::get_auth_context(request).await.and_then(|context| {
+            if context.user.is_admin() {
+                Ok(Self(context.guarded_user()))
+            } else {
+                Err(Error::ForbiddenAccess)
+            }
+        }) {
             Ok(guard) => Outcome::Success(guard),
             Err(e) => Outcome::Failure((e.to_status(), e)),
         }
@@ -47,28 +109,20 @@ impl<'r> FromRequest<'r> for UserGuard {
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for AdminGuard {
+impl<'r> FromRequest<'r> for CredentialsGuard {
     type Error = Error;
 
-    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         let backend = try_outcome!(request
             .guard::<&State<MongodbBackend>>()
             .await
             .map_failure(|_| (Status::InternalServerError, Error::InternalError)));
 
-        let handler = try_outcome!(request
-            .guard::<&State<EndpointHandler>>()
-            .await
-            .map_failure(|_| (Status::InternalServerError, Error::InternalError)));
-
-        match super::get_user_from_request(request, backend, handler)
+        match super::get_authenticated_user(request, backend)
             .await
-            .and_then(|user| {
-                if user.is_admin() {
-                    Ok(Self(user))
-                } else {
-                    Err(Error::ForbiddenAccess)
-                }
+            .and_then(|(user, auth_method)| match auth_method {
+                AuthMethod::Token => Err(Error::UnauthenticatedUser),
+                _ => Ok(Self(user)),
             }) {
             Ok(guard) => Outcome::Success(guard),
             Err(e) => Outcome::Failure((e.to_status(), e)),
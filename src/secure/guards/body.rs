@@ -0,0 +1,59 @@
+use derive_more::Deref;
+use rocket::{
+    data::{self, Data, FromData, Limits},
+    http::Status,
+    outcome::Outcome,
+    request::Request,
+    serde::json,
+};
+use serde::de::DeserializeOwned;
+
+use super::{get_auth_context, AuthMethod};
+use crate::{
+    error::Error,
+    secure::signature::{content_hash, CONTENT_HASH_HEADER},
+};
+
+/// JSON body, whose hash is checked against the signed `x-content-sha256`
+/// header when the request is authenticated with a HMAC signature.
+///
+/// Use it instead of `Json<T>` on every route taking a body.
+#[derive(Debug, Deref)]
+pub struct SignedJson<T>(pub T);
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for SignedJson<T> {
+    type Error = Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_bytes().await {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                let e = Error::BadRequest("request body too large".into());
+                return Outcome::Failure((Status::PayloadTooLarge, e));
+            }
+            Err(e) => return Outcome::Failure((Status::BadRequest, e.into())),
+        };
+
+        if let Ok(context) = get_auth_context(request).await {
+            if context.auth_method == AuthMethod::Signature
+                && request
+                    .headers()
+                    .get_one(CONTENT_HASH_HEADER)
+                    .map(str::to_lowercase)
+                    != Some(content_hash(&body))
+            {
+                return Outcome::Failure((Status::Unauthorized, Error::UnauthenticatedUser));
+            }
+        }
+
+        match json::from_slice(&body) {
+            Ok(value) => Outcome::Success(Self(value)),
+            Err(e) => Outcome::Failure((
+                Status::UnprocessableEntity,
+                Error::FormatError(e.to_string()),
+            )),
+        }
+    }
+}
@@ -1,8 +1,11 @@
 use rocket::{
+    http::Status,
     outcome::Outcome,
     request::{self, FromRequest, Request},
 };
 
+use crate::secure::ip::client_ip;
+
 /// Gets the Connecting Client's IP address & Useragent Information
 #[derive(Debug)]
 pub struct ClientInfo {
@@ -21,30 +24,15 @@ impl<'r> FromRequest<'r> for ClientInfo {
     type Error = ();
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let ua = request.headers().get("user-agent").next().unwrap();
-        let browser_info = if ua.is_empty() {
-            "".to_string()
-        } else {
-            ua.to_string()
-        };
+        let browser_info = request
+            .headers()
+            .get_one("user-agent")
+            .unwrap_or_default()
+            .to_string();
 
-        match request.real_ip() {
-            Some(ip) => {
-                let state = ClientInfo::new(ip.to_string(), browser_info);
-                Outcome::Success(state)
-            }
-            None => match request.headers().get("x-forwarded-for").next() {
-                Some(forwarded_ip) => {
-                    let forwarded_ip = forwarded_ip.split(',').next().unwrap();
-                    let state = ClientInfo::new(forwarded_ip.to_string(), browser_info);
-                    Outcome::Success(state)
-                }
-                None => {
-                    let ip = request.client_ip().unwrap();
-                    let state = ClientInfo::new(ip.to_string(), browser_info);
-                    Outcome::Success(state)
-                }
-            },
+        match client_ip(request) {
+            Some(ip) => Outcome::Success(ClientInfo::new(ip.to_string(), browser_info)),
+            None => Outcome::Failure((Status::BadRequest, ())),
         }
     }
 }
@@ -0,0 +1,25 @@
+use rocket::{
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+};
+
+use crate::{error::Error, secure::ip::client_ip, server::metrics::MetricsAcl};
+
+/// Client allowed to read the metrics by the metrics ACL; no api key is involved
+pub struct MetricsGuard;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsGuard {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let acl = request.rocket().state::<MetricsAcl>();
+        match (acl, client_ip(request)) {
+            (Some(acl), Some(ip)) if acl.is_allowed(&ip) => Outcome::Success(Self),
+            _ => {
+                let e = Error::ForbiddenAccess;
+                Outcome::Failure((e.to_status(), e))
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use chrono::offset::Utc;
+use rocket::{
+    http::Status,
+    outcome::{try_outcome, Outcome},
+    request::{self, FromRequest, Request},
+    State,
+};
+
+use super::AuthMethod;
+use crate::{
+    db::MongodbBackend,
+    error::Error,
+    secure::{
+        attempts::FailedAttempts,
+        session::SessionManager,
+        totp::{MfaPolicy, OTP_HEADER},
+    },
+};
+
+/// Proof of a recent second factor of the caller, when the `MfaPolicy` requires one:
+/// a session whose second factor is recent enough, or else a TOTP or recovery code
+/// in the `x-otp` header.
+pub struct SecondFactorGuard;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SecondFactorGuard {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let policy = match request.rocket().state::<MfaPolicy>() {
+            Some(policy) if policy.require_for_user_admin => policy,
+            _ => return Outcome::Success(Self),
+        };
+        let backend = try_outcome!(request
+            .guard::<&State<MongodbBackend>>()
+            .await
+            .map_failure(|_| (Status::InternalServerError, Error::InternalError)));
+        let attempts = try_outcome!(request
+            .guard::<&State<FailedAttempts>>()
+            .await
+            .map_failure(|_| (Status::InternalServerError, Error::InternalError)));
+
+        match verify(request, policy, backend, attempts).await {
+            Ok(_) => Outcome::Success(Self),
+            Err(e) => Outcome::Failure((e.to_status(), e)),
+        }
+    }
+}
+
+async fn verify(
+    request: &Request<'_>,
+    policy: &MfaPolicy,
+    backend: &MongodbBackend,
+    attempts: &FailedAttempts,
+) -> Result<(), Error> {
+    let context = super::get_auth_context(request).await?;
+
+    if context.auth_method == AuthMethod::Session {
+        let mfa_at = request
+            .rocket()
+            .state::<SessionManager>()
+            .and_then(|sessions| sessions.session(request))
+            .and_then(|session| session.mfa_at);
+        if mfa_at.is_some_and(|mfa_at| Utc::now().timestamp() - mfa_at <= policy.max_age_secs) {
+            return Ok(());
+        }
+    }
+
+    match request.headers().get_one(OTP_HEADER) {
+        Some(code) => {
+            let verified = backend.verify_second_factor(&context.user.email, code);
+            attempts
+                .check(&context.user.email, &context.ip.to_string(), verified)
+                .await
+        }
+        None => Err(Error::ForbiddenAccess),
+    }
+}
@@ -1,20 +1,44 @@
 pub mod auth;
+pub mod body;
 pub mod client;
+pub mod metrics;
+pub mod mfa;
 
-pub use auth::{AdminGuard, UserGuard};
+pub use auth::{AdminGuard, AuthContext, AuthMethod, CredentialsGuard, UserGuard};
+pub use body::SignedJson;
+pub use metrics::MetricsGuard;
+pub use mfa::SecondFactorGuard;
 
 use derive_more::Deref;
-use rocket::request::Request;
+use opentelemetry::{
+    trace::{FutureExt, TraceContextExt},
+    KeyValue,
+};
+use rocket::{request::Request, State};
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 
 use crate::{
-    db::MongodbBackend,
+    db::{usage::UsageTracker, MongodbBackend},
     error::Error,
     models::{
         endpoint::{Endpoint, EndpointHandler, Method},
         user::User,
     },
+    secure::{
+        api_key::ApiKeySources,
+        ip::client_ip,
+        jwt::TokenIssuer,
+        mtls::{certificate_identity, MtlsMode},
+        revocation::RevocationList,
+        session::SessionManager,
+        signature::SignatureVerifier,
+        token::hash_api_key,
+    },
+    server::{
+        metrics::Metrics,
+        telemetry::{end_span, start_span},
+    },
 };
 
 #[derive(Serialize, Deserialize, Deref)]
@@ -24,43 +48,306 @@ pub struct GuardedData<T> {
     pub ip: IpAddr,
 }
 
+/// Outcome of the authentication of a request, resolved once and kept in the request-local cache
+struct CachedAuth(Result<AuthContext, Error>);
+
+/// Get the authentication context of the request.
+///
+/// The user is looked up and the throttle decremented only once per request,
+/// whatever the number of guards asking for it.
+pub(crate) async fn get_auth_context<'r>(
+    request: &'r Request<'_>,
+) -> Result<&'r AuthContext, Error> {
+    let cached = request
+        .local_cache_async(async {
+            let backend = request.guard::<&State<MongodbBackend>>().await.succeeded();
+            let handler = request.guard::<&State<EndpointHandler>>().await.succeeded();
+
+            let span = start_span("auth_context", vec![]);
+            let context = match (backend, handler) {
+                (Some(backend), Some(handler)) => {
+                    get_user_from_request(request, backend, handler)
+                        .with_context(span.clone())
+                        .await
+                }
+                _ => Err(Error::InternalError),
+            };
+            if let Ok(context) = &context {
+                span.span()
+                    .set_attribute(KeyValue::new("enduser.id", context.user.email.clone()));
+            }
+            end_span(&span, &context);
+            if let (Err(e), Some(metrics)) = (&context, request.rocket().state::<Metrics>()) {
+                metrics.record_auth_failure(e);
+            }
+            CachedAuth(context)
+        })
+        .await;
+
+    cached.0.as_ref().map_err(|e| match e {
+        // the cached error can not be moved out, so give back an equivalent one
+        Error::UnauthenticatedUser => Error::UnauthenticatedUser,
+        Error::ForbiddenAccess => Error::ForbiddenAccess,
+        Error::TooManyRequests => Error::TooManyRequests,
+        Error::NotFound => Error::NotFound,
+        _ => Error::InternalError,
+    })
+}
+
+/// Authentication context of the request, if a guard already resolved it successfully
+pub(crate) fn cached_auth_context<'r>(request: &'r Request<'_>) -> Option<&'r AuthContext> {
+    request
+        .local_cache(|| CachedAuth(Err(Error::NotFound)))
+        .0
+        .as_ref()
+        .ok()
+}
+
 pub(crate) async fn get_user_from_request(
     request: &Request<'_>,
     backend: &MongodbBackend,
     handler: &EndpointHandler,
-) -> Result<GuardedData<User>, Error> {
-    match request
-        .headers()
-        .get_one("x-api-key")
-        .map(|header| header.trim())
-        .ok_or(Error::UnauthenticatedUser)
-    {
-        Ok(api_key) => backend
-            .get_user_from_api_key(api_key)
+) -> Result<AuthContext, Error> {
+    let authenticated = authenticate(request, backend).await?;
+    authorize(request, handler, authenticated)
+}
+
+/// Identify the user sending the request, and check it may connect from the client ip.
+///
+/// Unlike `get_user_from_request`, the endpoint ACL & throttles are not applied.
+pub(crate) async fn get_authenticated_user(
+    request: &Request<'_>,
+    backend: &MongodbBackend,
+) -> Result<(GuardedData<User>, AuthMethod), Error> {
+    let Authenticated {
+        user, auth_method, ..
+    } = authenticate(request, backend).await?;
+
+    match client_ip(request) {
+        Some(ip) if user.is_ip_allowed(&ip.to_string()) => {
+            Ok((GuardedData { inner: user, ip }, auth_method))
+        }
+        _ => Err(Error::ForbiddenAccess),
+    }
+}
+
+/// User identified from the request credentials
+struct Authenticated {
+    user: User,
+    auth_method: AuthMethod,
+    /// Hash of the user's api key, identifies the user's throttles
+    key_hash: String,
+}
+
+impl Authenticated {
+    fn new(user: User, auth_method: AuthMethod) -> Self {
+        Self {
+            key_hash: hash_api_key(&user.api_key),
+            user,
+            auth_method,
+        }
+    }
+}
+
+/// Identify the user sending the request, rejecting revoked api keys.
+///
+/// The api key of the request is checked before anything else, then the key
+/// the credentials derive from (access tokens, sessions, signatures, certificates).
+async fn authenticate(
+    request: &Request<'_>,
+    backend: &MongodbBackend,
+) -> Result<Authenticated, Error> {
+    let revocations = request.rocket().state::<RevocationList>();
+    let is_revoked =
+        |key_hash: &str| revocations.is_some_and(|revoked| revoked.is_revoked(key_hash));
+
+    if request_api_key(request).is_some_and(|api_key| is_revoked(&hash_api_key(&api_key))) {
+        return Err(Error::UnauthenticatedUser);
+    }
+
+    let authenticated = identify(request, backend).await?;
+    if is_revoked(&authenticated.key_hash) {
+        return Err(Error::UnauthenticatedUser);
+    }
+    Ok(authenticated)
+}
+
+/// Get the api key of the request from the configured sources
+fn request_api_key(request: &Request<'_>) -> Option<String> {
+    match request.rocket().state::<ApiKeySources>() {
+        Some(sources) => sources.api_key(request),
+        None => ApiKeySources::default().api_key(request),
+    }
+}
+
+/// Identify the user from the certificate and/or the credentials of the request
+async fn identify(request: &Request<'_>, backend: &MongodbBackend) -> Result<Authenticated, Error> {
+    let mtls_mode = request
+        .rocket()
+        .state::<MtlsMode>()
+        .copied()
+        .unwrap_or_default();
+
+    if mtls_mode == MtlsMode::Disabled {
+        return authenticate_credentials(request, backend).await;
+    }
+
+    let cert_user = match certificate_identity(request).await {
+        Some(identity) => Some(backend.get_user_from_certificate(&identity).await?),
+        None => None,
+    };
+
+    match (mtls_mode, cert_user) {
+        (MtlsMode::Certificate, Some(user)) => {
+            Ok(Authenticated::new(user, AuthMethod::Certificate))
+        }
+        (MtlsMode::CertificateAndApiKey, Some(cert_user)) => {
+            let authenticated = authenticate_credentials(request, backend).await?;
+            if authenticated.user.email == cert_user.email {
+                Ok(authenticated)
+            } else {
+                Err(Error::UnauthenticatedUser)
+            }
+        }
+        (MtlsMode::CertificateAndApiKey, None) => Err(Error::UnauthenticatedUser),
+        _ => authenticate_credentials(request, backend).await,
+    }
+}
+
+/// Identify the user from the access token, the signature or the api key of the request,
+/// the api key being read from the configured sources; or else from the admin session cookie
+async fn authenticate_credentials(
+    request: &Request<'_>,
+    backend: &MongodbBackend,
+) -> Result<Authenticated, Error> {
+    if let Some(token) = TokenIssuer::bearer_token(request) {
+        // validated without touching the database
+        let claims = request
+            .rocket()
+            .state::<TokenIssuer>()
+            .ok_or(Error::UnauthenticatedUser)?
+            .validate(token)?;
+
+        return Ok(Authenticated {
+            user: claims.to_user(),
+            auth_method: AuthMethod::Token,
+            key_hash: claims.kid,
+        });
+    }
+
+    if let Some(signed) = SignatureVerifier::signed_request(request) {
+        let signed = signed?;
+        let verifier = request
+            .rocket()
+            .state::<SignatureVerifier>()
+            .ok_or(Error::UnauthenticatedUser)?;
+
+        verifier.check_timestamp(&signed)?;
+        let user = backend.get_user_from_email(&signed.email).await?;
+        let secret = user
+            .signing_secret
+            .as_deref()
+            .ok_or(Error::UnauthenticatedUser)?;
+        verifier.verify(request, &signed, secret)?;
+        // burnt once the signature is checked, so no one else can burn it
+        backend
+            .use_signature_nonce(&signed.email, &signed.nonce, verifier.nonce_expiry(&signed))
+            .await?;
+
+        return Ok(Authenticated::new(user, AuthMethod::Signature));
+    }
+
+    match request_api_key(request) {
+        Some(api_key) => backend
+            .get_user_from_api_key(&api_key)
             .await
-            .and_then(|user| {
-                let uri = request.uri().path().as_str();
-                let method = Method::Http(request.method());
-
-                match (user.get_endpoint_allowed(uri, &method), request.client_ip()) {
-                    (Some(endpoint), Some(ip)) if user.is_ip_allowed(&ip.to_string()) => {
-                        if handler.can_access(
-                            api_key.to_string(),
-                            // handle "*" by overriding these value with concrete ones
-                            Endpoint {
-                                name: uri.to_string(),
-                                method,
-                                throttle: endpoint.throttle.clone(),
-                            },
-                        ) {
-                            Ok(GuardedData { inner: user, ip })
-                        } else {
-                            Err(Error::TooManyRequests)
-                        }
+            .map(|user| Authenticated::new(user, AuthMethod::ApiKey)),
+        None => authenticate_session(request, backend).await,
+    }
+}
+
+/// Identify the admin from the session cookie
+async fn authenticate_session(
+    request: &Request<'_>,
+    backend: &MongodbBackend,
+) -> Result<Authenticated, Error> {
+    let session = request
+        .rocket()
+        .state::<SessionManager>()
+        .ok_or(Error::UnauthenticatedUser)?
+        .verify_live(request, backend)
+        .await?;
+
+    // the user may have lost the admin rights since the login
+    match backend.get_user_from_email(&session.email).await? {
+        user if user.is_admin() => Ok(Authenticated::new(user, AuthMethod::Session)),
+        _ => Err(Error::ForbiddenAccess),
+    }
+}
+
+/// Check the user's ip & endpoint ACLs, and consume the request from the throttle
+fn authorize(
+    request: &Request<'_>,
+    handler: &EndpointHandler,
+    authenticated: Authenticated,
+) -> Result<AuthContext, Error> {
+    let Authenticated {
+        user,
+        auth_method,
+        key_hash,
+    } = authenticated;
+    let uri = request.uri().path().as_str();
+    let method = Method::Http(request.method());
+
+    match (user.get_endpoint_allowed(uri, &method), client_ip(request)) {
+        (Some(endpoint), Some(ip)) if user.is_ip_allowed(&ip.to_string()) => {
+            let endpoint = endpoint.clone();
+            // the route costs are declared on the rocket route, e.g. `/users/<email>`
+            let route = request
+                .route()
+                .map_or(uri, |route| route.uri.path())
+                .to_string();
+            let cost = handler.cost_of(&Endpoint {
+                name: route,
+                method,
+                throttle: None,
+                cost: endpoint.cost,
+            });
+            let span = start_span(
+                "throttle",
+                vec![KeyValue::new("rocketapi.endpoint", endpoint.name.clone())],
+            );
+            let remaining = handler
+                .acquire(
+                    key_hash.clone(),
+                    // handle "*" by overriding these value with concrete ones
+                    Endpoint {
+                        name: uri.to_string(),
+                        method,
+                        throttle: endpoint.throttle.clone(),
+                        cost: Some(cost),
+                    },
+                )
+                .inspect_err(|_| {
+                    if let Some(metrics) = request.rocket().state::<Metrics>() {
+                        metrics.record_throttle_rejection(&user.email);
                     }
-                    _ => Err(Error::ForbiddenAccess),
-                }
-            }),
-        Err(e) => Err(e),
+                });
+            end_span(&span, &remaining);
+            let remaining = remaining?;
+
+            if let Some(tracker) = request.rocket().state::<UsageTracker>() {
+                tracker.record(&key_hash, &user.email, &ip.to_string());
+            }
+
+            Ok(AuthContext {
+                user,
+                endpoint,
+                ip,
+                remaining,
+                auth_method,
+            })
+        }
+        _ => Err(Error::ForbiddenAccess),
     }
 }
@@ -0,0 +1,352 @@
+use ipnet::IpNet;
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    http::{uri::Origin, HeaderMap, Method, Status},
+    request::Request,
+    route::{self, Route},
+    Build, Data, Rocket,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, PoisonError, RwLock},
+};
+
+use crate::server::proxy_protocol::ProxiedPeers;
+
+/// Resolves the real client ip address of a request.
+///
+/// The `Forwarded`, `X-Forwarded-For` & `X-Real-IP` headers are only
+/// honoured when the connecting peer is one of the trusted proxies.
+/// Cloning shares the trusted proxies, replaced when the settings are reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpResolver {
+    trusted_proxies: Arc<RwLock<Vec<IpNet>>>,
+    proxied_peers: Option<ProxiedPeers>,
+}
+
+impl ClientIpResolver {
+    pub fn new(trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            trusted_proxies: Arc::new(RwLock::new(trusted_proxies)),
+            proxied_peers: None,
+        }
+    }
+
+    pub fn set_trusted_proxies(&self, trusted_proxies: Vec<IpNet>) {
+        *self
+            .trusted_proxies
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = trusted_proxies;
+    }
+
+    /// Take the peer address from the listener relaying the connection (PROXY protocol or TLS)
+    pub fn with_proxied_peers(mut self, peers: ProxiedPeers) -> Self {
+        self.proxied_peers = Some(peers);
+        self
+    }
+
+    /// Get the address of the connecting peer
+    pub fn peer(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let remote = request.remote()?;
+        match &self.proxied_peers {
+            Some(peers) => peers.get(&remote).map(|peer| peer.ip()),
+            None => Some(remote.ip()),
+        }
+    }
+
+    /// Check if the given address belongs to a trusted proxy
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|net| net.contains(ip))
+    }
+
+    /// Get the client ip address of the request
+    pub fn resolve(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let peer = self.peer(request)?;
+        Some(self.resolve_peer(peer, request.headers()))
+    }
+
+    /// Get the client behind the peer, from the forwarding headers of the trusted proxies
+    fn resolve_peer(&self, peer: IpAddr, headers: &HeaderMap<'_>) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+
+        let chain = if let Some(forwarded) = headers.get_one("forwarded") {
+            parse_forwarded(forwarded)
+        } else if let Some(forwarded_for) = headers.get_one("x-forwarded-for") {
+            forwarded_for.split(',').map(parse_node).collect()
+        } else if let Some(real_ip) = headers.get_one("x-real-ip") {
+            vec![parse_node(real_ip)]
+        } else {
+            return peer;
+        };
+
+        // walk the chain from the closest hop, skipping our own proxies
+        let mut client = peer;
+        for hop in chain.into_iter().rev() {
+            match hop {
+                Some(ip) if self.is_trusted(&ip) => client = ip,
+                Some(ip) => return ip,
+                // unparsable or obfuscated hop; nothing beyond it can be trusted
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Clients refused on every route.
+///
+/// Cloning shares the blocked networks, replaced when the settings are reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct IpBlocklist(Arc<RwLock<Vec<IpNet>>>);
+
+impl IpBlocklist {
+    pub fn new(blocked: Vec<IpNet>) -> Self {
+        Self(Arc::new(RwLock::new(blocked)))
+    }
+
+    pub fn set(&self, blocked: Vec<IpNet>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = blocked;
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|net| net.contains(ip))
+    }
+}
+
+/// Check the client of the request against the managed blocklist, if any
+pub fn is_blocked(request: &Request<'_>) -> bool {
+    match (request.rocket().state::<IpBlocklist>(), client_ip(request)) {
+        (Some(blocklist), Some(ip)) => blocklist.is_blocked(&ip),
+        _ => false,
+    }
+}
+
+/// Path the requests of the blocked clients are rerouted to
+const BLOCKED_PATH: &str = "/blocked-client";
+
+/// Fairing refusing the clients of the managed blocklist with 403, before any guard runs.
+///
+/// A fairing can't answer a request itself: the requests of the blocked clients
+/// are rerouted to a route refusing them, whatever their method & path.
+pub struct BlocklistFairing;
+
+/// Route handler refusing the rerouted requests
+#[derive(Clone)]
+struct RefuseBlocked;
+
+#[rocket::async_trait]
+impl route::Handler for RefuseBlocked {
+    async fn handle<'r>(&self, _: &'r Request<'_>, _: Data<'r>) -> route::Outcome<'r> {
+        route::Outcome::Failure(Status::Forbidden)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for BlocklistFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "IP blocklist",
+            kind: Kind::Ignite | Kind::Request,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let routes = [
+            Method::Get,
+            Method::Put,
+            Method::Post,
+            Method::Delete,
+            Method::Options,
+            Method::Head,
+            Method::Patch,
+        ]
+        .into_iter()
+        .map(|method| Route::new(method, BLOCKED_PATH, RefuseBlocked))
+        .collect::<Vec<_>>();
+        Ok(rocket.mount("/", routes))
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if is_blocked(request) {
+            request.set_uri(Origin::parse(BLOCKED_PATH).expect("valid blocked path"));
+        }
+    }
+}
+
+/// Get the client ip address of the request using the managed resolver.
+///
+/// Falls back to the connecting peer if no resolver is managed by the server.
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    match request.rocket().state::<ClientIpResolver>() {
+        Some(resolver) => resolver.resolve(request),
+        None => request.remote().map(|remote| remote.ip()),
+    }
+}
+
+/// Get the `for=` nodes of a RFC 7239 `Forwarded` header
+fn parse_forwarded(header: &str) -> Vec<Option<IpAddr>> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(parse_node(value))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Parse a node such as `192.0.2.1`, `192.0.2.1:80`, `"[2001:db8::1]:443"` or `2001:db8::1`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Header;
+
+    fn resolver() -> ClientIpResolver {
+        ClientIpResolver::new(vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "2001:db8:ffff::/48".parse().unwrap(),
+        ])
+    }
+
+    fn headers(headers: &[(&'static str, &'static str)]) -> HeaderMap<'static> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.add(Header::new(*name, *value));
+        }
+        map
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_the_headers_of_an_untrusted_peer() {
+        let spoofed = headers(&[
+            ("X-Forwarded-For", "1.2.3.4"),
+            ("Forwarded", "for=1.2.3.4"),
+            ("X-Real-IP", "1.2.3.4"),
+        ]);
+        assert_eq!(
+            resolver().resolve_peer(ip("198.51.100.7"), &spoofed),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn walks_the_trusted_proxies() {
+        // the client spoofs a first hop, our two proxies append theirs
+        let chain = headers(&[("X-Forwarded-For", "1.2.3.4, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(
+            resolver().resolve_peer(ip("10.0.0.1"), &chain),
+            ip("203.0.113.9")
+        );
+        // every hop trusted: the farthest one
+        let chain = headers(&[("X-Forwarded-For", "10.0.0.3,10.0.0.2")]);
+        assert_eq!(
+            resolver().resolve_peer(ip("10.0.0.1"), &chain),
+            ip("10.0.0.3")
+        );
+        // no forwarding header: the proxy itself
+        assert_eq!(
+            resolver().resolve_peer(ip("10.0.0.1"), &headers(&[])),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn parses_the_forwarded_header() {
+        let forwarded = headers(&[(
+            "Forwarded",
+            r#"for="[2001:db8::1]:443";proto=https, for=10.0.0.2:8080"#,
+        )]);
+        assert_eq!(
+            resolver().resolve_peer(ip("10.0.0.1"), &forwarded),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            parse_forwarded(r#"For="[2001:db8:ffff::1]";by=10.0.0.1, proto=http"#),
+            vec![Some(ip("2001:db8:ffff::1"))]
+        );
+    }
+
+    #[test]
+    fn stops_at_a_malformed_node() {
+        for node in ["unknown", "_hidden", "[2001:db8::1", "300.1.2.3", ""] {
+            assert_eq!(parse_node(node), None, "{}", node);
+        }
+        assert_eq!(parse_node(" 192.0.2.1:80 "), Some(ip("192.0.2.1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+
+        // nothing beyond an unparsable hop is trusted: the last trusted proxy is the client
+        let chain = headers(&[("X-Forwarded-For", "1.2.3.4, garbage, 10.0.0.2")]);
+        assert_eq!(
+            resolver().resolve_peer(ip("10.0.0.1"), &chain),
+            ip("10.0.0.2")
+        );
+        let forwarded = headers(&[("Forwarded", "for=_hidden, for=10.0.0.2")]);
+        assert_eq!(
+            resolver().resolve_peer(ip("10.0.0.1"), &forwarded),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[get("/ping")]
+    fn ping() -> &'static str {
+        "pong"
+    }
+
+    #[test]
+    fn refuses_the_blocked_clients() {
+        use rocket::local::blocking::Client;
+
+        let blocklist = IpBlocklist::new(vec!["203.0.113.0/24".parse().unwrap()]);
+        let rocket = rocket::build()
+            .attach(BlocklistFairing)
+            .manage(blocklist.clone())
+            .mount("/", routes![ping]);
+        let client = Client::tracked(rocket).unwrap();
+        let blocked = "203.0.113.7:4000".parse::<SocketAddr>().unwrap();
+        let allowed = "198.51.100.7:4000".parse::<SocketAddr>().unwrap();
+
+        let response = client.get("/ping").remote(allowed).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // any route, or none, whatever the method
+        let response = client.get("/ping").remote(blocked).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.post("/nowhere").remote(blocked).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // the reloaded blocklist applies to the next requests
+        blocklist.set(vec![]);
+        let response = client.get("/ping").remote(blocked).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
@@ -0,0 +1,407 @@
+use chrono::{offset::Utc, DateTime, TimeZone};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rocket::{fairing::AdHoc, request::Request, tokio};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+use uuid::Uuid;
+
+use crate::{
+    db::MongodbBackend,
+    error::Error,
+    models::{endpoint::Endpoint, user::User},
+    secure::token::hash_api_key,
+};
+
+/// ACL of the user carried by the token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclClaims {
+    pub ips: Vec<String>,
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl AclClaims {
+    /// Hex sha256 of the ACL, changes whenever the user's ACL is updated
+    pub fn digest(&self) -> String {
+        let acl = rocket::serde::json::to_string(self).unwrap_or_default();
+        hash_api_key(&acl)
+    }
+}
+
+/// Claims of the signed access token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    /// Email of the user
+    pub sub: String,
+    /// Admin flag of the user
+    pub adm: bool,
+    /// ACL of the user at issue time
+    pub acl: AclClaims,
+    /// Digest of `acl`
+    pub acl_digest: String,
+    /// Hash of the api key the token was exchanged from
+    pub kid: String,
+    /// Token id
+    pub jti: String,
+    pub iat: i64,
+    pub exp: i64,
+    /// OAuth client the token was issued to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Space separated OAuth scopes granted to the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+impl Claims {
+    /// Build the user the token was issued to, without its credentials
+    pub fn to_user(&self) -> User {
+        User {
+            created_ip: String::new(),
+            created_by: String::new(),
+            created_at: Utc
+                .timestamp_opt(self.iat, 0)
+                .single()
+                .unwrap_or_else(Utc::now),
+            email: self.sub.clone(),
+            description: String::new(),
+            api_key: String::new(),
+            is_admin: self.adm,
+            acl_allow_ips: self.acl.ips.clone(),
+            acl_allow_endpoints: self.acl.endpoints.clone(),
+            signing_secret: None,
+            cert_fingerprints: vec![],
+        }
+    }
+}
+
+/// Access token handed back to the client
+#[derive(Debug, Clone, Serialize)]
+pub struct IssuedToken {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Issues & validates short lived access tokens (HS256 JWT)
+pub struct TokenIssuer {
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    ttl_secs: i64,
+    revoked: Arc<Mutex<Revocations>>,
+}
+
+/// Tokens rejected before their expiry
+#[derive(Debug, Default)]
+struct Revocations {
+    // revoked token id => expiry of the token
+    tokens: HashMap<String, i64>,
+    // deleted OAuth client => expiry of the last token issued to it
+    clients: HashMap<String, i64>,
+}
+
+impl Revocations {
+    /// Forget the revocations of the expired tokens
+    fn prune(&mut self) {
+        let now = Utc::now().timestamp();
+        self.tokens.retain(|_, expires_at| *expires_at > now);
+        self.clients.retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+/// Add the tokens revoked in the backend, by any instance, to the revoked ones
+async fn load_revoked(revoked: &Mutex<Revocations>, backend: &MongodbBackend) -> Result<(), Error> {
+    let tokens = backend.get_revoked_tokens().await?;
+    let clients = backend.get_revoked_clients().await?;
+    let mut revoked = revoked.lock().unwrap_or_else(PoisonError::into_inner);
+    revoked.prune();
+    revoked
+        .tokens
+        .extend(tokens.into_iter().map(|token| (token.jti, token.exp)));
+    revoked.clients.extend(
+        clients
+            .into_iter()
+            .map(|client| (client.client_id, client.exp)),
+    );
+    Ok(())
+}
+
+impl TokenIssuer {
+    pub fn new(secret: &[u8], ttl_secs: u64) -> Self {
+        //! Create a token issuer signing with the given secret
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! TokenIssuer::new(derive_key(&server_settings.secret_key, "jwt")?.as_bytes(), 900);
+        //! ```
+        Self {
+            encoding_key: EncodingKey::from_secret(secret),
+            decoding_key: DecodingKey::from_secret(secret),
+            ttl_secs: ttl_secs as i64,
+            revoked: Arc::new(Mutex::new(Revocations::default())),
+        }
+    }
+
+    /// Get the bearer access token of the request, if any.
+    ///
+    /// Bearer values not shaped like a JWT are left to the api key sources.
+    pub fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+        request
+            .headers()
+            .get_one("authorization")
+            .and_then(|header| header.trim().strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|token| token.split('.').count() == 3)
+    }
+
+    /// Issue a token for the user
+    pub fn issue(&self, user: &User) -> Result<IssuedToken, Error> {
+        self.issue_claims(self.claims(user, user.acl_allow_endpoints.clone()))
+    }
+
+    /// Issue a token to an OAuth client acting as the user.
+    ///
+    /// The token's endpoints are the user's ACL rules named by the scopes (all of them if no scope).
+    pub fn issue_for_client(
+        &self,
+        user: &User,
+        client_id: &str,
+        scopes: &[String],
+    ) -> Result<IssuedToken, Error> {
+        let endpoints = user
+            .acl_allow_endpoints
+            .iter()
+            .filter(|endpoint| scopes.is_empty() || scopes.contains(&endpoint.name))
+            .cloned()
+            .collect();
+
+        let mut claims = self.claims(user, endpoints);
+        claims.client_id = Some(client_id.to_string());
+        claims.scope = Some(scopes.join(" "));
+        self.issue_claims(claims)
+    }
+
+    fn claims(&self, user: &User, endpoints: Vec<Endpoint>) -> Claims {
+        let now = Utc::now().timestamp();
+        let acl = AclClaims {
+            ips: user.acl_allow_ips.clone(),
+            endpoints,
+        };
+        Claims {
+            sub: user.email.clone(),
+            adm: user.is_admin,
+            acl_digest: acl.digest(),
+            acl,
+            kid: hash_api_key(&user.api_key),
+            jti: Uuid::new_v4().as_simple().to_string(),
+            iat: now,
+            exp: now + self.ttl_secs,
+            client_id: None,
+            scope: None,
+        }
+    }
+
+    fn issue_claims(&self, claims: Claims) -> Result<IssuedToken, Error> {
+        self.sign(&claims).map(|access_token| IssuedToken {
+            access_token,
+            token_type: "Bearer",
+            expires_in: self.ttl_secs,
+            expires_at: Utc
+                .timestamp_opt(claims.exp, 0)
+                .single()
+                .unwrap_or_else(Utc::now),
+        })
+    }
+
+    /// Reject the token with the given id until it expires
+    pub fn revoke(&self, jti: &str, exp: i64) {
+        let mut revoked = self.revoked.lock().unwrap_or_else(PoisonError::into_inner);
+        revoked.prune();
+        revoked.tokens.insert(jti.to_string(), exp);
+    }
+
+    /// Reject all the tokens issued to the OAuth client, returns the expiry
+    /// of the last one it may hold
+    pub fn revoke_client(&self, client_id: &str) -> i64 {
+        let exp = Utc::now().timestamp() + self.ttl_secs;
+        let mut revoked = self.revoked.lock().unwrap_or_else(PoisonError::into_inner);
+        revoked.prune();
+        revoked.clients.insert(client_id.to_string(), exp);
+        exp
+    }
+
+    /// Load the tokens revoked in the backend
+    pub async fn refresh_revoked(&self, backend: &MongodbBackend) -> Result<(), Error> {
+        load_revoked(&self.revoked, backend).await
+    }
+
+    /// Fairing polling the backend for the tokens revoked on any instance
+    pub fn fairing(&self, backend: MongodbBackend, poll_interval: Duration) -> AdHoc {
+        let revoked = self.revoked.clone();
+        AdHoc::on_liftoff("Revoked token polling", move |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(poll_interval);
+                    loop {
+                        interval.tick().await;
+                        // keep the known revocations until the next poll
+                        if let Err(e) = load_revoked(&revoked, &backend).await {
+                            log::warn!("could not refresh the revoked tokens: {}", e);
+                        }
+                    }
+                });
+            })
+        })
+    }
+
+    fn is_revoked(&self, claims: &Claims) -> bool {
+        let revoked = self.revoked.lock().unwrap_or_else(PoisonError::into_inner);
+        revoked.tokens.contains_key(&claims.jti)
+            || claims
+                .client_id
+                .as_ref()
+                .is_some_and(|client_id| revoked.clients.contains_key(client_id))
+    }
+
+    /// Sign the given claims
+    pub fn sign(&self, claims: &Claims) -> Result<String, Error> {
+        encode(&Header::new(Algorithm::HS256), claims, &self.encoding_key)
+            .map_err(|_| Error::InternalError)
+    }
+
+    /// Check the signature, expiry & ACL digest of the token, and get its claims
+    pub fn validate(&self, token: &str) -> Result<Claims, Error> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+
+        decode::<Claims>(token, &self.decoding_key, &validation)
+            .map_err(|_| Error::UnauthenticatedUser)
+            .and_then(|data| {
+                if self.is_revoked(&data.claims)
+                    || data.claims.acl_digest != data.claims.acl.digest()
+                {
+                    Err(Error::UnauthenticatedUser)
+                } else {
+                    Ok(data.claims)
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> User {
+        User {
+            created_ip: "127.0.0.1".into(),
+            created_by: "admin@example.com".into(),
+            created_at: Utc::now(),
+            email: "user@example.com".into(),
+            description: String::new(),
+            api_key: "api-key".into(),
+            is_admin: false,
+            acl_allow_ips: vec!["10.0.0.0/8".into()],
+            acl_allow_endpoints: vec![],
+            signing_secret: None,
+            cert_fingerprints: vec![],
+        }
+    }
+
+    #[test]
+    fn validates_the_issued_token() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let token = issuer.issue(&user()).unwrap().access_token;
+        let claims = issuer.validate(&token).unwrap();
+        assert_eq!(claims.sub, "user@example.com");
+        assert_eq!(claims.acl_digest, claims.acl.digest());
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let mut claims = issuer.claims(&user(), vec![]);
+        claims.iat -= 1000;
+        claims.exp = claims.iat + 900;
+        let token = issuer.sign(&claims).unwrap();
+        assert!(issuer.validate(&token).is_err());
+    }
+
+    #[test]
+    fn rejects_a_tampered_token() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let token = issuer.issue(&user()).unwrap().access_token;
+
+        // signature of another payload
+        let mut parts = token.split('.').collect::<Vec<_>>();
+        let mut claims = issuer.claims(&user(), vec![]);
+        claims.adm = true;
+        let forged = issuer.sign(&claims).unwrap();
+        let forged_payload = forged.split('.').nth(1).unwrap();
+        parts[1] = forged_payload;
+        assert!(issuer.validate(&parts.join(".")).is_err());
+
+        // altered signature
+        let mut signature = parts[2].to_string().into_bytes();
+        signature[0] = if signature[0] == b'A' { b'B' } else { b'A' };
+        let token = format!(
+            "{}.{}",
+            token.rsplit_once('.').unwrap().0,
+            String::from_utf8(signature).unwrap()
+        );
+        assert!(issuer.validate(&token).is_err());
+    }
+
+    #[test]
+    fn rejects_a_revoked_token() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let token = issuer.issue(&user()).unwrap().access_token;
+        let claims = issuer.validate(&token).unwrap();
+
+        issuer.revoke(&claims.jti, claims.exp);
+        assert!(issuer.validate(&token).is_err());
+        // the other tokens are still valid
+        let token = issuer.issue(&user()).unwrap().access_token;
+        assert!(issuer.validate(&token).is_ok());
+    }
+
+    #[test]
+    fn rejects_the_tokens_of_a_revoked_client() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let token = |client_id| {
+            issuer
+                .issue_for_client(&user(), client_id, &[])
+                .unwrap()
+                .access_token
+        };
+        let (deleted, other) = (token("deleted"), token("other"));
+
+        assert!(issuer.revoke_client("deleted") >= Utc::now().timestamp() + 900);
+        assert!(issuer.validate(&deleted).is_err());
+        assert!(issuer.validate(&other).is_ok());
+        // tokens issued to the user directly are left alone
+        let token = issuer.issue(&user()).unwrap().access_token;
+        assert!(issuer.validate(&token).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_token_signed_with_another_key() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let other = TokenIssuer::new(b"signup key", 900);
+        let token = other.issue(&user()).unwrap().access_token;
+        assert!(issuer.validate(&token).is_err());
+    }
+
+    #[test]
+    fn rejects_a_mismatching_acl_digest() {
+        let issuer = TokenIssuer::new(b"jwt key", 900);
+        let mut claims = issuer.claims(&user(), vec![]);
+        claims.acl.ips.push("0.0.0.0/0".into());
+        let token = issuer.sign(&claims).unwrap();
+        assert!(issuer.validate(&token).is_err());
+    }
+}
@@ -1,3 +1,14 @@
+pub mod api_key;
+pub mod attempts;
 pub mod cert;
 pub mod guards;
+pub mod ip;
+pub mod jwt;
+pub mod mtls;
+pub mod password;
+pub mod revocation;
+pub mod session;
+pub mod signature;
+pub mod signup;
 pub mod token;
+pub mod totp;
@@ -0,0 +1,77 @@
+use openssl::sha::sha256;
+use rocket::{
+    mtls::x509::{FromDer, GeneralName, ParsedExtension, X509Certificate},
+    request::Request,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::server::tls::ClientCertificates;
+
+/// How client certificates take part in the authentication
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MtlsMode {
+    /// Client certificates are not used to authenticate users
+    #[default]
+    Disabled,
+    /// A client certificate is enough to authenticate the user
+    Certificate,
+    /// Every request must present both a certificate and credentials of the same user
+    CertificateAndApiKey,
+}
+
+/// Identity of the user presenting a client certificate
+#[derive(Debug, Clone)]
+pub struct CertIdentity {
+    /// Hex sha256 of the certificate's public key (SubjectPublicKeyInfo)
+    pub fingerprint: String,
+    /// Email addresses & common names of the certificate's subject and SAN
+    pub names: Vec<String>,
+}
+
+impl From<&X509Certificate<'_>> for CertIdentity {
+    fn from(cert: &X509Certificate<'_>) -> Self {
+        let mut names = cert
+            .extensions()
+            .iter()
+            .filter_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectAlternativeName(san) => Some(san),
+                _ => None,
+            })
+            .flat_map(|san| san.general_names.iter())
+            .filter_map(|name| match name {
+                GeneralName::RFC822Name(email) => Some(email.to_string()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        names.extend(
+            cert.subject()
+                .iter_email()
+                .filter_map(|email| email.as_str().ok())
+                .map(String::from),
+        );
+        names.extend(
+            cert.subject()
+                .iter_common_name()
+                .filter_map(|name| name.as_str().ok())
+                .map(String::from),
+        );
+
+        Self {
+            fingerprint: hex::encode(sha256(cert.subject_pki.raw)),
+            names,
+        }
+    }
+}
+
+/// Get the identity of the client certificate presented with the request, if any.
+///
+/// The certificate chain was already verified against the client CA during the TLS handshake.
+pub async fn certificate_identity(request: &Request<'_>) -> Option<CertIdentity> {
+    let chain = request
+        .rocket()
+        .state::<ClientCertificates>()?
+        .get(&request.remote()?)?;
+    let (_, cert) = X509Certificate::from_der(chain.first()?).ok()?;
+    Some(CertIdentity::from(&cert))
+}
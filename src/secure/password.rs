@@ -0,0 +1,36 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use openssl::rand::rand_bytes;
+
+use crate::error::Error;
+
+/// Minimum number of characters of a password
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+
+/// Hash the password with argon2id and a random salt, in the PHC string format
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Error::BadRequest(format!(
+            "password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+
+    let mut salt = [0u8; 16];
+    rand_bytes(&mut salt).map_err(|_| Error::InternalError)?;
+    let salt = SaltString::b64_encode(&salt).map_err(|_| Error::InternalError)?;
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| Error::InternalError)
+}
+
+/// Check the password against its PHC string hash
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
@@ -0,0 +1,75 @@
+use rocket::{fairing::AdHoc, tokio};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+    time::Duration,
+};
+
+use crate::{db::MongodbBackend, models::revocation::Revocation};
+
+/// In-process copy of the revoked api keys, refreshed from the backend by polling
+#[derive(Clone, Default)]
+pub struct RevocationList {
+    // key hash => revocation
+    revoked: Arc<RwLock<HashMap<String, Revocation>>>,
+    /// Loaded from the backend at least once
+    loaded: Arc<AtomicBool>,
+}
+
+impl RevocationList {
+    /// Check if the api key hash is revoked
+    pub fn is_revoked(&self, key_hash: &str) -> bool {
+        self.revoked
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(key_hash)
+    }
+
+    /// Whether the revocations were loaded from the backend; until then
+    /// the server is not ready, as leaked keys would be accepted
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Relaxed)
+    }
+
+    /// Revoke the key on this instance right away, the others pick it up at their next poll
+    pub fn insert(&self, revocation: Revocation) {
+        self.revoked
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(revocation.key_hash.clone(), revocation);
+    }
+
+    /// Load the revocations stored by the backend
+    pub async fn refresh(&self, backend: &MongodbBackend) {
+        match backend.get_all_revocations().await {
+            Ok(revocations) => {
+                let revoked = revocations
+                    .into_iter()
+                    .map(|revocation| (revocation.key_hash.clone(), revocation))
+                    .collect();
+                *self.revoked.write().unwrap_or_else(PoisonError::into_inner) = revoked;
+                self.loaded.store(true, Ordering::Relaxed);
+            }
+            // keep the last known list rather than dropping every revocation
+            Err(e) => log::warn!("could not refresh the revocation list: {}", e),
+        }
+    }
+
+    /// Fairing polling the backend for revocations made on any instance
+    pub fn fairing(self, backend: MongodbBackend, poll_interval: Duration) -> AdHoc {
+        AdHoc::on_liftoff("Revocation list polling", move |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(poll_interval);
+                    loop {
+                        interval.tick().await;
+                        self.refresh(&backend).await;
+                    }
+                });
+            })
+        })
+    }
+}
@@ -0,0 +1,216 @@
+use chrono::offset::Utc;
+use openssl::memcmp;
+use rocket::{
+    http::{Cookie, CookieJar, Method, SameSite},
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    time,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+use uuid::Uuid;
+
+use crate::{
+    db::MongodbBackend,
+    error::Error,
+    models::ratelimit::{KeyedLimiter, RateTime},
+    secure::signature::generate_signing_secret,
+};
+
+/// Header carrying the CSRF token of the session on state changing requests
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Browser session of an admin, kept encrypted in a private cookie
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    /// Session id
+    pub id: String,
+    /// Email of the admin
+    pub email: String,
+    /// Token to echo in the `x-csrf-token` header
+    pub csrf_token: String,
+    /// Unix timestamp the session expires at
+    pub expires_at: i64,
+    /// Unix timestamp of the last second factor given in the session
+    #[serde(default)]
+    pub mfa_at: Option<i64>,
+}
+
+/// Throttle of the login attempts of each client ip
+pub struct LoginLimiter(KeyedLimiter);
+
+impl LoginLimiter {
+    pub fn new(throttle: RateTime) -> Self {
+        Self(KeyedLimiter::new(throttle))
+    }
+
+    /// Count a login attempt of the client ip, or Err(TooManyRequests) past its throttle
+    pub fn acquire(&self, ip: &str) -> Result<(), Error> {
+        if self.0.try_acquire(ip) {
+            Ok(())
+        } else {
+            Err(Error::TooManyRequests)
+        }
+    }
+}
+
+/// Starts, checks & ends the cookie sessions
+pub struct SessionManager {
+    cookie_name: String,
+    ttl_secs: i64,
+    secure: bool,
+    // session id => expiry, of the sessions logged out on this instance;
+    // the backend has the sessions logged out on every instance
+    logged_out: Mutex<HashMap<String, i64>>,
+}
+
+impl SessionManager {
+    pub fn new(cookie_name: String, ttl_secs: u64, secure: bool) -> Self {
+        //! Create a session manager, the cookie is only sent over https when `secure` is set
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! SessionManager::new("rocketapi_session".to_string(), 3600, true);
+        //! ```
+        Self {
+            cookie_name,
+            ttl_secs: ttl_secs as i64,
+            secure,
+            logged_out: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start a session for the admin, setting the session cookie
+    pub fn start(
+        &self,
+        cookies: &CookieJar<'_>,
+        email: &str,
+        mfa_at: Option<i64>,
+    ) -> Result<Session, Error> {
+        let session = Session {
+            id: Uuid::new_v4().as_simple().to_string(),
+            email: email.to_string(),
+            csrf_token: generate_signing_secret(),
+            expires_at: Utc::now().timestamp() + self.ttl_secs,
+            mfa_at,
+        };
+        let value = rocket::serde::json::to_string(&session).map_err(|_| Error::InternalError)?;
+
+        cookies.add_private(
+            Cookie::build(self.cookie_name.clone(), value)
+                .path("/")
+                .http_only(true)
+                .secure(self.secure)
+                .same_site(SameSite::Strict)
+                .max_age(time::Duration::seconds(self.ttl_secs))
+                .finish(),
+        );
+        Ok(session)
+    }
+
+    /// Get the live session of the request, if any
+    pub fn session(&self, request: &Request<'_>) -> Option<Session> {
+        let session = request
+            .cookies()
+            .get_private(&self.cookie_name)
+            .and_then(|cookie| rocket::serde::json::from_str::<Session>(cookie.value()).ok())?;
+
+        if session.expires_at <= Utc::now().timestamp() || self.is_logged_out(&session.id) {
+            None
+        } else {
+            Some(session)
+        }
+    }
+
+    /// Get the live session of the request, checking the CSRF token
+    /// on any method other than GET, HEAD & OPTIONS
+    pub fn verify(&self, request: &Request<'_>) -> Result<Session, Error> {
+        let session = self.session(request).ok_or(Error::UnauthenticatedUser)?;
+        if !matches!(
+            request.method(),
+            Method::Get | Method::Head | Method::Options
+        ) {
+            let csrf_token = request.headers().get_one(CSRF_HEADER).unwrap_or_default();
+            if csrf_token.len() != session.csrf_token.len()
+                || !memcmp::eq(csrf_token.as_bytes(), session.csrf_token.as_bytes())
+            {
+                return Err(Error::ForbiddenAccess);
+            }
+        }
+        Ok(session)
+    }
+
+    /// Get the live session of the request as [`SessionManager::verify`] does,
+    /// unless the session was ended on another instance
+    pub async fn verify_live(
+        &self,
+        request: &Request<'_>,
+        backend: &MongodbBackend,
+    ) -> Result<Session, Error> {
+        let session = self.verify(request)?;
+        if backend.is_session_ended(&session.id).await? {
+            Err(Error::UnauthenticatedUser)
+        } else {
+            Ok(session)
+        }
+    }
+
+    /// End the session, removing the cookie & rejecting the session until it expires
+    pub async fn end(
+        &self,
+        cookies: &CookieJar<'_>,
+        session: &Session,
+        backend: &MongodbBackend,
+    ) -> Result<(), Error> {
+        cookies.remove_private(
+            Cookie::build(self.cookie_name.clone(), "")
+                .path("/")
+                .finish(),
+        );
+
+        // rejected at once on this instance, on the others through the backend
+        {
+            let mut logged_out = self
+                .logged_out
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let now = Utc::now().timestamp();
+            logged_out.retain(|_, expires_at| *expires_at > now);
+            logged_out.insert(session.id.clone(), session.expires_at);
+        }
+
+        backend
+            .insert_ended_session(&session.id, session.expires_at)
+            .await
+    }
+
+    fn is_logged_out(&self, id: &str) -> bool {
+        self.logged_out
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains_key(id)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+        let verified = match (
+            rocket.state::<SessionManager>(),
+            rocket.state::<MongodbBackend>(),
+        ) {
+            (Some(sessions), Some(backend)) => sessions.verify_live(request, backend).await,
+            _ => Err(Error::UnauthenticatedUser),
+        };
+        match verified {
+            Ok(session) => Outcome::Success(session),
+            Err(e) => Outcome::Failure((e.to_status(), e)),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use chrono::offset::Utc;
+use openssl::{
+    hash::MessageDigest, memcmp, pkey::PKey, rand::rand_bytes, sha::sha256, sign::Signer,
+};
+use rocket::request::Request;
+use std::{collections::HashMap, str::FromStr};
+
+use crate::error::Error;
+
+/// Authorization scheme of signed requests
+pub const SIGNATURE_SCHEME: &str = "HMAC-SHA256";
+/// Header carrying the hex encoded sha256 of the request body
+pub const CONTENT_HASH_HEADER: &str = "x-content-sha256";
+
+/// Parameters of the `Authorization: HMAC-SHA256 email=..,timestamp=..,nonce=..,signature=..` header
+#[derive(Debug, Clone)]
+pub struct SignedRequest {
+    pub email: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl FromStr for SignedRequest {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let params = s
+            .split(',')
+            .filter_map(|param| param.trim().split_once('='))
+            .collect::<HashMap<_, _>>();
+
+        match (
+            params.get("email"),
+            params.get("timestamp").and_then(|ts| ts.parse().ok()),
+            params.get("nonce"),
+            params.get("signature"),
+        ) {
+            (Some(email), Some(timestamp), Some(nonce), Some(signature)) if !nonce.is_empty() => {
+                Ok(Self {
+                    email: email.to_string(),
+                    timestamp,
+                    nonce: nonce.to_string(),
+                    signature: signature.to_string(),
+                })
+            }
+            _ => Err(Error::UnauthenticatedUser),
+        }
+    }
+}
+
+/// Verifies the signature and the clock skew of signed requests;
+/// the nonces are burnt in the backend, shared by every instance
+pub struct SignatureVerifier {
+    max_skew_secs: i64,
+}
+
+impl SignatureVerifier {
+    pub fn new(max_skew_secs: u64) -> Self {
+        Self {
+            max_skew_secs: max_skew_secs as i64,
+        }
+    }
+
+    /// Get the signed request parameters from the `Authorization` header, if any
+    pub fn signed_request(request: &Request<'_>) -> Option<Result<SignedRequest, Error>> {
+        request
+            .headers()
+            .get_one("authorization")
+            .and_then(|header| header.trim().strip_prefix(SIGNATURE_SCHEME))
+            .map(str::parse)
+    }
+
+    /// Check the request was signed within the allowed clock skew
+    pub fn check_timestamp(&self, signed: &SignedRequest) -> Result<(), Error> {
+        if (Utc::now().timestamp() - signed.timestamp).abs() > self.max_skew_secs {
+            Err(Error::UnauthenticatedUser)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Unix time until which the nonce of the request must be kept:
+    /// the request can't be replayed past the skew window anyway
+    pub fn nonce_expiry(&self, signed: &SignedRequest) -> i64 {
+        signed.timestamp + self.max_skew_secs
+    }
+
+    /// Check the signature of the request with the user's secret
+    pub fn verify(
+        &self,
+        request: &Request<'_>,
+        signed: &SignedRequest,
+        secret: &str,
+    ) -> Result<(), Error> {
+        let content_hash = request
+            .headers()
+            .get_one(CONTENT_HASH_HEADER)
+            .unwrap_or_default();
+
+        let expected = sign(
+            secret,
+            &string_to_sign(
+                request.method().as_str(),
+                &request.uri().to_string(),
+                signed.timestamp,
+                &signed.nonce,
+                content_hash,
+            ),
+        )?;
+
+        if expected.len() != signed.signature.len()
+            || !memcmp::eq(expected.as_bytes(), signed.signature.as_bytes())
+        {
+            return Err(Error::UnauthenticatedUser);
+        }
+        Ok(())
+    }
+}
+
+/// Build the string a client signs:
+/// method, path & query, timestamp, nonce and body hash separated by new lines
+pub fn string_to_sign(
+    method: &str,
+    path_and_query: &str,
+    timestamp: i64,
+    nonce: &str,
+    content_hash: &str,
+) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}",
+        method.to_uppercase(),
+        path_and_query,
+        timestamp,
+        nonce,
+        content_hash.to_lowercase()
+    )
+}
+
+/// Hex encoded HMAC-SHA256 of the message
+pub fn sign(secret: &str, message: &str) -> Result<String, Error> {
+    let key = PKey::hmac(secret.as_bytes()).map_err(|_| Error::InternalError)?;
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).map_err(|_| Error::InternalError)?;
+    signer
+        .update(message.as_bytes())
+        .and_then(|_| signer.sign_to_vec())
+        .map(hex::encode)
+        .map_err(|_| Error::InternalError)
+}
+
+/// Key dedicated to one purpose, derived from the server secret key,
+/// so a key leaked or misused in one place can't forge anything elsewhere
+pub fn derive_key(secret: &str, purpose: &str) -> Result<String, Error> {
+    sign(secret, purpose)
+}
+
+/// Hex encoded sha256 of a request body
+pub fn content_hash(body: &[u8]) -> String {
+    hex::encode(sha256(body))
+}
+
+/// Generate a new per-user signing secret
+pub fn generate_signing_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand_bytes(&mut secret).expect("openssl random generator failed");
+    hex::encode(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn hmac_sha256_vector() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?").unwrap(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn derives_a_key_per_purpose() {
+        let jwt = derive_key("secret", "jwt").unwrap();
+        assert_eq!(jwt, derive_key("secret", "jwt").unwrap());
+        assert_ne!(jwt, derive_key("secret", "signup").unwrap());
+        assert_ne!(jwt, derive_key("other secret", "jwt").unwrap());
+        assert_ne!(jwt, "secret");
+    }
+
+    #[test]
+    fn body_hash() {
+        assert_eq!(content_hash(b""), EMPTY_SHA256);
+        assert_eq!(
+            content_hash(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn canonical_string() {
+        let expected = format!("GET\n/api/ping?x=1\n1700000000\nn0nce\n{}", EMPTY_SHA256);
+        assert_eq!(
+            string_to_sign("GET", "/api/ping?x=1", 1700000000, "n0nce", EMPTY_SHA256),
+            expected
+        );
+        // the method is upper cased & the body hash lower cased
+        assert_eq!(
+            string_to_sign(
+                "get",
+                "/api/ping?x=1",
+                1700000000,
+                "n0nce",
+                &EMPTY_SHA256.to_uppercase()
+            ),
+            expected
+        );
+        assert_eq!(
+            sign("secret", &expected).unwrap(),
+            "949875a764278a84e1a0b42b00187e5d47287d8b801d9be03a2491d5f500de0b"
+        );
+    }
+
+    #[test]
+    fn authorization_params() {
+        let signed: SignedRequest = " email=a@b.c, timestamp=1700000000,nonce=n0nce,signature=abcd"
+            .parse()
+            .unwrap();
+        assert_eq!(signed.email, "a@b.c");
+        assert_eq!(signed.timestamp, 1700000000);
+        assert_eq!(signed.nonce, "n0nce");
+        assert_eq!(signed.signature, "abcd");
+
+        for header in [
+            "email=a@b.c,timestamp=1700000000,signature=abcd",
+            "email=a@b.c,timestamp=1700000000,nonce=,signature=abcd",
+            "email=a@b.c,timestamp=yesterday,nonce=n0nce,signature=abcd",
+            "",
+        ] {
+            assert!(header.parse::<SignedRequest>().is_err(), "{}", header);
+        }
+    }
+
+    #[test]
+    fn skew_window() {
+        let verifier = SignatureVerifier::new(300);
+        let mut signed: SignedRequest = "email=a,timestamp=0,nonce=n,signature=s".parse().unwrap();
+
+        signed.timestamp = Utc::now().timestamp() - 299;
+        assert!(verifier.check_timestamp(&signed).is_ok());
+        assert_eq!(verifier.nonce_expiry(&signed), signed.timestamp + 300);
+        signed.timestamp = Utc::now().timestamp() + 400;
+        assert!(verifier.check_timestamp(&signed).is_err());
+        signed.timestamp = Utc::now().timestamp() - 400;
+        assert!(verifier.check_timestamp(&signed).is_err());
+    }
+}
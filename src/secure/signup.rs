@@ -0,0 +1,40 @@
+use crate::{
+    error::Error,
+    models::ratelimit::{KeyedLimiter, RateTime},
+    secure::signature::sign,
+};
+
+/// Throttle of the signups of each client ip
+pub struct SignupLimiter(KeyedLimiter);
+
+impl SignupLimiter {
+    pub fn new(throttle: RateTime) -> Self {
+        Self(KeyedLimiter::new(throttle))
+    }
+
+    /// Count a signup of the client ip, or Err(TooManyRequests) past its throttle
+    pub fn acquire(&self, ip: &str) -> Result<(), Error> {
+        if self.0.try_acquire(ip) {
+            Ok(())
+        } else {
+            Err(Error::TooManyRequests)
+        }
+    }
+}
+
+/// Derives the verification token of a pending signup from its salt,
+/// so the pending token can be mailed again without being stored
+pub struct SignupTokens {
+    key: String,
+}
+
+impl SignupTokens {
+    pub fn new(key: String) -> Self {
+        Self { key }
+    }
+
+    /// Verification token of the email's signup with the given salt
+    pub fn token(&self, email: &str, salt: &str) -> Result<String, Error> {
+        sign(&self.key, &format!("signup\n{}\n{}", email, salt))
+    }
+}
@@ -1,3 +1,4 @@
+use openssl::sha::sha256;
 use uuid::Uuid;
 
 /// Generate the API Key
@@ -6,3 +7,8 @@ pub fn generate_api_string(salt: &str) -> String {
         .as_simple()
         .to_string()
 }
+
+/// Hash of the API Key, used wherever the key itself should not be kept
+pub fn hash_api_key(api_key: &str) -> String {
+    hex::encode(sha256(api_key.as_bytes()))
+}
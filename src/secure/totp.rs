@@ -0,0 +1,158 @@
+use base32::Alphabet;
+use openssl::{hash::MessageDigest, pkey::PKey, rand::rand_bytes, sign::Signer};
+
+use crate::{error::Error, secure::token::hash_api_key};
+
+/// Seconds a TOTP code is valid for (RFC 6238 time step)
+pub const TOTP_STEP_SECS: i64 = 30;
+/// Digits of a TOTP code
+pub const TOTP_DIGITS: u32 = 6;
+/// Number of recovery codes handed out at enrolment
+pub const RECOVERY_CODES: usize = 10;
+/// Header carrying the TOTP or recovery code of a request
+pub const OTP_HEADER: &str = "x-otp";
+
+const SECRET_ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// When a recent second factor is required
+#[derive(Debug, Clone)]
+pub struct MfaPolicy {
+    /// Issuer shown in the authenticator apps
+    pub issuer: String,
+    /// Require a second factor to create, update & delete users
+    pub require_for_user_admin: bool,
+    /// Seconds a second factor given at session login stays recent
+    pub max_age_secs: i64,
+}
+
+/// Generate a new base32 encoded TOTP secret (160 bits, as for HMAC-SHA1)
+pub fn generate_totp_secret() -> Result<String, Error> {
+    let mut secret = [0u8; 20];
+    rand_bytes(&mut secret).map_err(|_| Error::InternalError)?;
+    Ok(base32::encode(SECRET_ALPHABET, &secret))
+}
+
+/// `otpauth://` uri to enrol the secret in an authenticator app
+pub fn provisioning_uri(issuer: &str, email: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, email, secret, issuer, TOTP_DIGITS, TOTP_STEP_SECS
+    )
+}
+
+/// TOTP code of the secret for the given time step
+fn totp_code(secret: &str, step: i64) -> Option<String> {
+    let key = base32::decode(SECRET_ALPHABET, secret)?;
+    let key = PKey::hmac(&key).ok()?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).ok()?;
+    signer.update(&step.to_be_bytes()).ok()?;
+    let hmac = signer.sign_to_vec().ok()?;
+
+    // dynamic truncation (RFC 4226 section 5.3)
+    let offset = (hmac[hmac.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hmac[offset] & 0x7f,
+        hmac[offset + 1],
+        hmac[offset + 2],
+        hmac[offset + 3],
+    ]) % 10u32.pow(TOTP_DIGITS);
+
+    Some(format!("{:0width$}", code, width = TOTP_DIGITS as usize))
+}
+
+/// Check the code against the steps around the timestamp, allowing one step of clock drift.
+///
+/// Returns the matching time step, which must not be accepted again.
+pub fn verify_totp(secret: &str, code: &str, timestamp: i64) -> Option<i64> {
+    let step = timestamp / TOTP_STEP_SECS;
+    (step - 1..=step + 1).find(|step| {
+        totp_code(secret, *step).is_some_and(|expected| {
+            expected.len() == code.len()
+                && openssl::memcmp::eq(expected.as_bytes(), code.as_bytes())
+        })
+    })
+}
+
+/// Generate single use recovery codes, returned along with their hashes to store
+pub fn generate_recovery_codes() -> Result<(Vec<String>, Vec<String>), Error> {
+    let mut codes = Vec::with_capacity(RECOVERY_CODES);
+    for _ in 0..RECOVERY_CODES {
+        let mut code = [0u8; 5];
+        rand_bytes(&mut code).map_err(|_| Error::InternalError)?;
+        let code = hex::encode(code);
+        codes.push(format!("{}-{}", &code[..5], &code[5..]));
+    }
+    let hashes = codes.iter().map(|code| hash_api_key(code)).collect();
+    Ok((codes, hashes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// base32 of the RFC 6238 SHA1 seed "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn rfc6238_vectors() {
+        // RFC 6238 appendix B, the last 6 of the 8 digits
+        for (timestamp, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(
+                totp_code(RFC_SECRET, timestamp / TOTP_STEP_SECS).as_deref(),
+                Some(code),
+                "at {}",
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn one_step_of_drift() {
+        let step = 1111111109 / TOTP_STEP_SECS;
+        assert_eq!(verify_totp(RFC_SECRET, "081804", 1111111109), Some(step));
+        assert_eq!(
+            verify_totp(RFC_SECRET, "081804", 1111111109 + TOTP_STEP_SECS),
+            Some(step)
+        );
+        assert_eq!(
+            verify_totp(RFC_SECRET, "081804", 1111111109 - TOTP_STEP_SECS),
+            Some(step)
+        );
+        assert_eq!(
+            verify_totp(RFC_SECRET, "081804", 1111111109 + 2 * TOTP_STEP_SECS),
+            None
+        );
+    }
+
+    #[test]
+    fn wrong_codes() {
+        for code in ["081805", "81804", "0818040", ""] {
+            assert_eq!(verify_totp(RFC_SECRET, code, 1111111109), None, "{}", code);
+        }
+        assert_eq!(verify_totp("not base32!", "081804", 1111111109), None);
+    }
+
+    #[test]
+    fn generated_secret() {
+        let secret = generate_totp_secret().unwrap();
+        assert_eq!(base32::decode(SECRET_ALPHABET, &secret).unwrap().len(), 20);
+        assert!(totp_code(&secret, 0).is_some());
+    }
+
+    #[test]
+    fn recovery_codes() {
+        let (codes, hashes) = generate_recovery_codes().unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        assert!(codes
+            .iter()
+            .all(|code| code.len() == 11 && code.as_bytes()[5] == b'-'));
+        assert_eq!(hashes[0], hash_api_key(&codes[0]));
+    }
+}
@@ -0,0 +1,270 @@
+use chrono::offset::Utc;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    serde::json::json,
+    Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+use super::{config::AccessLogConfig, request_id::RequestId};
+use crate::{
+    error::Error,
+    secure::{guards::cached_auth_context, ip::client_ip},
+    Result,
+};
+
+/// Characters of the api key shown in the access log
+const KEY_PREFIX_LEN: usize = 8;
+/// Lines waiting for the output, the next ones are dropped
+const QUEUE_LINES: usize = 10_000;
+
+/// Where the access log is written
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogOutput {
+    /// One line per request on the standard output
+    #[default]
+    Stdout,
+    /// Appended to a file, rotated by size
+    File,
+    /// Sent to the local syslog daemon
+    Syslog,
+}
+
+/// Time the request was received
+struct RequestStart(Option<Instant>);
+
+/// File renamed to `<path>.1`, `<path>.2`... once it reaches `max_bytes`
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // the oldest file is overwritten by the one before it
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 >= self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    Syslog(Logger<LoggerBackend, Formatter3164>),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> std::result::Result<(), String> {
+        match self {
+            Sink::Stdout => writeln!(io::stdout(), "{}", line).map_err(|e| e.to_string()),
+            Sink::File(file) => file.write_line(line).map_err(|e| e.to_string()),
+            Sink::Syslog(logger) => logger.info(line).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Fairing writing one JSON line per request.
+///
+/// The lines are written by a dedicated thread, so that no request waits for the output;
+/// the lines past a full queue are dropped & counted. Cloning shares the queue.
+#[derive(Clone)]
+pub struct AccessLog {
+    sender: Arc<Mutex<Option<SyncSender<String>>>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Log the lines dropped since the last report
+fn report_dropped(dropped: &AtomicU64) {
+    let count = dropped.swap(0, Ordering::Relaxed);
+    if count > 0 {
+        log::warn!("{} access log lines dropped, the output is too slow", count);
+    }
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self> {
+        //! Open the output of the access log
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! AccessLog::new(&AccessLogConfig::default());
+        //! ```
+        let mut sink = match config.output {
+            AccessLogOutput::Stdout => Sink::Stdout,
+            AccessLogOutput::File => Sink::File(RotatingFile::open(
+                PathBuf::from(&config.file),
+                config.max_bytes,
+                config.max_files,
+            )?),
+            AccessLogOutput::Syslog => {
+                let facility: Facility = config.syslog_facility.parse().map_err(|_| {
+                    Error::ConfigurationError(format!(
+                        "unknown syslog facility: {}",
+                        config.syslog_facility
+                    ))
+                })?;
+                let formatter = Formatter3164 {
+                    facility,
+                    hostname: None,
+                    process: env!("CARGO_PKG_NAME").into(),
+                    pid: std::process::id(),
+                };
+                Sink::Syslog(
+                    syslog::unix(formatter)
+                        .map_err(|e| Error::ConfigurationError(format!("syslog: {}", e)))?,
+                )
+            }
+        };
+
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_LINES);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = dropped.clone();
+        let writer = thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || {
+                for line in receiver {
+                    if let Err(e) = sink.write_line(&line) {
+                        log::error!("could not write the access log: {}", e);
+                    }
+                    report_dropped(&writer_dropped);
+                }
+            })?;
+
+        Ok(Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            writer: Arc::new(Mutex::new(Some(writer))),
+            dropped,
+        })
+    }
+
+    /// Stop taking lines and wait for the queued ones to be written.
+    ///
+    /// Blocking: joins the writer thread.
+    pub fn close(&self) {
+        self.sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(writer) = writer {
+            if writer.join().is_err() {
+                log::error!("the access log writer panicked");
+            }
+        }
+        report_dropped(&self.dropped);
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let latency = request
+            .local_cache(|| RequestStart(None))
+            .0
+            .map(|start| start.elapsed().as_secs_f64() * 1000.0);
+        // only known once a guard authenticated the request
+        let auth = cached_auth_context(request);
+
+        // the path only, the query may carry an api key
+        let line = json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "request_id": RequestId::of(request),
+            "email": auth.map(|context| &context.user.email),
+            "key_prefix": auth.map(|context| {
+                context.user.api_key.chars().take(KEY_PREFIX_LEN).collect::<String>()
+            }),
+            "method": request.method().as_str(),
+            "path": request.uri().path().as_str(),
+            "acl_rule": auth.map(|context| {
+                format!("{} {}", context.endpoint.method, context.endpoint.name)
+            }),
+            "status": response.status().code,
+            "latency_ms": latency,
+            "bytes": response.body().preset_size(),
+            "client_ip": client_ip(request).map(|ip| ip.to_string()),
+        });
+        if let Some(sender) = &*self.sender.lock().unwrap_or_else(PoisonError::into_inner) {
+            match sender.try_send(line.to_string()) {
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                // closed at shutdown
+                Err(TrySendError::Disconnected(_)) | Ok(_) => {}
+            }
+        }
+    }
+}
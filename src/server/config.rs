@@ -1,7 +1,45 @@
 #![allow(unused_must_use)]
-use crate::{error::Error, secure::cert::generate_cert};
-use serde::{de, Deserialize, Deserializer};
-use std::{collections::HashMap, fs::File, io::Read, net::IpAddr, path::Path};
+use crate::{
+    error::Error,
+    mailer::{MailerKind, SmtpTls},
+    models::{
+        endpoint::{Endpoint, Method},
+        ratelimit::RateTime,
+    },
+    secure::{
+        api_key::{ApiKeySource, API_KEY_PARAM},
+        cert::generate_cert,
+        mtls::MtlsMode,
+    },
+    server::{access_log::AccessLogOutput, telemetry::TraceExporter},
+};
+use ipnet::IpNet;
+use rocket::config::LogLevel;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+/// Prefix of the environment variables overriding the settings,
+/// e.g. `ROCKETAPI_SERVER__PORT` for `server.port`
+const ENV_PREFIX: &str = "ROCKETAPI";
+/// Separator of the nested keys in the environment variables
+const ENV_SEPARATOR: &str = "__";
+/// Settings given as comma separated lists in the environment variables
+const ENV_LIST_KEYS: [&str; 5] = [
+    "server.trusted_proxies",
+    "server.blocked_ips",
+    "metrics.allow_ips",
+    "auth.api_key.sources",
+    "signup.acl_allow_ips",
+];
+/// Extensions of the drop-in files, the other files of the directory are ignored
+const DROP_IN_EXTENSIONS: [&str; 4] = ["yml", "yaml", "toml", "json"];
+/// Shown in place of the secrets when the settings are printed
+const REDACTED: &str = "[redacted]";
 
 const SRV_ADDR: &str = "127.0.0.1";
 const SRV_PORT: usize = 8080;
@@ -9,6 +47,8 @@ const SRV_KEEP_ALIVE: usize = 60;
 const SRV_FORMS_LIMIT: usize = 1024 * 256;
 const SRV_JSON_LIMIT: usize = 1024 * 256;
 const SRV_SECRET_KEY: &str = "t/xZkYvxfC8CSfTSH9ANiIR9t1SvLHqOYZ7vH4fp11s=";
+/// Secret key once shipped in config.sample.yml
+const SAMPLE_SECRET_KEY: &str = "8Xui8SN4mI+7egV/9dlfYYLGQJeEx4+DwmSQLwDVXJg=";
 
 const SSL_ENABLED: bool = false;
 const SSL_GENERATE_SELF_SIGNED: bool = true;
@@ -20,11 +60,71 @@ const MONGO_PORT: usize = 27017;
 const MONGO_USER: &str = "";
 const MONGO_PASS: &str = "";
 
+const HMAC_ENABLED: bool = false;
+const HMAC_MAX_SKEW_SECS: u64 = 300;
+
+const TOKEN_ENABLED: bool = false;
+const TOKEN_TTL_SECS: u64 = 900;
+
+const OAUTH_ENABLED: bool = false;
+
+const SESSION_ENABLED: bool = false;
+const SESSION_COOKIE_NAME: &str = "rocketapi_session";
+const SESSION_TTL_SECS: u64 = 3600;
+const SESSION_LOGIN_THROTTLE: &str = "10/min";
+
+const MFA_ISSUER: &str = "rocketapi";
+const MFA_REQUIRE_FOR_USER_ADMIN: bool = false;
+const MFA_MAX_AGE_SECS: u64 = 300;
+
+const CREDENTIALS_FAILED_ATTEMPTS: &str = "5/min";
+
+const SIGNUP_ENABLED: bool = false;
+const SIGNUP_TOKEN_TTL_SECS: u64 = 24 * 3600;
+const SIGNUP_THROTTLE: &str = "5/hour";
+const SIGNUP_RESEND_AFTER_SECS: u64 = 300;
+
+const MAILER_FROM: &str = "rocketapi@localhost";
+const MAILER_FILE: &str = "./mail.log";
+const SMTP_PORT: u16 = 587;
+
+const REVOCATION_POLL_SECS: u64 = 10;
+
+const USAGE_FLUSH_SECS: u64 = 5;
+const USAGE_WINDOW_DAYS: u32 = 30;
+const USAGE_STALE_AFTER_DAYS: u32 = 90;
+
+const ACCESS_LOG_ENABLED: bool = false;
+const ACCESS_LOG_FILE: &str = "./access.log";
+const ACCESS_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const ACCESS_LOG_MAX_FILES: usize = 5;
+const ACCESS_LOG_SYSLOG_FACILITY: &str = "daemon";
+
+const METRICS_ENABLED: bool = false;
+const METRICS_ALLOW_IPS: [&str; 2] = ["127.0.0.1/32", "::1/128"];
+
+const TRACING_OTLP_ENDPOINT: &str = "http://localhost:4318/v1/traces";
+const TRACING_FILE: &str = "./traces.json";
+const TRACING_SERVICE_NAME: &str = "rocketapi";
+
+const HEALTH_TIMEOUT_MS: u64 = 2000;
+const HEALTH_CERT_EXPIRY_WARN_DAYS: u32 = 14;
+
+const SHUTDOWN_DRAIN_SECS: u64 = 5;
+const SHUTDOWN_GRACE_SECS: u32 = 10;
+const SHUTDOWN_FLUSH_TIMEOUT_SECS: u64 = 10;
+
+const RELOAD_WATCH: bool = true;
+const RELOAD_POLL_SECS: u64 = 5;
+
+const CACHE_TTL_SECS: u64 = 60;
+const CACHE_CAPACITY: usize = 10_000;
+
 /// Rocket API Server parameters
-#[derive(Deserialize, Clone, Debug, Default)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct Settings {
     /// Server config related parameters
-    #[serde(default)]
+    #[serde(default, deserialize_with = "configure_server")]
     pub server: ServerConfig,
 
     /// SSL Configuration
@@ -34,32 +134,393 @@ pub struct Settings {
     /// Mongo DB configuration
     #[serde(default, deserialize_with = "configure_mongodb")]
     pub mongo_db: MongoDb,
+
+    /// Authenticated user cache configuration
+    #[serde(default)]
+    pub cache: CacheConfig,
+
+    /// Authentication methods configuration
+    #[serde(default)]
+    pub auth: AuthConfig,
+
+    /// Self-service signup configuration
+    #[serde(default)]
+    pub signup: SignupConfig,
+
+    /// Outgoing emails configuration
+    #[serde(default, deserialize_with = "configure_mailer")]
+    pub mailer: MailerConfig,
+
+    /// Api key usage tracking configuration
+    #[serde(default)]
+    pub usage: UsageConfig,
+
+    /// Access log configuration
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+
+    /// Prometheus metrics configuration
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+
+    /// OpenTelemetry tracing configuration
+    #[serde(default)]
+    pub tracing: TracingConfig,
+
+    /// Health checks configuration
+    #[serde(default)]
+    pub health: HealthConfig,
+
+    /// Graceful shutdown configuration
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+
+    /// Rate limit defaults
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// Configuration reload parameters
+    #[serde(default)]
+    pub reload: ReloadConfig,
+}
+
+/// Units a request to the route consumes from the throttle
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct EndpointCost {
+    pub name: String,
+    pub method: Method,
+    pub cost: usize,
+}
+
+/// Rate limit defaults, applied when the user's ACL rule sets none
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct RateLimitConfig {
+    /// Throttle of the endpoints whose ACL rule has none, e.g. 100/min
+    #[serde(default)]
+    pub default_throttle: Option<RateTime>,
+    /// Route costs; routes not listed cost 1 unit
+    #[serde(default)]
+    pub costs: Vec<EndpointCost>,
+}
+
+/// Configuration reload parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ReloadConfig {
+    /// Reload when the config file or a drop-in file changes, besides SIGHUP
+    #[serde(default = "default_reload_watch")]
+    pub watch: bool,
+    /// Seconds between two checks of the config files
+    #[serde(default = "default_reload_poll_secs")]
+    pub poll_secs: u64,
+}
+
+impl Default for ReloadConfig {
+    fn default() -> Self {
+        Self {
+            watch: RELOAD_WATCH,
+            poll_secs: RELOAD_POLL_SECS,
+        }
+    }
+}
+
+/// Graceful shutdown parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ShutdownConfig {
+    /// Seconds the requests are still served after SIGTERM/SIGINT, with a failing readiness
+    #[serde(default = "default_shutdown_drain_secs")]
+    pub drain_secs: u64,
+    /// Seconds the requests in flight get to complete once the server stops
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub grace_secs: u32,
+    /// Seconds to write the buffered key usage to the backend
+    #[serde(default = "default_shutdown_flush_timeout_secs")]
+    pub flush_timeout_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_secs: SHUTDOWN_DRAIN_SECS,
+            grace_secs: SHUTDOWN_GRACE_SECS,
+            flush_timeout_secs: SHUTDOWN_FLUSH_TIMEOUT_SECS,
+        }
+    }
+}
+
+/// Health check parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HealthConfig {
+    /// Milliseconds the readiness check waits for MongoDB
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Days before the expiry of the TLS certificate from which the readiness warns
+    #[serde(default = "default_health_cert_expiry_warn_days")]
+    pub cert_expiry_warn_days: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: HEALTH_TIMEOUT_MS,
+            cert_expiry_warn_days: HEALTH_CERT_EXPIRY_WARN_DAYS,
+        }
+    }
+}
+
+/// OpenTelemetry tracing parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TracingConfig {
+    /// none, otlp or file
+    #[serde(default)]
+    pub exporter: TraceExporter,
+    /// OTLP/HTTP traces endpoint of the collector
+    #[serde(default = "default_tracing_otlp_endpoint")]
+    pub otlp_endpoint: String,
+    /// File of the `file` exporter
+    #[serde(default = "default_tracing_file")]
+    pub file: String,
+    /// `service.name` of the spans
+    #[serde(default = "default_tracing_service_name")]
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            exporter: TraceExporter::default(),
+            otlp_endpoint: TRACING_OTLP_ENDPOINT.to_string(),
+            file: TRACING_FILE.to_string(),
+            service_name: TRACING_SERVICE_NAME.to_string(),
+        }
+    }
+}
+
+/// Prometheus metrics parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MetricsConfig {
+    /// Serve the metrics at `/metrics`
+    #[serde(default = "default_metrics_enabled")]
+    pub enabled: bool,
+    /// Clients (ip or CIDR) allowed to read the metrics
+    #[serde(
+        default = "default_metrics_allow_ips",
+        deserialize_with = "configure_trusted_proxies"
+    )]
+    pub allow_ips: Vec<IpNet>,
+    /// Serve the metrics on this address only, instead of the api address
+    #[serde(default)]
+    pub bind: Option<SocketAddr>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: METRICS_ENABLED,
+            allow_ips: default_metrics_allow_ips(),
+            bind: None,
+        }
+    }
+}
+
+/// Access log parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct AccessLogConfig {
+    /// Log one JSON line per request
+    #[serde(default = "default_access_log_enabled")]
+    pub enabled: bool,
+    /// stdout, file or syslog
+    #[serde(default)]
+    pub output: AccessLogOutput,
+    /// File of the `file` output
+    #[serde(default = "default_access_log_file")]
+    pub file: String,
+    /// Size at which the file is rotated, 0 to never rotate
+    #[serde(default = "default_access_log_max_bytes")]
+    pub max_bytes: u64,
+    /// Rotated files kept
+    #[serde(default = "default_access_log_max_files")]
+    pub max_files: usize,
+    /// Facility of the `syslog` output
+    #[serde(default = "default_access_log_syslog_facility")]
+    pub syslog_facility: String,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: ACCESS_LOG_ENABLED,
+            output: AccessLogOutput::default(),
+            file: ACCESS_LOG_FILE.to_string(),
+            max_bytes: ACCESS_LOG_MAX_BYTES,
+            max_files: ACCESS_LOG_MAX_FILES,
+            syslog_facility: ACCESS_LOG_SYSLOG_FACILITY.to_string(),
+        }
+    }
+}
+
+/// Api key usage tracking parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UsageConfig {
+    /// Seconds between two writes of the buffered usage
+    #[serde(default = "default_usage_flush_secs")]
+    pub flush_secs: u64,
+    /// Days of the rolling request count
+    #[serde(default = "default_usage_window_days")]
+    pub window_days: u32,
+    /// Days without a request after which the stale-key report lists a key
+    #[serde(default = "default_usage_stale_after_days")]
+    pub stale_after_days: u32,
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            flush_secs: USAGE_FLUSH_SECS,
+            window_days: USAGE_WINDOW_DAYS,
+            stale_after_days: USAGE_STALE_AFTER_DAYS,
+        }
+    }
+}
+
+/// Sources of the settings, on top of the defaults; each one overrides the previous ones
+#[derive(Debug, Default, Clone)]
+pub struct ConfigSources {
+    /// Main config file
+    pub file: Option<PathBuf>,
+    /// Directory of drop-in config files, read in file name order
+    pub drop_in_dir: Option<PathBuf>,
+    /// `key.path` & value pairs from the command line, applied after the environment
+    pub overrides: Vec<(String, String)>,
+}
+
+/// Show a secret as set or not, never its value
+fn serialize_secret<S>(secret: &str, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if secret.is_empty() {
+        serializer.serialize_str("")
+    } else {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+fn serialize_optional_secret<S>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match secret {
+        Some(secret) => serialize_secret(secret, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Config files of the drop-in directory, in file name order
+pub(super) fn drop_in_files(dir: &Path) -> crate::Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Err(Error::ConfigurationError(format!(
+            "config directory {} not found",
+            dir.display()
+        )));
+    }
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.retain(|file| {
+        file.is_file()
+            && file
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| DROP_IN_EXTENSIONS.contains(&extension))
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// The `ROCKETAPI_` environment variables as strings, but for the comma separated lists:
+/// the numbers & booleans are parsed when deserialized, the secrets are taken verbatim
+#[derive(Clone, Debug)]
+struct EnvironmentSource(config::Environment);
+
+impl EnvironmentSource {
+    /// The variables of the process, unless given
+    fn new(variables: Option<config::Map<String, String>>) -> Self {
+        Self(
+            config::Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator(ENV_SEPARATOR)
+                .ignore_empty(true)
+                .source(variables),
+        )
+    }
+}
+
+impl config::Source for EnvironmentSource {
+    fn clone_into_box(&self) -> Box<dyn config::Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<config::Map<String, config::Value>, config::ConfigError> {
+        let mut values = self.0.collect()?;
+        let origin = "the environment".to_string();
+        for key in ENV_LIST_KEYS {
+            if let Some(value) = values.remove(key) {
+                let list = value
+                    .into_string()?
+                    .split(',')
+                    .map(|item| config::Value::new(Some(&origin), item))
+                    .collect::<Vec<_>>();
+                values.insert(key.to_string(), config::Value::new(Some(&origin), list));
+            }
+        }
+        Ok(values)
+    }
 }
 
 impl Settings {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
-        //! Read configuration settings from yaml file
+    pub fn from_sources(sources: &ConfigSources) -> crate::Result<Self> {
+        //! Layer the settings: defaults, config file, drop-in files,
+        //! `ROCKETAPI_` environment variables & command line overrides
         //!
         //! ## Example usage
         //! ```ignore
-        //! Settings::from_file("config.sample.yml");
+        //! // ROCKETAPI_SERVER__PORT=9000 overrides the port of the config file
+        //! Settings::from_sources(&ConfigSources {
+        //!     file: Some("config.sample.yml".into()),
+        //!     ..ConfigSources::default()
+        //! });
         //! ```
         //!
-        match config::Config::builder()
-            .add_source(config::File::with_name(path.as_ref().to_str().unwrap()))
-            .build()
-        {
-            Ok(c) => match c.try_deserialize() {
-                Ok(cfg) => Ok(cfg),
-                Err(e) => Err(Error::ConfigurationError(e.to_string())),
-            },
-            Err(e) => Err(Error::ConfigurationError(e.to_string())),
+        let mut builder = config::Config::builder();
+        if let Some(file) = &sources.file {
+            builder = builder.add_source(config::File::from(file.as_path()));
+        }
+        if let Some(dir) = &sources.drop_in_dir {
+            for file in drop_in_files(dir)? {
+                builder = builder.add_source(config::File::from(file));
+            }
         }
+        builder = builder.add_source(EnvironmentSource::new(None));
+        for (key, value) in &sources.overrides {
+            builder = builder
+                .set_override(key.as_str(), value.as_str())
+                .map_err(|e| Error::ConfigurationError(e.to_string()))?;
+        }
+
+        builder
+            .build()
+            .and_then(|c| c.try_deserialize())
+            .map_err(|e| Error::ConfigurationError(e.to_string()))
+    }
+
+    /// The settings as JSON, with the secrets redacted
+    pub fn to_redacted_json(&self) -> crate::Result<String> {
+        rocket::serde::json::to_pretty_string(self).map_err(|e| Error::FormatError(e.to_string()))
     }
 }
 
 /// Rocket Server params
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct ServerConfig {
     /// Server Ip Address to start Rocket API Server
     #[serde(default = "default_server_host")]
@@ -77,8 +538,37 @@ pub struct ServerConfig {
     #[serde(default = "default_server_json_limit")]
     pub json_limit: usize,
     /// Api Server Secret key
-    #[serde(default = "default_server_secret_key")]
+    #[serde(
+        default = "default_server_secret_key",
+        serialize_with = "serialize_secret"
+    )]
     pub secret_key: String,
+    /// File holding the secret key, e.g. a mounted Docker/Kubernetes secret; wins over `secret_key`
+    #[serde(default)]
+    pub secret_key_file: Option<String>,
+    /// Development mode: allows the built-in secret key
+    #[serde(default)]
+    pub dev_mode: bool,
+    /// Log level (off, critical, normal or debug), rocket's own setting when not set
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+    /// Proxies (ip or CIDR) whose forwarding headers are honoured
+    #[serde(default, deserialize_with = "configure_trusted_proxies")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Clients (ip or CIDR) refused on every route
+    #[serde(default, deserialize_with = "configure_blocked_ips")]
+    pub blocked_ips: Vec<IpNet>,
+    /// Expect a PROXY protocol (v1/v2) header on every accepted connection
+    #[serde(default)]
+    pub proxy_protocol: bool,
+}
+
+impl ServerConfig {
+    /// Whether the secret key is a published one, shared by every such deployment:
+    /// built in the binary or copied from the sample config
+    pub fn uses_default_secret_key(&self) -> bool {
+        [SRV_SECRET_KEY, SAMPLE_SECRET_KEY].contains(&self.secret_key.as_str())
+    }
 }
 
 impl Default for ServerConfig {
@@ -90,30 +580,46 @@ impl Default for ServerConfig {
             forms_limit: SRV_FORMS_LIMIT,
             json_limit: SRV_JSON_LIMIT,
             secret_key: SRV_SECRET_KEY.into(),
+            secret_key_file: None,
+            dev_mode: false,
+            log_level: None,
+            trusted_proxies: vec![],
+            blocked_ips: vec![],
+            proxy_protocol: false,
         }
     }
 }
 
 /// Server SSL params
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct SslConfig {
     /// Enabled: yes/no
     #[serde(default = "default_ssl_enabled")]
     pub enabled: bool,
     /// Let the server generate a self-signed pair: yes/no
     #[serde(default = "default_ssl_self_signed")]
-    generate_self_signed: bool,
+    pub generate_self_signed: bool,
     /// key file (if generate_self_signed is `NO`)
     #[serde(default = "default_ssl_key_file")]
     key_file: String,
     /// certificate pem file (if generate_self_signed is `NO`)
     #[serde(default = "default_ssl_cert_file")]
     cert_file: String,
+    /// CA bundle pem file verifying the client certificates (enables mutual TLS)
+    #[serde(default)]
+    client_ca_file: Option<String>,
+    /// Refuse TLS connections without a client certificate
+    #[serde(default)]
+    pub client_cert_mandatory: bool,
 
     // Not to be included in config file
     // hidden and for use with rocket app
+    #[serde(skip_serializing)]
     pub pem_certificate: Option<Vec<u8>>,
+    #[serde(skip_serializing)]
     pub pem_private_key: Option<Vec<u8>>,
+    #[serde(skip_serializing)]
+    pub pem_client_ca: Option<Vec<u8>>,
 }
 
 impl Default for SslConfig {
@@ -123,14 +629,17 @@ impl Default for SslConfig {
             generate_self_signed: SSL_GENERATE_SELF_SIGNED,
             key_file: SSL_KEY_FILE.into(),
             cert_file: SSL_CERT_FILE.into(),
+            client_ca_file: None,
+            client_cert_mandatory: false,
             pem_certificate: None,
             pem_private_key: None,
+            pem_client_ca: None,
         }
     }
 }
 
 /// Mongo Databse parameters
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct MongoDb {
     #[serde(default = "default_mongo_host")]
     pub mongo_host: String,
@@ -138,12 +647,16 @@ pub struct MongoDb {
     pub mongo_port: usize,
     #[serde(default = "default_mongo_user")]
     pub auth_user: String,
-    #[serde(default = "default_mongo_pass")]
+    #[serde(default = "default_mongo_pass", serialize_with = "serialize_secret")]
     pub auth_pass: String,
+    /// File holding the password, wins over `auth_pass`
+    #[serde(default)]
+    pub auth_pass_file: Option<String>,
     #[serde(default = "default_mongo_db")]
     pub db: HashMap<String, String>,
 
     // hidden setting to deserialize from host & port
+    #[serde(skip_serializing)]
     pub db_uri: Option<String>,
 }
 
@@ -154,12 +667,322 @@ impl Default for MongoDb {
             mongo_port: MONGO_PORT,
             auth_user: MONGO_USER.into(),
             auth_pass: MONGO_PASS.into(),
+            auth_pass_file: None,
             db: HashMap::new(),
             db_uri: None,
         }
     }
 }
 
+/// Authenticated user cache parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CacheConfig {
+    /// Seconds a user stays cached; 0 disables the cache
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Maximum number of cached users; 0 disables the cache
+    #[serde(default = "default_cache_capacity")]
+    pub capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: CACHE_TTL_SECS,
+            capacity: CACHE_CAPACITY,
+        }
+    }
+}
+
+/// Self-service signup parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SignupConfig {
+    /// Serve `/signup` & `/signup/verify`
+    #[serde(default = "default_signup_enabled")]
+    pub enabled: bool,
+    /// Seconds a verification token stays valid
+    #[serde(default = "default_signup_token_ttl_secs")]
+    pub token_ttl_secs: u64,
+    /// Signups allowed from one client ip, e.g. 5/hour
+    #[serde(default = "default_signup_throttle")]
+    pub throttle: RateTime,
+    /// Seconds before the token of a pending signup is mailed again
+    #[serde(default = "default_signup_resend_after_secs")]
+    pub resend_after_secs: u64,
+    /// Page the verification link points to, the token is appended as `?token=`
+    #[serde(default)]
+    pub verify_url: Option<String>,
+    /// ACL template of the verified accounts: allowed ips
+    #[serde(default = "default_signup_acl_allow_ips")]
+    pub acl_allow_ips: Vec<String>,
+    /// ACL template of the verified accounts: allowed endpoints
+    #[serde(default)]
+    pub acl_allow_endpoints: Vec<Endpoint>,
+}
+
+impl Default for SignupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: SIGNUP_ENABLED,
+            token_ttl_secs: SIGNUP_TOKEN_TTL_SECS,
+            throttle: default_signup_throttle(),
+            resend_after_secs: SIGNUP_RESEND_AFTER_SECS,
+            verify_url: None,
+            acl_allow_ips: default_signup_acl_allow_ips(),
+            acl_allow_endpoints: vec![],
+        }
+    }
+}
+
+/// Outgoing emails parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MailerConfig {
+    /// log | file | smtp
+    #[serde(default)]
+    pub kind: MailerKind,
+    /// Sender of the emails
+    #[serde(default = "default_mailer_from")]
+    pub from: String,
+    /// File the `file` mailer appends to
+    #[serde(default = "default_mailer_file")]
+    pub file: String,
+    /// SMTP relay of the `smtp` mailer
+    #[serde(default)]
+    pub smtp_host: String,
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+    /// none | start_tls | tls
+    #[serde(default)]
+    pub smtp_tls: SmtpTls,
+    #[serde(default)]
+    pub smtp_user: Option<String>,
+    #[serde(default, serialize_with = "serialize_optional_secret")]
+    pub smtp_pass: Option<String>,
+    /// File holding the SMTP password, wins over `smtp_pass`
+    #[serde(default)]
+    pub smtp_pass_file: Option<String>,
+}
+
+impl Default for MailerConfig {
+    fn default() -> Self {
+        Self {
+            kind: MailerKind::default(),
+            from: MAILER_FROM.to_string(),
+            file: MAILER_FILE.to_string(),
+            smtp_host: String::new(),
+            smtp_port: SMTP_PORT,
+            smtp_tls: SmtpTls::default(),
+            smtp_user: None,
+            smtp_pass: None,
+            smtp_pass_file: None,
+        }
+    }
+}
+
+/// Authentication methods
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AuthConfig {
+    /// Where the api key is read from
+    #[serde(default)]
+    pub api_key: ApiKeyConfig,
+    /// HMAC request signing
+    #[serde(default)]
+    pub hmac: HmacConfig,
+    /// Client certificate authentication
+    #[serde(default)]
+    pub mtls: MtlsConfig,
+    /// Short lived access tokens exchanged from an api key
+    #[serde(default)]
+    pub token: TokenConfig,
+    /// OAuth2 client credentials grant
+    #[serde(default)]
+    pub oauth: OAuthConfig,
+    /// Cookie sessions of the admins
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// TOTP second factor
+    #[serde(default)]
+    pub mfa: MfaConfig,
+    /// Password & second factor checks
+    #[serde(default)]
+    pub credentials: CredentialsConfig,
+    /// Emergency api key revocations
+    #[serde(default)]
+    pub revocation: RevocationConfig,
+}
+
+/// Api key revocation parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RevocationConfig {
+    /// Seconds between two reloads of the revoked keys & tokens from the database
+    #[serde(default = "default_revocation_poll_secs")]
+    pub poll_secs: u64,
+}
+
+impl Default for RevocationConfig {
+    fn default() -> Self {
+        Self {
+            poll_secs: REVOCATION_POLL_SECS,
+        }
+    }
+}
+
+/// Second factor parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct MfaConfig {
+    /// Issuer shown in the authenticator apps
+    #[serde(default = "default_mfa_issuer")]
+    pub issuer: String,
+    /// Require a recent second factor to create, update & delete users
+    #[serde(default = "default_mfa_require_for_user_admin")]
+    pub require_for_user_admin: bool,
+    /// Seconds a second factor given at session login stays recent
+    #[serde(default = "default_mfa_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl Default for MfaConfig {
+    fn default() -> Self {
+        Self {
+            issuer: MFA_ISSUER.to_string(),
+            require_for_user_admin: MFA_REQUIRE_FOR_USER_ADMIN,
+            max_age_secs: MFA_MAX_AGE_SECS,
+        }
+    }
+}
+
+/// Password & second factor check parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CredentialsConfig {
+    /// Failed checks allowed for one user, and from one client ip, e.g. 5/min
+    #[serde(default = "default_credentials_failed_attempts")]
+    pub failed_attempts: RateTime,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            failed_attempts: default_credentials_failed_attempts(),
+        }
+    }
+}
+
+/// Admin cookie session parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SessionConfig {
+    /// Serve `/session` login & logout, and accept the session cookie
+    #[serde(default = "default_session_enabled")]
+    pub enabled: bool,
+    /// Name of the private session cookie
+    #[serde(default = "default_session_cookie_name")]
+    pub cookie_name: String,
+    /// Seconds a session stays valid
+    #[serde(default = "default_session_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Login attempts allowed from one client ip, e.g. 10/min
+    #[serde(default = "default_session_login_throttle")]
+    pub login_throttle: RateTime,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: SESSION_ENABLED,
+            cookie_name: SESSION_COOKIE_NAME.to_string(),
+            ttl_secs: SESSION_TTL_SECS,
+            login_throttle: default_session_login_throttle(),
+        }
+    }
+}
+
+/// Api key credential sources
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ApiKeyConfig {
+    /// header | bearer | api_key | cookie | query, tried in order
+    #[serde(default = "default_api_key_sources")]
+    pub sources: Vec<ApiKeySource>,
+    /// Name of the cookie carrying the api key
+    #[serde(default = "default_api_key_cookie_name")]
+    pub cookie_name: String,
+    /// Name of the query parameter carrying the api key, redacted from the logs
+    #[serde(default = "default_api_key_query_param")]
+    pub query_param: String,
+}
+
+impl Default for ApiKeyConfig {
+    fn default() -> Self {
+        Self {
+            sources: default_api_key_sources(),
+            cookie_name: default_api_key_cookie_name(),
+            query_param: default_api_key_query_param(),
+        }
+    }
+}
+
+/// OAuth2 parameters, the access tokens follow the `token` parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct OAuthConfig {
+    /// Serve the `/oauth` token, introspection, revocation & client registration endpoints
+    #[serde(default = "default_oauth_enabled")]
+    pub enabled: bool,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: OAUTH_ENABLED,
+        }
+    }
+}
+
+/// Access token parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TokenConfig {
+    /// Serve `/auth/token` and accept `Authorization: Bearer <token>`
+    #[serde(default = "default_token_enabled")]
+    pub enabled: bool,
+    /// Seconds an access token stays valid
+    #[serde(default = "default_token_ttl_secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            enabled: TOKEN_ENABLED,
+            ttl_secs: TOKEN_TTL_SECS,
+        }
+    }
+}
+
+/// Client certificate authentication parameters
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct MtlsConfig {
+    /// disabled | certificate | certificate_and_api_key
+    #[serde(default)]
+    pub mode: MtlsMode,
+}
+
+/// HMAC request signing parameters
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HmacConfig {
+    /// Accept `Authorization: HMAC-SHA256 ...` signed requests
+    #[serde(default = "default_hmac_enabled")]
+    pub enabled: bool,
+    /// Maximum difference in seconds between the request timestamp and the server clock
+    #[serde(default = "default_hmac_max_skew_secs")]
+    pub max_skew_secs: u64,
+}
+
+impl Default for HmacConfig {
+    fn default() -> Self {
+        Self {
+            enabled: HMAC_ENABLED,
+            max_skew_secs: HMAC_MAX_SKEW_SECS,
+        }
+    }
+}
+
 // All Server defaults
 fn default_server_host() -> IpAddr {
     SRV_ADDR.parse().unwrap()
@@ -223,6 +1046,210 @@ fn default_mongo_db() -> HashMap<String, String> {
     HashMap::new()
 }
 
+// All Cache defaults
+fn default_cache_ttl_secs() -> u64 {
+    CACHE_TTL_SECS
+}
+
+fn default_cache_capacity() -> usize {
+    CACHE_CAPACITY
+}
+
+// All Auth defaults
+fn default_api_key_sources() -> Vec<ApiKeySource> {
+    vec![ApiKeySource::Header]
+}
+
+fn default_api_key_cookie_name() -> String {
+    API_KEY_PARAM.to_string()
+}
+
+fn default_api_key_query_param() -> String {
+    API_KEY_PARAM.to_string()
+}
+
+fn default_hmac_enabled() -> bool {
+    HMAC_ENABLED
+}
+
+fn default_hmac_max_skew_secs() -> u64 {
+    HMAC_MAX_SKEW_SECS
+}
+
+fn default_token_enabled() -> bool {
+    TOKEN_ENABLED
+}
+
+fn default_token_ttl_secs() -> u64 {
+    TOKEN_TTL_SECS
+}
+
+fn default_oauth_enabled() -> bool {
+    OAUTH_ENABLED
+}
+
+fn default_session_enabled() -> bool {
+    SESSION_ENABLED
+}
+
+fn default_session_cookie_name() -> String {
+    SESSION_COOKIE_NAME.to_string()
+}
+
+fn default_session_ttl_secs() -> u64 {
+    SESSION_TTL_SECS
+}
+
+fn default_session_login_throttle() -> RateTime {
+    SESSION_LOGIN_THROTTLE
+        .parse()
+        .expect("valid default login throttle")
+}
+
+fn default_mfa_issuer() -> String {
+    MFA_ISSUER.to_string()
+}
+
+fn default_mfa_require_for_user_admin() -> bool {
+    MFA_REQUIRE_FOR_USER_ADMIN
+}
+
+fn default_mfa_max_age_secs() -> u64 {
+    MFA_MAX_AGE_SECS
+}
+
+fn default_credentials_failed_attempts() -> RateTime {
+    CREDENTIALS_FAILED_ATTEMPTS
+        .parse()
+        .expect("valid default failed attempts throttle")
+}
+
+fn default_revocation_poll_secs() -> u64 {
+    REVOCATION_POLL_SECS
+}
+
+// All Tracing defaults
+fn default_tracing_otlp_endpoint() -> String {
+    TRACING_OTLP_ENDPOINT.to_string()
+}
+
+fn default_tracing_file() -> String {
+    TRACING_FILE.to_string()
+}
+
+fn default_tracing_service_name() -> String {
+    TRACING_SERVICE_NAME.to_string()
+}
+
+// All Metrics defaults
+fn default_metrics_enabled() -> bool {
+    METRICS_ENABLED
+}
+
+fn default_metrics_allow_ips() -> Vec<IpNet> {
+    METRICS_ALLOW_IPS
+        .iter()
+        .filter_map(|network| network.parse().ok())
+        .collect()
+}
+
+// All Access log defaults
+fn default_access_log_enabled() -> bool {
+    ACCESS_LOG_ENABLED
+}
+
+fn default_access_log_file() -> String {
+    ACCESS_LOG_FILE.to_string()
+}
+
+fn default_access_log_max_bytes() -> u64 {
+    ACCESS_LOG_MAX_BYTES
+}
+
+fn default_access_log_max_files() -> usize {
+    ACCESS_LOG_MAX_FILES
+}
+
+fn default_access_log_syslog_facility() -> String {
+    ACCESS_LOG_SYSLOG_FACILITY.to_string()
+}
+
+// All Usage defaults
+fn default_health_timeout_ms() -> u64 {
+    HEALTH_TIMEOUT_MS
+}
+
+fn default_health_cert_expiry_warn_days() -> u32 {
+    HEALTH_CERT_EXPIRY_WARN_DAYS
+}
+
+fn default_shutdown_drain_secs() -> u64 {
+    SHUTDOWN_DRAIN_SECS
+}
+
+fn default_shutdown_grace_secs() -> u32 {
+    SHUTDOWN_GRACE_SECS
+}
+
+fn default_shutdown_flush_timeout_secs() -> u64 {
+    SHUTDOWN_FLUSH_TIMEOUT_SECS
+}
+
+fn default_reload_watch() -> bool {
+    RELOAD_WATCH
+}
+
+fn default_reload_poll_secs() -> u64 {
+    RELOAD_POLL_SECS
+}
+
+fn default_usage_flush_secs() -> u64 {
+    USAGE_FLUSH_SECS
+}
+
+fn default_usage_window_days() -> u32 {
+    USAGE_WINDOW_DAYS
+}
+
+fn default_usage_stale_after_days() -> u32 {
+    USAGE_STALE_AFTER_DAYS
+}
+
+// All Signup & Mailer defaults
+fn default_signup_enabled() -> bool {
+    SIGNUP_ENABLED
+}
+
+fn default_signup_token_ttl_secs() -> u64 {
+    SIGNUP_TOKEN_TTL_SECS
+}
+
+fn default_signup_throttle() -> RateTime {
+    SIGNUP_THROTTLE
+        .parse()
+        .expect("valid default signup throttle")
+}
+
+fn default_signup_resend_after_secs() -> u64 {
+    SIGNUP_RESEND_AFTER_SECS
+}
+
+fn default_signup_acl_allow_ips() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn default_mailer_from() -> String {
+    MAILER_FROM.to_string()
+}
+
+fn default_mailer_file() -> String {
+    MAILER_FILE.to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    SMTP_PORT
+}
+
 /// SSL configuration deserializer
 fn configure_ssl<'de, D>(deserializer: D) -> Result<Option<SslConfig>, D::Error>
 where
@@ -244,33 +1271,105 @@ where
                     return Err(de::Error::custom("key_file and/or cert_file not available"));
                 } else {
                     // read key
-                    let mut key = Vec::new();
-                    {
-                        let mut kf = File::open(&s.key_file).unwrap();
-                        kf.read_to_end(&mut key);
-                    }
+                    let key = fs::read(&s.key_file).map_err(|e| {
+                        de::Error::custom(format!("key_file {}: {}", s.key_file, e))
+                    })?;
                     // read certificate
-                    let mut cert = Vec::new();
-                    {
-                        let mut cf = File::open(&s.cert_file).unwrap();
-                        cf.read_to_end(&mut cert);
-                    }
+                    let cert = fs::read(&s.cert_file).map_err(|e| {
+                        de::Error::custom(format!("cert_file {}: {}", s.cert_file, e))
+                    })?;
                     s.pem_certificate = Some(cert);
                     s.pem_private_key = Some(key);
                 }
             }
+            if let (true, Some(ca_file)) = (s.enabled, &s.client_ca_file) {
+                // mutual TLS is enabled, read the client CA bundle
+                if !Path::new(ca_file).is_file() {
+                    return Err(de::Error::custom("client_ca_file not available"));
+                }
+                let ca = fs::read(ca_file)
+                    .map_err(|e| de::Error::custom(format!("client_ca_file {}: {}", ca_file, e)))?;
+                s.pem_client_ca = Some(ca);
+            }
             Ok(Some(s))
         }
         None => Ok(None),
     }
 }
 
+/// Trusted proxies deserializer, accepts plain ip addresses as well as CIDR ranges
+fn configure_trusted_proxies<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_ip_networks(Vec::<String>::deserialize(deserializer)?, "trusted proxy")
+}
+
+/// Blocked clients deserializer
+fn configure_blocked_ips<'de, D>(deserializer: D) -> Result<Vec<IpNet>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_ip_networks(Vec::<String>::deserialize(deserializer)?, "blocked ip")
+}
+
+/// Parse ip addresses or CIDR networks
+fn parse_ip_networks<E: de::Error>(networks: Vec<String>, what: &str) -> Result<Vec<IpNet>, E> {
+    networks
+        .iter()
+        .map(|network| {
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| E::custom(format!("invalid {} '{}'", what, network)))
+        })
+        .collect()
+}
+
+/// Read a secret from its file, without the trailing line break
+fn read_secret_file<E: de::Error>(path: &str) -> Result<String, E> {
+    let secret = fs::read_to_string(path)
+        .map_err(|e| E::custom(format!("could not read the secret file {}: {}", path, e)))?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(E::custom(format!("the secret file {} is empty", path)));
+    }
+    Ok(secret.to_string())
+}
+
+/// Server configuration deserializer
+fn configure_server<'de, D>(deserializer: D) -> Result<ServerConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut server_config = ServerConfig::deserialize(deserializer)?;
+    if let Some(path) = &server_config.secret_key_file {
+        server_config.secret_key = read_secret_file(path)?;
+    }
+    Ok(server_config)
+}
+
+/// Mailer configuration deserializer
+fn configure_mailer<'de, D>(deserializer: D) -> Result<MailerConfig, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut mailer_config = MailerConfig::deserialize(deserializer)?;
+    if let Some(path) = &mailer_config.smtp_pass_file {
+        mailer_config.smtp_pass = Some(read_secret_file(path)?);
+    }
+    Ok(mailer_config)
+}
+
 /// Mongo DB configuration deserializer
 fn configure_mongodb<'de, D>(deserializer: D) -> Result<MongoDb, D::Error>
 where
     D: Deserializer<'de>,
 {
     let mut mongodb_config = MongoDb::deserialize(deserializer)?;
+    if let Some(path) = &mongodb_config.auth_pass_file {
+        mongodb_config.auth_pass = read_secret_file(path)?;
+    }
     if mongodb_config.mongo_host.is_empty() {
         return Err(de::Error::custom("Database host not configured"));
     }
@@ -291,3 +1390,48 @@ where
 
     Ok(mongodb_config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(variables: &[(&str, &str)]) -> config::Config {
+        let variables = variables
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        config::Config::builder()
+            .add_source(EnvironmentSource::new(Some(variables)))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn keeps_the_secrets_verbatim() {
+        let config = environment(&[
+            ("ROCKETAPI_SERVER__SECRET_KEY", "007"),
+            ("ROCKETAPI_MAILER__SMTP_PASS", "1e3"),
+            ("ROCKETAPI_MONGO_DB__AUTH_PASS", "TRUE"),
+        ]);
+        assert_eq!(config.get_string("server.secret_key").unwrap(), "007");
+        assert_eq!(config.get_string("mailer.smtp_pass").unwrap(), "1e3");
+        assert_eq!(config.get_string("mongo_db.auth_pass").unwrap(), "TRUE");
+    }
+
+    #[test]
+    fn parses_the_numbers_booleans_and_lists() {
+        let config = environment(&[
+            ("ROCKETAPI_SERVER__PORT", "9000"),
+            ("ROCKETAPI_SERVER__PROXY_PROTOCOL", "TRUE"),
+            ("ROCKETAPI_SERVER__TRUSTED_PROXIES", "10.0.0.1,10.0.0.0/8"),
+            ("ROCKETAPI_SERVER__BLOCKED_IPS", ""),
+        ]);
+        assert_eq!(config.get::<u16>("server.port").unwrap(), 9000);
+        assert!(config.get_bool("server.proxy_protocol").unwrap());
+        assert_eq!(
+            config.get::<Vec<String>>("server.trusted_proxies").unwrap(),
+            ["10.0.0.1", "10.0.0.0/8"]
+        );
+        assert!(config.get_array("server.blocked_ips").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use chrono::{DateTime, Duration as ChronoDuration, TimeZone, Utc};
+use openssl::{asn1::Asn1Time, x509::X509};
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        self,
+        time::{sleep, timeout},
+    },
+};
+use serde::Serialize;
+use std::{
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use super::{config::HealthConfig, systemd};
+use crate::{db::MongodbBackend, error::Error, secure::revocation::RevocationList, Result};
+
+/// Readiness checks of the server
+///
+/// Cloning shares the expiry of the certificate, refreshed when it is reloaded.
+#[derive(Clone)]
+pub struct Health {
+    timeout: Duration,
+    cert_expiry_warn_days: i64,
+    /// Expiry of the TLS certificate, None without TLS
+    cert_not_after: Arc<RwLock<Option<DateTime<Utc>>>>,
+}
+
+/// Result of one readiness check
+#[derive(Serialize, Debug, Default)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Expiry of the TLS certificate
+#[derive(Serialize, Debug)]
+pub struct CertificateCheck {
+    pub ok: bool,
+    pub not_after: DateTime<Utc>,
+    pub days_left: i64,
+    /// Expires within `health.cert_expiry_warn_days`
+    pub expiring_soon: bool,
+}
+
+/// Readiness of the server to serve requests
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub mongodb: Check,
+    pub user_collection: Check,
+    /// The revoked api keys were loaded
+    pub revocations: Check,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_certificate: Option<CertificateCheck>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == "ready"
+    }
+}
+
+/// Expiry of the first certificate of the pem chain
+fn cert_not_after(pem_certificate: &[u8]) -> Result<DateTime<Utc>> {
+    let cert = X509::from_pem(pem_certificate).map_err(|_| Error::SslCertificateError)?;
+    let diff = Asn1Time::from_unix(0)
+        .and_then(|epoch| epoch.diff(cert.not_after()))
+        .map_err(|_| Error::SslCertificateError)?;
+    Utc.timestamp_opt(diff.days as i64 * 86400 + diff.secs as i64, 0)
+        .single()
+        .ok_or(Error::SslCertificateError)
+}
+
+impl Health {
+    pub fn new(config: &HealthConfig, pem_certificate: Option<&[u8]>) -> Result<Self> {
+        Ok(Self {
+            timeout: Duration::from_millis(config.timeout_ms),
+            cert_expiry_warn_days: config.cert_expiry_warn_days as i64,
+            cert_not_after: Arc::new(RwLock::new(
+                pem_certificate.map(cert_not_after).transpose()?,
+            )),
+        })
+    }
+
+    /// Check the expiry of this certificate from now on
+    pub fn set_certificate(&self, pem_certificate: &[u8]) -> Result<()> {
+        let not_after = cert_not_after(pem_certificate)?;
+        *self
+            .cert_not_after
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(not_after);
+        Ok(())
+    }
+
+    /// Run the check, failed once the timeout elapsed
+    async fn check<T>(
+        &self,
+        query: impl std::future::Future<Output = Result<T>>,
+    ) -> (Check, Option<T>) {
+        let start = Instant::now();
+        let result = match timeout(self.timeout, query).await {
+            // the details stay in the logs, the route is not authenticated
+            Ok(result) => result.map_err(|e| {
+                log::warn!("readiness check failed: {}", e);
+                "the query failed".to_string()
+            }),
+            Err(_) => Err(format!("no answer within {}ms", self.timeout.as_millis())),
+        };
+        let latency_ms = Some(start.elapsed().as_secs_f64() * 1000.0);
+        match result {
+            Ok(value) => (
+                Check {
+                    ok: true,
+                    latency_ms,
+                    error: None,
+                },
+                Some(value),
+            ),
+            Err(error) => (
+                Check {
+                    ok: false,
+                    latency_ms,
+                    error: Some(error),
+                },
+                None,
+            ),
+        }
+    }
+
+    fn certificate(&self) -> Option<CertificateCheck> {
+        let cert_not_after = *self
+            .cert_not_after
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        cert_not_after.map(|not_after| {
+            let left = not_after - Utc::now();
+            CertificateCheck {
+                ok: left > ChronoDuration::zero(),
+                not_after,
+                days_left: left.num_days(),
+                expiring_soon: left.num_days() < self.cert_expiry_warn_days,
+            }
+        })
+    }
+
+    /// Check MongoDB answers, the user collection exists, the revoked keys are loaded
+    /// & the TLS certificate is valid
+    pub async fn readiness(
+        &self,
+        backend: &MongodbBackend,
+        revocations: &RevocationList,
+    ) -> Readiness {
+        let (mongodb, _) = self.check(backend.ping()).await;
+        let user_collection = if mongodb.ok {
+            match self.check(backend.user_collection_exists()).await {
+                (mut check, Some(false)) => {
+                    check.ok = false;
+                    check.error = Some("user_db/user_collection does not exist".into());
+                    check
+                }
+                (check, _) => check,
+            }
+        } else {
+            Check {
+                error: Some("mongodb is not reachable".into()),
+                ..Check::default()
+            }
+        };
+        let revocations = Check {
+            ok: revocations.is_loaded(),
+            error: (!revocations.is_loaded())
+                .then(|| "the revoked api keys are not loaded yet".to_string()),
+            ..Check::default()
+        };
+        let tls_certificate = self.certificate();
+        if let Some(cert) = tls_certificate.as_ref().filter(|cert| cert.expiring_soon) {
+            log::warn!(
+                "the TLS certificate expires in {} days, on {}",
+                cert.days_left,
+                cert.not_after
+            );
+        }
+
+        let ready = mongodb.ok
+            && user_collection.ok
+            && revocations.ok
+            && tls_certificate.as_ref().is_none_or(|cert| cert.ok);
+        Readiness {
+            status: if ready { "ready" } else { "not_ready" },
+            mongodb,
+            user_collection,
+            revocations,
+            tls_certificate,
+        }
+    }
+
+    /// Fairing notifying systemd once the server is ready, for the `Type=notify` unit
+    pub fn fairing(self, backend: MongodbBackend, revocations: RevocationList) -> AdHoc {
+        AdHoc::on_liftoff("Readiness notification", move |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    while !self.readiness(&backend, &revocations).await.is_ready() {
+                        sleep(Duration::from_secs(1)).await;
+                    }
+                    systemd::notify("READY=1");
+                });
+            })
+        })
+    }
+}
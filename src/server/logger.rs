@@ -0,0 +1,63 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::secure::api_key::redact_query_param;
+
+/// Logger printing rocket's records with the api key query parameter redacted.
+///
+/// Installed before rocket ignites, rocket then keeps it instead of its own logger.
+pub struct RedactingLogger {
+    query_param: String,
+}
+
+impl RedactingLogger {
+    /// Install the logger for the process, with the given maximum level
+    pub fn install(query_param: &str, level: LevelFilter) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(Self {
+            query_param: query_param.to_string(),
+        }))
+        .map(|_| log::set_max_level(level))
+    }
+}
+
+/// Records of rocket's launch are logged as warnings, to be shown at every level
+fn is_launch_record(metadata: &Metadata<'_>) -> bool {
+    metadata.target().contains("rocket::launch")
+}
+
+impl Log for RedactingLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level() || is_launch_record(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // hyper & rustls are only worth reading when debugging
+        let module = record.module_path().unwrap_or_default();
+        if (module.starts_with("hyper") || module.starts_with("rustls"))
+            && log::max_level() < LevelFilter::Debug
+        {
+            return;
+        }
+
+        let message = redact_query_param(&record.args().to_string(), &self.query_param);
+        let indent = if record.target().ends_with('_') {
+            "   >> "
+        } else {
+            ""
+        };
+        match record.level() {
+            Level::Error if !is_launch_record(record.metadata()) => {
+                println!("{}Error: {}", indent, message)
+            }
+            Level::Warn if !is_launch_record(record.metadata()) => {
+                println!("{}Warning: {}", indent, message)
+            }
+            _ => println!("{}{}", indent, message),
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,169 @@
+use ipnet::IpNet;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Data, Request, Response,
+};
+use std::{net::IpAddr, time::Instant};
+
+use crate::{error::Error, Result};
+
+/// Route label of the requests no route matched, to keep the labels bounded
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Prometheus metrics of the server.
+///
+/// Cloning shares the same metrics, e.g. with the listener dedicated to `/metrics`.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_latency: HistogramVec,
+    auth_failures: IntCounterVec,
+    throttle_rejections: IntCounterVec,
+    backend_latency: HistogramVec,
+    throttles: IntGauge,
+}
+
+/// Ip networks allowed to read the metrics
+pub struct MetricsAcl(pub Vec<IpNet>);
+
+impl MetricsAcl {
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Time the request was received
+struct RequestStart(Option<Instant>);
+
+fn prometheus_error(e: prometheus::Error) -> Error {
+    Error::ConfigurationError(format!("metrics: {}", e))
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("rocketapi_http_requests_total", "Requests handled"),
+            &["route", "method", "status"],
+        )
+        .map_err(prometheus_error)?;
+        let request_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "rocketapi_http_request_duration_seconds",
+                "Time to handle a request",
+            ),
+            &["route", "method", "status"],
+        )
+        .map_err(prometheus_error)?;
+        let auth_failures = IntCounterVec::new(
+            Opts::new("rocketapi_auth_failures_total", "Rejected credentials"),
+            &["reason"],
+        )
+        .map_err(prometheus_error)?;
+        let throttle_rejections = IntCounterVec::new(
+            Opts::new(
+                "rocketapi_throttle_rejections_total",
+                "Requests rejected by the throttle of the user",
+            ),
+            &["email"],
+        )
+        .map_err(prometheus_error)?;
+        let backend_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "rocketapi_backend_query_duration_seconds",
+                "Time of a query to the backend",
+            ),
+            &["method"],
+        )
+        .map_err(prometheus_error)?;
+        let throttles = IntGauge::new(
+            "rocketapi_endpoint_throttles",
+            "Throttles held by the endpoint handler",
+        )
+        .map_err(prometheus_error)?;
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(requests.clone()))
+            .and_then(|_| registry.register(Box::new(request_latency.clone())))
+            .and_then(|_| registry.register(Box::new(auth_failures.clone())))
+            .and_then(|_| registry.register(Box::new(throttle_rejections.clone())))
+            .and_then(|_| registry.register(Box::new(backend_latency.clone())))
+            .and_then(|_| registry.register(Box::new(throttles.clone())))
+            .map_err(prometheus_error)?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_latency,
+            auth_failures,
+            throttle_rejections,
+            backend_latency,
+            throttles,
+        })
+    }
+
+    /// Latency histogram of the backend queries, labelled by method
+    pub fn backend_latency(&self) -> HistogramVec {
+        self.backend_latency.clone()
+    }
+
+    /// Count an authentication or authorization failure
+    pub fn record_auth_failure(&self, error: &Error) {
+        let reason = match error {
+            Error::UnauthenticatedUser => "unauthenticated",
+            Error::ForbiddenAccess => "forbidden",
+            Error::TooManyRequests => "throttled",
+            _ => return,
+        };
+        self.auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn record_throttle_rejection(&self, email: &str) {
+        self.throttle_rejections.with_label_values(&[email]).inc();
+    }
+
+    /// Render the metrics in the Prometheus text format
+    pub fn render(&self, throttle_count: usize) -> Result<String> {
+        self.throttles.set(throttle_count as i64);
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|_| Error::InternalError)?;
+        String::from_utf8(buffer).map_err(|_| Error::InternalError)
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let route = request
+            .route()
+            .map(|route| route.uri.as_str())
+            .unwrap_or(UNMATCHED_ROUTE);
+        let status = response.status().code.to_string();
+        let labels = [route, request.method().as_str(), status.as_str()];
+
+        self.requests.with_label_values(&labels).inc();
+        if let Some(start) = request.local_cache(|| RequestStart(None)).0 {
+            self.request_latency
+                .with_label_values(&labels)
+                .observe(start.elapsed().as_secs_f64());
+        }
+    }
+}
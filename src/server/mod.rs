@@ -1,53 +1,182 @@
 use clap::Parser;
-use rocket::{data::Limits, Build, Config, Rocket};
-use std::path::Path;
+use rocket::{config::LogLevel, data::Limits, fairing::AdHoc, Build, Config, Rocket};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::exit,
+    time::Duration,
+};
 
 use crate::{
-    controllers, db::MongodbBackend, error::Error, models::endpoint::EndpointHandler, Result,
+    controllers,
+    db::{cache::UserCache, usage::UsageTracker, MongodbBackend},
+    error::Error,
+    mailer::{FileMailer, LogMailer, Mailer, MailerKind, SmtpMailer},
+    models::endpoint::EndpointHandler,
+    secure::{
+        api_key::ApiKeySources,
+        attempts::FailedAttempts,
+        ip::{BlocklistFairing, ClientIpResolver, IpBlocklist},
+        jwt::TokenIssuer,
+        mtls::MtlsMode,
+        revocation::RevocationList,
+        session::{LoginLimiter, SessionManager},
+        signature::{derive_key, SignatureVerifier},
+        signup::{SignupLimiter, SignupTokens},
+        totp::MfaPolicy,
+    },
+    Result,
 };
 
+/// Structured access log
+pub mod access_log;
+use self::access_log::AccessLog;
+
 /// Catchers like 500, 501, 404, etc
 mod catchers;
 
 /// Server & App Configurations
 pub mod config;
-use self::config::Settings;
+use self::config::{ConfigSources, MailerConfig, Settings};
+
+/// Liveness & readiness checks
+pub mod health;
+use self::health::Health;
+
+/// Logger redacting api keys
+mod logger;
+use self::logger::RedactingLogger;
+
+/// Prometheus metrics
+pub mod metrics;
+use self::metrics::{Metrics, MetricsAcl};
+
+/// Settings reload
+pub mod reload;
+use self::reload::Reloader;
+
+/// Request ids & the scope of the route handlers
+pub mod request_id;
+use self::request_id::{scoped, scoped_catchers, RequestIdFairing};
+
+/// Graceful shutdown
+pub mod shutdown;
+use self::shutdown::GracefulShutdown;
+
+/// Notifications to systemd
+mod systemd;
+
+/// OpenTelemetry tracing
+pub mod telemetry;
+use self::telemetry::TracingFairing;
+
+/// PROXY protocol listener
+pub mod proxy_protocol;
+use self::proxy_protocol::ProxyProtocolListener;
+
+/// TLS listener with a reloadable certificate
+pub mod tls;
+use self::tls::{CertificateResolver, TlsListener};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct CliOpts {
     /// loads the server configurations
     #[clap(short = 'c', long)]
-    config: String,
+    config: Option<String>,
+    /// loads the drop-in configurations of the directory, in file name order
+    #[clap(short = 'd', long)]
+    config_dir: Option<String>,
+    /// ip address to listen on
+    #[clap(long)]
+    host: Option<IpAddr>,
+    /// port to listen on
+    #[clap(short = 'p', long)]
+    port: Option<u16>,
+    /// overrides a setting, e.g. `--set auth.token.enabled=true`
+    #[clap(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+    /// prints the effective configuration, secrets redacted, and exits
+    #[clap(long)]
+    print_config: bool,
+    /// development mode, allows the built-in secret key
+    #[clap(long)]
+    dev: bool,
 }
 
-/// Parse the settings from the command line arguments
-fn parse_settings_from_cli() -> Result<Settings> {
+/// Parse the settings from the command line arguments,
+/// on top of the config files & environment variables
+fn parse_settings_from_cli() -> Result<(Settings, ConfigSources)> {
     // parse the cli options
     let cli_opts = CliOpts::parse();
-    let cfg_file = &cli_opts.config;
 
-    if cfg_file.is_empty() {
-        // No config file, so start
-        // with default settings
-        Ok(Settings::default())
-    } else {
-        // Config file passed in cli, check
-        // to see if config file exists
-        if Path::new(cfg_file).exists() {
-            // load settings from the config file or return error
-            // if error in loading the given config file
-            Settings::from_file(&cfg_file)
-        } else {
+    // Config file passed in cli, check
+    // to see if config file exists
+    let file = match cli_opts.config {
+        Some(cfg_file) if !Path::new(&cfg_file).exists() => {
             // config file does not exist, quit app
-            Err(Error::ConfigFileNotFound)
+            return Err(Error::ConfigFileNotFound);
         }
+        cfg_file => cfg_file.map(PathBuf::from),
+    };
+
+    // the command line flags override every other source
+    let mut overrides = vec![];
+    if let Some(host) = cli_opts.host {
+        overrides.push(("server.host".to_string(), host.to_string()));
+    }
+    if let Some(port) = cli_opts.port {
+        overrides.push(("server.port".to_string(), port.to_string()));
+    }
+    if cli_opts.dev {
+        overrides.push(("server.dev_mode".to_string(), true.to_string()));
+    }
+    for setting in cli_opts.overrides {
+        match setting.split_once('=') {
+            Some((key, value)) => overrides.push((key.to_string(), value.to_string())),
+            None => {
+                return Err(Error::ConfigurationError(format!(
+                    "--set {}: expected KEY=VALUE",
+                    setting
+                )))
+            }
+        }
+    }
+
+    let sources = ConfigSources {
+        file,
+        drop_in_dir: cli_opts.config_dir.map(PathBuf::from),
+        overrides,
+    };
+    let settings = Settings::from_sources(&sources)?;
+
+    if cli_opts.print_config {
+        println!("{}", settings.to_redacted_json()?);
+        exit(0);
     }
+    Ok((settings, sources))
+}
+
+/// Build the mailer delivering the emails of the server
+fn build_mailer(config: MailerConfig) -> Result<Box<dyn Mailer>> {
+    Ok(match config.kind {
+        MailerKind::Log => Box::new(LogMailer),
+        MailerKind::File => Box::new(FileMailer::new(config.file)),
+        MailerKind::Smtp => Box::new(SmtpMailer::new(
+            &config.smtp_host,
+            config.smtp_port,
+            config.smtp_tls,
+            config.smtp_user.zip(config.smtp_pass),
+            &config.from,
+        )?),
+    })
 }
 
 /// Initialise the Rocket Server app
 pub async fn init_server() -> Result<Rocket<Build>> {
-    let settings = parse_settings_from_cli()?;
+    let (settings, config_sources) = parse_settings_from_cli()?;
+    // kept to tell the changes of the next reloads
+    let loaded_settings = settings.clone();
 
     let db_settings = settings.mongo_db;
     if db_settings.db.is_empty() {
@@ -56,49 +185,179 @@ pub async fn init_server() -> Result<Rocket<Build>> {
 
     let server_settings = settings.server;
 
+    // The built-in secret key ships in the binary: whoever has it can forge
+    // the access tokens & decrypt the session cookies
+    if server_settings.uses_default_secret_key() && !server_settings.dev_mode {
+        return Err(Error::ConfigurationError(
+            "server.secret_key is the built-in or sample one: set server.secret_key or \
+             server.secret_key_file, or use --dev for development"
+                .into(),
+        ));
+    }
+
+    // The PROXY header is only accepted from the trusted proxies, every
+    // connection would be dropped without any
+    if server_settings.proxy_protocol && server_settings.trusted_proxies.is_empty() {
+        return Err(Error::ConfigurationError(
+            "server.proxy_protocol needs the load balancer in server.trusted_proxies".into(),
+        ));
+    }
+
     let limits = Limits::new()
         .limit("forms", server_settings.forms_limit.into())
         .limit("json", server_settings.json_limit.into());
 
+    // TLS is served by our own listener, whose certificate is swapped when the
+    // settings are reloaded; the expiry of the certificate is part of the readiness
+    let ssl_cfg = settings.ssl.filter(|ssl_cfg| ssl_cfg.enabled);
+    let ssl_enabled = ssl_cfg.is_some();
+    let health = Health::new(
+        &settings.health,
+        ssl_cfg
+            .as_ref()
+            .and_then(|ssl_cfg| ssl_cfg.pem_certificate.as_deref()),
+    )?;
+    let certificate = match &ssl_cfg {
+        Some(ssl_cfg) => match (&ssl_cfg.pem_certificate, &ssl_cfg.pem_private_key) {
+            (Some(cert), Some(key)) => Some(CertificateResolver::new(cert, key)?),
+            // ssl certificate info not available
+            _ => return Err(Error::SslCertificateError),
+        },
+        None => None,
+    };
+    // client certificates are verified against the client CA bundle
+    let mtls_enabled = ssl_cfg
+        .as_ref()
+        .is_some_and(|ssl_cfg| ssl_cfg.pem_client_ca.is_some());
+
+    // With TLS or the PROXY protocol, the public address is served by our listener
+    // which relays the connections to rocket on the loopback interface
+    let public_addr = SocketAddr::new(server_settings.host, server_settings.port as u16);
+    let loopback = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+    let (tls_listener, proxy_listener, rocket_addr) = match (&ssl_cfg, &certificate) {
+        (Some(ssl_cfg), Some(certificate)) => (
+            Some(
+                TlsListener::bind(
+                    public_addr,
+                    ssl_cfg,
+                    certificate.clone(),
+                    server_settings.proxy_protocol,
+                )
+                .await?,
+            ),
+            None,
+            loopback,
+        ),
+        _ if server_settings.proxy_protocol => (
+            None,
+            Some(ProxyProtocolListener::bind(public_addr).await?),
+            loopback,
+        ),
+        _ => (None, None, public_addr),
+    };
+
     let rocket_cfg = Config::figment()
-        .merge(("address", server_settings.host.to_string()))
-        .merge(("port", server_settings.port as u16))
+        .merge(("address", rocket_addr.ip().to_string()))
+        .merge(("port", rocket_addr.port()))
         .merge(("limits", limits))
         .merge(("secret_key", (server_settings.secret_key.as_str())))
-        .merge(("keep_alive", server_settings.keep_alive as u32));
-
-    // Configure SSL status for the api server
-    let rocket_cfg = if let Some(ssl_cfg) = settings.ssl {
-        if ssl_cfg.enabled {
-            // ssl is enabled
-            if ssl_cfg.pem_certificate.is_some() && ssl_cfg.pem_private_key.is_some() {
-                // merge the certs & key into rocket config
-                rocket_cfg
-                    .merge(("tls.certs", ssl_cfg.pem_certificate))
-                    .merge(("tls.key", ssl_cfg.pem_private_key))
-            } else {
-                // ssl certificate info not available
-                return Err(Error::SslCertificateError);
-            }
-        } else {
-            // ssl not enabled
-            rocket_cfg
+        .merge(("keep_alive", server_settings.keep_alive as u32))
+        // the termination signals are handled by the graceful shutdown
+        .merge(("shutdown.ctrlc", false))
+        .merge(("shutdown.signals", Vec::<String>::new()))
+        .merge(("shutdown.grace", settings.shutdown.grace_secs));
+    let rocket_cfg = match server_settings.log_level {
+        Some(log_level) => rocket_cfg.merge(("log_level", log_level)),
+        None => rocket_cfg,
+    };
+
+    // Api keys sent in the query string must not end up in the logs
+    let api_key_settings = settings.auth.api_key;
+    let api_key_sources = ApiKeySources::new(
+        api_key_settings.sources,
+        api_key_settings.cookie_name,
+        api_key_settings.query_param,
+    );
+    if let Some(query_param) = api_key_sources.query_param() {
+        let log_level = rocket_cfg
+            .extract_inner::<LogLevel>("log_level")
+            .unwrap_or(LogLevel::Normal);
+        if RedactingLogger::install(query_param, log_level.into()).is_err() {
+            return Err(Error::ConfigurationError(
+                "could not install the logger redacting api keys".into(),
+            ));
         }
+    }
+
+    // Client certificate authentication needs the TLS listener to verify them
+    let mtls_mode = settings.auth.mtls.mode;
+    if mtls_mode != MtlsMode::Disabled && !mtls_enabled {
+        return Err(Error::ConfigurationError(
+            "auth.mtls requires ssl with a client_ca_file".into(),
+        ));
+    }
+
+    let user_cache = UserCache::new(
+        Duration::from_secs(settings.cache.ttl_secs),
+        settings.cache.capacity,
+    );
+    let backend = MongodbBackend::connect(
+        db_settings.db_uri.unwrap(),
+        db_settings.db.clone(),
+        user_cache,
+    )
+    .await?;
+
+    // Prometheus metrics, including the latency of the backend queries
+    let metrics_settings = settings.metrics;
+    let metrics = if metrics_settings.enabled {
+        Some(Metrics::new()?)
     } else {
-        // no ssl configuration
-        rocket_cfg
+        None
+    };
+    let backend = match &metrics {
+        Some(metrics) => backend.with_query_latency(metrics.backend_latency()),
+        None => backend,
     };
-
-    let backend =
-        MongodbBackend::connect(db_settings.db_uri.unwrap(), db_settings.db.clone()).await?;
 
     // Configure the Rocket server with configured settings
     let app = rocket::custom(rocket_cfg);
 
+    // Warn once the logger is set up
+    let app = if server_settings.uses_default_secret_key() {
+        app.attach(AdHoc::on_liftoff("Development mode", |_| {
+            Box::pin(async {
+                log::warn!("development mode, the built-in secret key is in use");
+            })
+        }))
+    } else {
+        app
+    };
+
+    // Request id of the caller or else generated, echoed in the response
+    let app = app.attach(RequestIdFairing);
+
+    // One JSON line per request, attached early to time the whole request
+    let app = if settings.access_log.enabled {
+        let access_log = AccessLog::new(&settings.access_log)?;
+        // managed too, to write the queued lines at shutdown
+        app.attach(access_log.clone()).manage(access_log)
+    } else {
+        app
+    };
+
+    // Request spans, children of the caller's traceparent; the guards, controllers
+    // & backend calls of the routes run within them
+    let app = match telemetry::init(&settings.tracing)? {
+        // the provider is kept to flush the pending spans at shutdown
+        Some(provider) => app.attach(TracingFairing).manage(provider),
+        None => app,
+    };
+
     // Catchers
     let app = app.register(
         "/",
-        rocket::catchers![
+        scoped_catchers(rocket::catchers![
             catchers::bad_request,
             catchers::forbidden,
             catchers::not_authorized,
@@ -106,28 +365,307 @@ pub async fn init_server() -> Result<Rocket<Build>> {
             catchers::unprocessed_entity,
             catchers::internal_server_error,
             catchers::too_many_requests,
-        ],
+        ]),
     );
 
     // Add the index route
-    let app = app.mount("/", routes![controllers::index::home,]);
+    let app = app.mount("/", scoped(routes![controllers::index::home,]));
+    // Add the liveness & readiness routes, without authentication
+    let app = app.mount(
+        "/health",
+        scoped(routes![
+            controllers::health::live,
+            controllers::health::ready,
+        ]),
+    );
     // Add the hello world route
-    let app = app.mount("/api", routes![controllers::hellow::ping,]);
+    let app = app.mount("/api", scoped(routes![controllers::hellow::ping,]));
     // Add the Users routes
     let app = app.mount(
         "/users",
-        routes![
+        scoped(routes![
             controllers::users::get_current_user,
             controllers::users::create_user,
             controllers::users::get_all_users,
             controllers::users::update_user,
             controllers::users::delete_user,
-        ],
+            controllers::users::rotate_signing_secret,
+            controllers::credentials::set_password,
+            controllers::credentials::enrol_totp,
+            controllers::credentials::confirm_totp,
+            controllers::credentials::disable_totp,
+        ]),
+    );
+    // Add the Admin routes
+    let app = app.mount(
+        "/admin",
+        scoped(routes![
+            controllers::admin::cache_stats,
+            controllers::admin::revoke_key,
+            controllers::admin::get_all_revocations,
+            controllers::admin::key_usage,
+            controllers::admin::stale_keys,
+            controllers::admin::get_audit_log,
+        ]),
+    );
+
+    // Units each request consumes from the user's throttle, routes not listed
+    // cost 1 unit; & the throttle of the ACL rules that set none
+    let rate_limit_settings = settings.rate_limit;
+    let endpoint_handler = rate_limit_settings
+        .costs
+        .into_iter()
+        .fold(EndpointHandler::default(), |handler, cost| {
+            handler.with_cost(&cost.name, cost.method, cost.cost)
+        })
+        .with_default_throttle(rate_limit_settings.default_throttle);
+    let blocklist = IpBlocklist::new(server_settings.blocked_ips);
+
+    let client_ip_resolver = ClientIpResolver::new(server_settings.trusted_proxies);
+    let (app, client_ip_resolver) = match (tls_listener, proxy_listener) {
+        (Some(listener), _) => {
+            let peers = listener.peers();
+            let certificates = listener.certificates();
+            (
+                app.attach(listener.fairing(client_ip_resolver.clone()))
+                    .manage(certificates),
+                client_ip_resolver.with_proxied_peers(peers),
+            )
+        }
+        (None, Some(listener)) => {
+            let peers = listener.peers();
+            (
+                app.attach(listener.fairing(client_ip_resolver.clone())),
+                client_ip_resolver.with_proxied_peers(peers),
+            )
+        }
+        (None, None) => (app, client_ip_resolver),
+    };
+
+    // Metrics of every request; served on the api address, or on their own one
+    let app = match (metrics, metrics_settings.bind) {
+        (Some(metrics), Some(bind)) => {
+            let metrics_cfg = Config::figment()
+                .merge(("address", bind.ip().to_string()))
+                .merge(("port", bind.port()))
+                .merge(("log_level", LogLevel::Critical))
+                // served until the api server is drained & the process exits
+                .merge(("shutdown.ctrlc", false))
+                .merge(("shutdown.signals", Vec::<String>::new()));
+            let metrics_app = rocket::custom(metrics_cfg)
+                .mount("/", scoped(routes![controllers::metrics::metrics]))
+                .manage(metrics.clone())
+                .manage(endpoint_handler.clone())
+                .manage(MetricsAcl(metrics_settings.allow_ips));
+            rocket::tokio::spawn(async move {
+                if let Err(e) = metrics_app.launch().await {
+                    log::error!("could not serve the metrics on {}: {}", bind, e);
+                }
+            });
+            app.attach(metrics.clone()).manage(metrics)
+        }
+        (Some(metrics), None) => app
+            .mount("/", scoped(routes![controllers::metrics::metrics]))
+            .attach(metrics.clone())
+            .manage(metrics)
+            .manage(MetricsAcl(metrics_settings.allow_ips)),
+        (None, _) => app,
+    };
+
+    // Accept HMAC signed requests
+    let app = if settings.auth.hmac.enabled {
+        // the used nonces expire with the skew window
+        if let Err(e) = backend.create_nonce_index().await {
+            log::warn!("could not create the signature nonce index: {}", e);
+        }
+        app.manage(SignatureVerifier::new(settings.auth.hmac.max_skew_secs))
+    } else {
+        app
+    };
+
+    // Access tokens, signed with a key derived from the server secret key
+    let token_settings = settings.auth.token;
+    let oauth_settings = settings.auth.oauth;
+    let app = if token_settings.enabled || oauth_settings.enabled {
+        let issuer = TokenIssuer::new(
+            derive_key(&server_settings.secret_key, "jwt")?.as_bytes(),
+            token_settings.ttl_secs,
+        );
+        // tokens revoked before their expiry, by any instance
+        if let Err(e) = backend.create_revoked_token_index().await {
+            log::warn!("could not create the revoked token index: {}", e);
+        }
+        if let Err(e) = issuer.refresh_revoked(&backend).await {
+            log::warn!("could not load the revoked tokens: {}", e);
+        }
+        app.attach(issuer.fairing(
+            backend.clone(),
+            Duration::from_secs(settings.auth.revocation.poll_secs.max(1)),
+        ))
+        .manage(issuer)
+    } else {
+        app
+    };
+    // Exchange api keys for access tokens
+    let app = if token_settings.enabled {
+        app.mount("/auth", scoped(routes![controllers::auth::issue_token,]))
+    } else {
+        app
+    };
+    // OAuth2 client credentials grant
+    let app = if oauth_settings.enabled {
+        app.mount(
+            "/oauth",
+            scoped(routes![
+                controllers::oauth::token,
+                controllers::oauth::introspect,
+                controllers::oauth::revoke,
+                controllers::oauth::create_client,
+                controllers::oauth::get_all_clients,
+                controllers::oauth::delete_client,
+            ]),
+        )
+    } else {
+        app
+    };
+
+    // Admin sessions in a private cookie, encrypted with the server secret key
+    let session_settings = settings.auth.session;
+    let app = if session_settings.enabled {
+        // the ended sessions expire with their cookie
+        if let Err(e) = backend.create_ended_session_index().await {
+            log::warn!("could not create the ended session index: {}", e);
+        }
+        app.mount(
+            "/session",
+            scoped(routes![
+                controllers::session::login,
+                controllers::session::current_session,
+                controllers::session::step_up,
+                controllers::session::logout,
+            ]),
+        )
+        .manage(SessionManager::new(
+            session_settings.cookie_name,
+            session_settings.ttl_secs,
+            ssl_enabled,
+        ))
+        .manage(LoginLimiter::new(session_settings.login_throttle))
+    } else {
+        app
+    };
+
+    // Self-service signup, verified by email
+    let signup_settings = settings.signup;
+    let app = if signup_settings.enabled {
+        app.mount(
+            "/signup",
+            scoped(routes![
+                controllers::signup::signup,
+                controllers::signup::verify,
+            ]),
+        )
+        .manage(SignupLimiter::new(signup_settings.throttle.clone()))
+        // tokens derived with the server secret key
+        .manage(SignupTokens::new(derive_key(
+            &server_settings.secret_key,
+            "signup",
+        )?))
+        .manage(signup_settings)
+    } else {
+        app
+    };
+    let mailer = build_mailer(settings.mailer)?;
+
+    // Revoked api keys, loaded before serving the first request; the server
+    // is not ready until they are, if the backend can't be reached yet
+    let revocations = RevocationList::default();
+    revocations.refresh(&backend).await;
+    let app = app.attach(revocations.clone().fairing(
+        backend.clone(),
+        Duration::from_secs(settings.auth.revocation.poll_secs.max(1)),
+    ));
+
+    // Tell systemd the server is ready once the readiness checks pass
+    let app = app.attach(health.clone().fairing(backend.clone(), revocations.clone()));
+
+    // Api key usage, buffered & written in batches
+    let usage_settings = settings.usage;
+    let usage_tracker = UsageTracker::default();
+    let app = app.attach(usage_tracker.clone().fairing(
+        backend.clone(),
+        Duration::from_secs(usage_settings.flush_secs.max(1)),
+        usage_settings.window_days as i64,
+    ));
+
+    // Drain the requests on SIGTERM/SIGINT before stopping
+    let graceful_shutdown = GracefulShutdown::new(&settings.shutdown);
+    let app = app.attach(graceful_shutdown.clone().fairing());
+
+    // Reload the settings on SIGHUP & when the config files change
+    let reload_settings = settings.reload;
+    let reloader = Reloader::new(
+        config_sources,
+        loaded_settings,
+        client_ip_resolver.clone(),
+        blocklist.clone(),
+        endpoint_handler.clone(),
     );
+    let reloader = match certificate {
+        Some(certificate) => reloader.with_certificate(certificate, health.clone()),
+        None => reloader,
+    };
+    let app = app
+        .mount(
+            "/admin",
+            scoped(routes![
+                controllers::admin::last_reload,
+                controllers::admin::reload
+            ]),
+        )
+        .attach(reloader.clone().fairing(
+            reload_settings.watch,
+            Duration::from_secs(reload_settings.poll_secs.max(1)),
+        ));
+
+    // Second factor policy
+    let mfa_settings = settings.auth.mfa;
+    let mfa_policy = MfaPolicy {
+        issuer: mfa_settings.issuer,
+        require_for_user_admin: mfa_settings.require_for_user_admin,
+        max_age_secs: mfa_settings.max_age_secs as i64,
+    };
+    // Failed password & second factor checks
+    let failed_attempts = FailedAttempts::new(settings.auth.credentials.failed_attempts);
 
     let app = app
         // Add Endpoint Handler to the state
-        .manage(EndpointHandler::default())
+        .manage(endpoint_handler)
+        // Add the client ip resolver to the state
+        .manage(client_ip_resolver)
+        // Refuse the blocked clients, added to the state
+        .attach(BlocklistFairing)
+        .manage(blocklist)
+        // Add the settings reload to the state
+        .manage(reloader)
+        // Add the client certificate authentication mode to the state
+        .manage(mtls_mode)
+        // Add the api key credential sources to the state
+        .manage(api_key_sources)
+        // Add the second factor policy to the state
+        .manage(mfa_policy)
+        // Add the failed credential checks throttle to the state
+        .manage(failed_attempts)
+        // Add the mailer to the state
+        .manage(mailer)
+        // Add the revoked api keys to the state
+        .manage(revocations)
+        // Add the key usage tracking to the state
+        .manage(usage_tracker)
+        .manage(usage_settings)
+        // Add the readiness checks to the state
+        .manage(health)
+        .manage(graceful_shutdown)
         // Add Db settings to the state
         .manage(db_settings.db)
         // Add Mongo connection to the state
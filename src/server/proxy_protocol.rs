@@ -0,0 +1,394 @@
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        self,
+        io::{self, AsyncRead, AsyncReadExt},
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    },
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use crate::secure::ip::ClientIpResolver;
+
+/// Time given to the load balancer to send the PROXY header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest PROXY v1 header, CRLF included
+const V1_MAX_LEN: usize = 107;
+/// PROXY v2 signature
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// Real peer addresses of the connections relayed to the Rocket listener,
+/// keyed by the local address of the relaying connection.
+#[derive(Debug, Clone, Default)]
+pub struct ProxiedPeers(Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>);
+
+impl ProxiedPeers {
+    /// Get the real peer of a relayed connection
+    pub fn get(&self, relay: &SocketAddr) -> Option<SocketAddr> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(relay)
+            .copied()
+    }
+
+    pub(super) fn insert(&self, relay: SocketAddr, peer: SocketAddr) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(relay, peer);
+    }
+
+    pub(super) fn remove(&self, relay: &SocketAddr) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(relay);
+    }
+}
+
+/// Public listener accepting PROXY protocol (v1 & v2) connections.
+///
+/// The PROXY header is stripped and the connection relayed to the
+/// Rocket server listening on the loopback interface; connections
+/// from peers other than the trusted proxies are dropped.
+pub struct ProxyProtocolListener {
+    listener: TcpListener,
+    peers: ProxiedPeers,
+}
+
+impl ProxyProtocolListener {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        //! Bind the public address of the server
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! ProxyProtocolListener::bind("0.0.0.0:8080".parse().unwrap()).await?;
+        //! ```
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            peers: ProxiedPeers::default(),
+        })
+    }
+
+    /// Real peers of the relayed connections
+    pub fn peers(&self) -> ProxiedPeers {
+        self.peers.clone()
+    }
+
+    /// Fairing starting the relay once Rocket is listening, the PROXY header
+    /// being only accepted from the proxies trusted by the resolver
+    pub fn fairing(self, trusted: ClientIpResolver) -> AdHoc {
+        AdHoc::on_liftoff("PROXY protocol listener", move |rocket| {
+            Box::pin(async move {
+                let upstream = SocketAddr::new(rocket.config().address, rocket.config().port);
+                tokio::spawn(self.serve(upstream, trusted));
+            })
+        })
+    }
+
+    async fn serve(self, upstream: SocketAddr, trusted: ClientIpResolver) {
+        loop {
+            let (stream, remote) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("PROXY protocol: accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            let peers = self.peers.clone();
+            let trusted = trusted.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay(stream, remote, upstream, peers, &trusted).await {
+                    log::warn!("PROXY protocol: connection from {} dropped: {}", remote, e);
+                }
+            });
+        }
+    }
+}
+
+async fn relay(
+    mut stream: TcpStream,
+    remote: SocketAddr,
+    upstream: SocketAddr,
+    peers: ProxiedPeers,
+    trusted: &ClientIpResolver,
+) -> io::Result<()> {
+    let peer = read_peer(&mut stream, remote, trusted).await?;
+
+    let mut upstream = TcpStream::connect(upstream).await?;
+    upstream.set_nodelay(true)?;
+    let relay = upstream.local_addr()?;
+
+    // registered before any byte is relayed, so the request can always see it
+    peers.insert(relay, peer);
+    // errors past this point are the usual resets from either side
+    let _ = io::copy_bidirectional(&mut stream, &mut upstream).await;
+    peers.remove(&relay);
+
+    Ok(())
+}
+
+/// Read the PROXY header of the connection, returns the real peer address.
+///
+/// The header is only read from a trusted proxy, anyone else could spoof its address.
+pub(super) async fn read_peer<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    remote: SocketAddr,
+    trusted: &ClientIpResolver,
+) -> io::Result<SocketAddr> {
+    if !trusted.is_trusted(&remote.ip()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "not a trusted proxy",
+        ));
+    }
+    Ok(timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY header received"))??
+        // LOCAL/UNKNOWN connections (eg: health checks) are the load balancer's own
+        .unwrap_or(remote))
+}
+
+/// Read the PROXY header, returns the source address it carries (if any)
+async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // shortest v1 header ("PROXY UNKNOWN\r\n") is longer than the v2 signature
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if prefix.starts_with(b"PROXY ") {
+        read_v1(stream, prefix.to_vec()).await
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    mut line: Vec<u8>,
+) -> io::Result<Option<SocketAddr>> {
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ascii"))?;
+
+    match line.split(' ').collect::<Vec<_>>()[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("invalid PROXY v1 source address"))?;
+            let port = sport
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if version_command & 0x0F == 0 {
+        // LOCAL command
+        return Ok(None);
+    }
+
+    // address family in the high nibble: 1 = INET, 2 = INET6; TLVs past the addresses are ignored
+    match (family >> 4, payload.len()) {
+        (1, n) if n >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        (2, n) if n >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // UNSPEC or unix sockets
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the header at the start of the bytes, returning what is left to relay
+    async fn parse(bytes: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = bytes;
+        let peer = read_header(&mut stream).await;
+        (peer, stream.to_vec())
+    }
+
+    fn v2(version_command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[rocket::async_test]
+    async fn only_trusts_the_proxies() {
+        let trusted = ClientIpResolver::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let header = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+
+        let mut stream = &header[..];
+        let peer = read_peer(&mut stream, "10.0.0.2:4000".parse().unwrap(), &trusted).await;
+        assert_eq!(peer.unwrap(), "192.168.0.1:56324".parse().unwrap());
+
+        let mut stream = &header[..];
+        let peer = read_peer(&mut stream, "203.0.113.7:4000".parse().unwrap(), &trusted).await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        // nothing read from the untrusted peer
+        assert_eq!(stream, &header[..]);
+    }
+
+    #[rocket::async_test]
+    async fn v1_tcp4() {
+        let (peer, rest) =
+            parse(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n").await;
+        assert_eq!(peer.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[rocket::async_test]
+    async fn v1_tcp6() {
+        let (peer, _) = parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+        assert_eq!(peer.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[rocket::async_test]
+    async fn v1_unknown() {
+        let (peer, rest) = parse(b"PROXY UNKNOWN\r\nGET /").await;
+        assert_eq!(peer.unwrap(), None);
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[rocket::async_test]
+    async fn v1_malformed() {
+        for header in [
+            &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n"[..],
+            b"PROXY TCP4 192.168.0.300 192.168.0.11 56324 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 65536 443\r\n",
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 \xff 443\r\n",
+        ] {
+            let (peer, _) = parse(header).await;
+            assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn v1_oversized() {
+        let mut header = b"PROXY TCP4 ".to_vec();
+        header.extend([b'1'; 200]);
+        header.extend(b"\r\n");
+        let (peer, _) = parse(&header).await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[rocket::async_test]
+    async fn v1_truncated() {
+        let (peer, _) = parse(b"PROXY TCP4 192.168.0.1").await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[rocket::async_test]
+    async fn v2_tcp4() {
+        let mut header = v2(
+            0x21,
+            0x11,
+            &[192, 168, 0, 1, 192, 168, 0, 11, 0xDC, 0x04, 0x01, 0xBB],
+        );
+        header.extend(b"GET /");
+        let (peer, rest) = parse(&header).await;
+        assert_eq!(peer.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[rocket::async_test]
+    async fn v2_tcp6_with_tlvs() {
+        let mut payload = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        payload.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend([0x0F, 0xA0, 0x01, 0xBB]);
+        // a NOOP TLV past the addresses
+        payload.extend([0x04, 0x00, 0x01, 0x00]);
+        let (peer, rest) = parse(&v2(0x21, 0x21, &payload)).await;
+        assert_eq!(peer.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn v2_local_and_unspec() {
+        let (peer, _) = parse(&v2(0x20, 0x00, &[])).await;
+        assert_eq!(peer.unwrap(), None);
+        let (peer, _) = parse(&v2(0x21, 0x00, &[])).await;
+        assert_eq!(peer.unwrap(), None);
+        // addresses shorter than their family
+        let (peer, _) = parse(&v2(0x21, 0x11, &[192, 168, 0, 1])).await;
+        assert_eq!(peer.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    async fn v2_unsupported_version() {
+        let (peer, _) = parse(&v2(0x11, 0x11, &[0; 12])).await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[rocket::async_test]
+    async fn v2_truncated() {
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header.truncate(header.len() - 4);
+        let (peer, _) = parse(&header).await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let (peer, _) = parse(&V2_SIGNATURE[..8]).await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[rocket::async_test]
+    async fn v2_largest_payload() {
+        let mut payload = vec![192, 168, 0, 1, 192, 168, 0, 11, 0xDC, 0x04, 0x01, 0xBB];
+        payload.resize(u16::MAX as usize, 0);
+        let (peer, rest) = parse(&v2(0x21, 0x11, &payload)).await;
+        assert_eq!(peer.unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn missing_header() {
+        let (peer, _) = parse(b"GET / HTTP/1.1\r\nHost: x\r\n").await;
+        assert_eq!(peer.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,608 @@
+use chrono::{DateTime, Utc};
+use openssl::{pkey::PKey, x509::X509};
+use rocket::{
+    fairing::AdHoc,
+    serde::json::Value,
+    tokio::{
+        self,
+        signal::unix::{signal, SignalKind},
+    },
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs, future,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use super::{
+    config::{drop_in_files, ConfigSources, Settings},
+    health::Health,
+    tls::{certified_key, CertificateResolver},
+};
+use crate::{
+    error::Error,
+    models::endpoint::EndpointHandler,
+    secure::ip::{ClientIpResolver, IpBlocklist},
+    Result,
+};
+
+/// Settings applied without a restart; the changes of any other setting need one
+const RELOADABLE: [&str; 6] = [
+    "server.log_level",
+    "server.trusted_proxies",
+    "server.blocked_ips",
+    "rate_limit",
+    "ssl.cert_file",
+    "ssl.key_file",
+];
+/// The certificate & key read from the files, reloaded with the TLS listener
+const SSL_CERTIFICATE: &str = "ssl certificate";
+
+/// Outcome of a reload
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReloadStatus {
+    /// The new settings are in use
+    Applied,
+    /// Nothing changed
+    Unchanged,
+    /// The new settings are invalid, the previous ones are kept
+    Rejected,
+}
+
+/// Result of a reload of the settings
+#[derive(Serialize, Clone, Debug)]
+pub struct ReloadResult {
+    pub time: DateTime<Utc>,
+    /// SIGHUP, file change or admin request
+    pub trigger: String,
+    pub status: ReloadStatus,
+    /// Settings changed & applied
+    pub applied: Vec<String>,
+    /// Settings changed but only applied at the next restart
+    pub restart_required: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Reloads the settings from their sources, on SIGHUP, when a config file
+/// changes or on request, and applies the reloadable ones.
+///
+/// Cloning shares the current settings & the last result.
+#[derive(Clone)]
+pub struct Reloader {
+    sources: ConfigSources,
+    /// Settings the server started with
+    started: Arc<Settings>,
+    /// Settings of the last successful load
+    settings: Arc<Mutex<Settings>>,
+    last_result: Arc<Mutex<Option<ReloadResult>>>,
+    client_ip_resolver: ClientIpResolver,
+    blocklist: IpBlocklist,
+    endpoint_handler: EndpointHandler,
+    /// Certificate of the TLS listener & the readiness checking its expiry, None without TLS
+    certificate: Option<(CertificateResolver, Health)>,
+}
+
+/// Flatten the settings into `key.path => value`, the lists being values
+fn leaves(prefix: &str, value: Value, flattened: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                leaves(&path, value, flattened);
+            }
+        }
+        value => {
+            flattened.insert(prefix.to_string(), value);
+        }
+    }
+}
+
+fn flatten(settings: &Settings) -> BTreeMap<String, Value> {
+    let mut flattened = BTreeMap::new();
+    if let Ok(value) = rocket::serde::json::to_value(settings) {
+        leaves("", value, &mut flattened);
+    }
+    flattened
+}
+
+/// The secrets, redacted in the flattened settings
+fn secrets(settings: &Settings) -> [(&'static str, Option<&str>); 3] {
+    [
+        (
+            "server.secret_key",
+            Some(settings.server.secret_key.as_str()),
+        ),
+        (
+            "mongo_db.auth_pass",
+            Some(settings.mongo_db.auth_pass.as_str()),
+        ),
+        ("mailer.smtp_pass", settings.mailer.smtp_pass.as_deref()),
+    ]
+}
+
+/// Keys of the settings whose value differs; the secrets are compared on their raw values
+fn changed_keys(before: &Settings, after: &Settings) -> Vec<String> {
+    let (flat_before, flat_after) = (flatten(before), flatten(after));
+    let mut changed: Vec<String> = flat_after
+        .iter()
+        .filter(|(key, value)| flat_before.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    changed.extend(
+        flat_before
+            .into_keys()
+            .filter(|key| !flat_after.contains_key(key)),
+    );
+    for ((key, before), (_, after)) in secrets(before).into_iter().zip(secrets(after)) {
+        if before != after && !changed.iter().any(|change| change == key) {
+            changed.push(key.to_string());
+        }
+    }
+    changed
+}
+
+/// The reloadable setting the key belongs to, if any
+fn reloadable(key: &str) -> Option<&'static str> {
+    RELOADABLE
+        .into_iter()
+        .find(|reloadable| key == *reloadable || key.starts_with(&format!("{}.", reloadable)))
+}
+
+/// Check the new settings could be started with, before using any of them
+fn validate(settings: &Settings) -> Result<()> {
+    if settings.mongo_db.db.is_empty() {
+        return Err(Error::DatabaseNotConfigured);
+    }
+    if settings.server.uses_default_secret_key() && !settings.server.dev_mode {
+        return Err(Error::ConfigurationError(
+            "server.secret_key is the built-in or sample one".into(),
+        ));
+    }
+    // the certificate & key must be readable and belong together
+    if let Some(ssl) = settings.ssl.as_ref().filter(|ssl| ssl.enabled) {
+        let cert = ssl
+            .pem_certificate
+            .as_deref()
+            .and_then(|pem| X509::from_pem(pem).ok())
+            .ok_or(Error::SslCertificateError)?;
+        let key = ssl
+            .pem_private_key
+            .as_deref()
+            .and_then(|pem| PKey::private_key_from_pem(pem).ok())
+            .ok_or(Error::SslCertificateError)?;
+        let matching = cert
+            .public_key()
+            .map(|public_key| public_key.public_eq(&key))
+            .unwrap_or(false);
+        if !matching {
+            return Err(Error::ConfigurationError(
+                "the TLS private key does not match the certificate".into(),
+            ));
+        }
+        // & usable by the TLS listener
+        if let (Some(cert), Some(key)) = (&ssl.pem_certificate, &ssl.pem_private_key) {
+            certified_key(cert, key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the certificate or key files read for the settings differ;
+/// a self-signed certificate is generated anew at each load
+fn certificate_changed(current: &Settings, new: &Settings) -> bool {
+    match (&current.ssl, &new.ssl) {
+        (Some(current), Some(new)) if new.enabled && !new.generate_self_signed => {
+            current.pem_certificate != new.pem_certificate
+                || current.pem_private_key != new.pem_private_key
+        }
+        _ => false,
+    }
+}
+
+/// Whether the client CA files read for the settings differ
+fn client_ca_changed(current: &Settings, new: &Settings) -> bool {
+    match (&current.ssl, &new.ssl) {
+        (Some(current), Some(new)) if new.enabled => current.pem_client_ca != new.pem_client_ca,
+        _ => false,
+    }
+}
+
+impl Reloader {
+    pub fn new(
+        sources: ConfigSources,
+        settings: Settings,
+        client_ip_resolver: ClientIpResolver,
+        blocklist: IpBlocklist,
+        endpoint_handler: EndpointHandler,
+    ) -> Self {
+        Self {
+            sources,
+            started: Arc::new(settings.clone()),
+            settings: Arc::new(Mutex::new(settings)),
+            last_result: Arc::new(Mutex::new(None)),
+            client_ip_resolver,
+            blocklist,
+            endpoint_handler,
+            certificate: None,
+        }
+    }
+
+    /// Swap the certificate of the TLS listener when the certificate files change
+    pub fn with_certificate(mut self, resolver: CertificateResolver, health: Health) -> Self {
+        self.certificate = Some((resolver, health));
+        self
+    }
+
+    /// Result of the last reload, None before the first one
+    pub fn last_result(&self) -> Option<ReloadResult> {
+        self.last_result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Apply the reloadable settings that changed
+    fn apply(&self, new: &Settings, changed: &[String]) {
+        let is_changed = |key: &str| changed.iter().any(|change| change == key);
+        if is_changed("server.log_level") {
+            if let Some(level) = new.server.log_level {
+                log::set_max_level(level.into());
+            }
+        }
+        if is_changed("server.trusted_proxies") {
+            self.client_ip_resolver
+                .set_trusted_proxies(new.server.trusted_proxies.clone());
+        }
+        if is_changed("server.blocked_ips") {
+            self.blocklist.set(new.server.blocked_ips.clone());
+        }
+        if is_changed("rate_limit") {
+            let rate_limit = new.rate_limit.clone();
+            self.endpoint_handler.set_rate_limits(
+                rate_limit
+                    .costs
+                    .into_iter()
+                    .map(|cost| (cost.name, cost.method, cost.cost))
+                    .collect(),
+                rate_limit.default_throttle,
+            );
+        }
+        if is_changed(SSL_CERTIFICATE) {
+            self.set_certificate(new);
+        }
+    }
+
+    /// Present the new certificate to the next TLS handshakes & check its expiry
+    fn set_certificate(&self, new: &Settings) {
+        let pem = new.ssl.as_ref().and_then(|ssl| {
+            ssl.pem_certificate
+                .as_deref()
+                .zip(ssl.pem_private_key.as_deref())
+        });
+        if let (Some((resolver, health)), Some((cert, key))) = (&self.certificate, pem) {
+            if let Err(e) = resolver
+                .set(cert, key)
+                .and_then(|_| health.set_certificate(cert))
+            {
+                log::error!("could not use the new TLS certificate: {}", e);
+            }
+        }
+    }
+
+    /// Load the settings from their sources & apply the reloadable ones;
+    /// the current settings are kept when the new ones are invalid.
+    ///
+    /// Blocking: the config files are read & the certificates parsed.
+    pub fn reload(&self, trigger: &str) -> ReloadResult {
+        let loaded =
+            Settings::from_sources(&self.sources).and_then(|new| validate(&new).map(|_| new));
+        let mut result = ReloadResult {
+            time: Utc::now(),
+            trigger: trigger.to_string(),
+            status: ReloadStatus::Unchanged,
+            applied: vec![],
+            restart_required: vec![],
+            error: None,
+        };
+
+        match loaded {
+            Ok(new) => {
+                let mut current = self.settings.lock().unwrap_or_else(PoisonError::into_inner);
+                let changed = changed_keys(&current, &new);
+                let certificate = certificate_changed(&current, &new);
+                if !changed.is_empty() || certificate || client_ca_changed(&current, &new) {
+                    result.status = ReloadStatus::Applied;
+                }
+
+                // applied now when changed since the last load
+                // the certificate files are only reloaded by the TLS listener
+                let applies = |key: &str| {
+                    reloadable(key).filter(|reloadable| {
+                        self.certificate.is_some() || !reloadable.starts_with("ssl.")
+                    })
+                };
+                result.applied = changed
+                    .iter()
+                    .filter_map(|key| applies(key))
+                    .map(String::from)
+                    .collect();
+                // removing the log level leaves the current one until the restart
+                if new.server.log_level.is_none() {
+                    result.applied.retain(|key| key != "server.log_level");
+                }
+                if certificate && self.certificate.is_some() {
+                    result.applied.push(SSL_CERTIFICATE.into());
+                }
+                result.applied.sort();
+                result.applied.dedup();
+                self.apply(&new, &result.applied);
+
+                // pending until the restart when changed since the start
+                result.restart_required = changed_keys(&self.started, &new)
+                    .into_iter()
+                    .filter(|key| {
+                        applies(key).is_none()
+                            || (key == "server.log_level" && new.server.log_level.is_none())
+                    })
+                    .collect();
+                if self.certificate.is_none() && certificate_changed(&self.started, &new) {
+                    result.restart_required.push(SSL_CERTIFICATE.into());
+                }
+                if client_ca_changed(&self.started, &new) {
+                    result.restart_required.push("ssl client CA file".into());
+                }
+                *current = new;
+
+                if !result.applied.is_empty() {
+                    log::warn!(
+                        "settings reloaded on {}, applied: {}",
+                        trigger,
+                        result.applied.join(", ")
+                    );
+                }
+                if !result.restart_required.is_empty() {
+                    log::warn!(
+                        "settings reloaded on {}, pending a restart: {}",
+                        trigger,
+                        result.restart_required.join(", ")
+                    );
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "settings reload on {} rejected, the current settings are kept: {}",
+                    trigger,
+                    e
+                );
+                result.status = ReloadStatus::Rejected;
+                result.error = Some(e.to_string());
+            }
+        }
+
+        *self
+            .last_result
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(result.clone());
+        result
+    }
+
+    /// Modification time of each config file, to notice the changes
+    fn file_stamps(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let mut files: Vec<PathBuf> = self.sources.file.iter().cloned().collect();
+        if let Some(dir) = &self.sources.drop_in_dir {
+            files.extend(drop_in_files(dir).unwrap_or_default());
+        }
+        files
+            .into_iter()
+            .map(|file| {
+                let modified = fs::metadata(&file).and_then(|m| m.modified()).ok();
+                (file, modified)
+            })
+            .collect()
+    }
+
+    /// Fairing reloading the settings on SIGHUP and, when `watch` is set,
+    /// when the config files change, checked every `poll_interval`
+    pub fn fairing(self, watch: bool, poll_interval: Duration) -> AdHoc {
+        AdHoc::on_liftoff("Settings reload", move |_| {
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    let mut hangup = signal(SignalKind::hangup())
+                        .map_err(|e| log::warn!("could not listen to SIGHUP: {}", e))
+                        .ok();
+                    let mut interval = tokio::time::interval(poll_interval);
+                    let mut stamps = self.file_stamps();
+                    loop {
+                        let trigger = tokio::select! {
+                            _ = async {
+                                match hangup.as_mut() {
+                                    Some(hangup) => hangup.recv().await,
+                                    None => future::pending().await,
+                                }
+                            } => "SIGHUP",
+                            _ = interval.tick(), if watch => {
+                                let current = self.file_stamps();
+                                if current == stamps {
+                                    continue;
+                                }
+                                "file change"
+                            }
+                        };
+                        stamps = self.file_stamps();
+
+                        let reloader = self.clone();
+                        if let Err(e) =
+                            tokio::task::spawn_blocking(move || reloader.reload(trigger)).await
+                        {
+                            log::error!("could not reload the settings: {}", e);
+                        }
+                    }
+                });
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{secure::cert::generate_cert, server::config::SslConfig};
+    use std::net::IpAddr;
+
+    /// Settings serving TLS with the given pem certificate & key
+    fn with_certificate(cert: Vec<u8>, key: Vec<u8>) -> Settings {
+        let mut settings = Settings::default();
+        settings
+            .mongo_db
+            .db
+            .insert("user_db".into(), "users".into());
+        settings.server.secret_key = "not the default".into();
+        let mut ssl = SslConfig::default();
+        ssl.enabled = true;
+        ssl.generate_self_signed = false;
+        ssl.pem_certificate = Some(cert);
+        ssl.pem_private_key = Some(key);
+        settings.ssl = Some(ssl);
+        settings
+    }
+
+    #[test]
+    fn rejects_a_key_not_matching_the_certificate() {
+        let (first, second) = (generate_cert(), generate_cert());
+        let cert = first.x509_certificate.to_pem().unwrap();
+        let key = first.private_key.private_key_to_pem_pkcs8().unwrap();
+        let other_key = second.private_key.private_key_to_pem_pkcs8().unwrap();
+
+        assert!(validate(&with_certificate(cert.clone(), key)).is_ok());
+        assert!(matches!(
+            validate(&with_certificate(cert, other_key)),
+            Err(Error::ConfigurationError(_))
+        ));
+        assert!(validate(&with_certificate(b"not a certificate".to_vec(), vec![])).is_err());
+    }
+
+    #[test]
+    fn keeps_the_settings_when_the_new_ones_are_invalid() {
+        let dir = std::env::temp_dir().join(format!("rocketapi-reload-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (generate_cert(), generate_cert());
+        let write = |name: &str, pem: Vec<u8>| {
+            let path = dir.join(name);
+            fs::write(&path, pem).unwrap();
+            path.display().to_string()
+        };
+        let cert = write("cert.pem", first.x509_certificate.to_pem().unwrap());
+        let key = write(
+            "key.pem",
+            first.private_key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        let other_key = write(
+            "other_key.pem",
+            second.private_key.private_key_to_pem_pkcs8().unwrap(),
+        );
+        let config = dir.join("config.yml");
+        let write_config = |blocked: &str, key: &str| {
+            let yaml = format!(
+                "server:\n  secret_key: not the default\n  blocked_ips: [{}]\n\
+                 mongo_db:\n  db:\n    user_db: users\n\
+                 ssl:\n  enabled: yes\n  generate_self_signed: no\n  \
+                 cert_file: {}\n  key_file: {}\n",
+                blocked, cert, key
+            );
+            fs::write(&config, yaml).unwrap();
+        };
+
+        write_config("203.0.113.0/24", &key);
+        let sources = ConfigSources {
+            file: Some(config.clone()),
+            ..ConfigSources::default()
+        };
+        let settings = Settings::from_sources(&sources).unwrap();
+        let blocklist = IpBlocklist::new(settings.server.blocked_ips.clone());
+        let reloader = Reloader::new(
+            sources,
+            settings,
+            ClientIpResolver::default(),
+            blocklist.clone(),
+            EndpointHandler::default(),
+        );
+        let (blocked, unblocked): (IpAddr, IpAddr) = (
+            "203.0.113.7".parse().unwrap(),
+            "198.51.100.7".parse().unwrap(),
+        );
+
+        // the key of another certificate: nothing is applied
+        write_config("198.51.100.0/24", &other_key);
+        let result = reloader.reload("test");
+        assert_eq!(result.status, ReloadStatus::Rejected);
+        assert!(result.error.is_some());
+        assert!(result.applied.is_empty());
+        assert!(blocklist.is_blocked(&blocked) && !blocklist.is_blocked(&unblocked));
+        let current = reloader.settings.lock().unwrap().clone();
+        assert_eq!(
+            current.server.blocked_ips,
+            ["203.0.113.0/24".parse().unwrap()]
+        );
+
+        // fixed: the change since the kept settings is applied
+        write_config("198.51.100.0/24", &key);
+        let result = reloader.reload("test");
+        assert_eq!(result.status, ReloadStatus::Applied);
+        assert_eq!(result.applied, ["server.blocked_ips"]);
+        assert!(!blocklist.is_blocked(&blocked) && blocklist.is_blocked(&unblocked));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn classifies_the_reloadable_settings() {
+        assert_eq!(reloadable("rate_limit"), Some("rate_limit"));
+        assert_eq!(
+            reloadable("rate_limit.default_throttle"),
+            Some("rate_limit")
+        );
+        assert_eq!(reloadable("rate_limit.costs"), Some("rate_limit"));
+        assert_eq!(reloadable("server.blocked_ips"), Some("server.blocked_ips"));
+        assert_eq!(
+            reloadable("server.trusted_proxies"),
+            Some("server.trusted_proxies")
+        );
+        // only the whole key or its children
+        assert_eq!(reloadable("rate_limiter"), None);
+        assert_eq!(reloadable("server.blocked_ips_file"), None);
+        assert_eq!(reloadable("server.port"), None);
+        assert_eq!(reloadable("ssl.client_ca_file"), None);
+    }
+
+    #[test]
+    fn reports_the_rotated_secrets() {
+        let before = Settings::default();
+        let mut after = before.clone();
+        after.server.secret_key = "rotated".into();
+        after.mongo_db.auth_pass = "rotated".into();
+        after.mailer.smtp_pass = Some("rotated".into());
+
+        let mut changed = changed_keys(&before, &after);
+        changed.sort();
+        assert_eq!(
+            changed,
+            [
+                "mailer.smtp_pass",
+                "mongo_db.auth_pass",
+                "server.secret_key"
+            ]
+        );
+        // none of them applies without a restart
+        assert!(changed.iter().all(|key| reloadable(key).is_none()));
+        assert!(changed_keys(&after, &after.clone()).is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+use opentelemetry::trace::FutureExt;
+use rocket::{
+    catcher::{self, Catcher},
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+    route::{self, Route},
+    tokio, Data, Request, Response,
+};
+
+use super::telemetry::request_context;
+
+/// Header carrying the request id, from the caller or else generated
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest request id accepted from the caller
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    /// Id of the request handled by the current task
+    static CURRENT_REQUEST_ID: String;
+}
+
+/// Id of the request, shared by its logs, error bodies & response header
+pub struct RequestId(pub String);
+
+/// Only ids that are safe to log & echo are accepted from the caller
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request
+            .local_cache(|| {
+                RequestId(match request.headers().get_one(REQUEST_ID_HEADER) {
+                    Some(id) if is_valid_request_id(id) => id.to_string(),
+                    _ => uuid::Uuid::new_v4().as_simple().to_string(),
+                })
+            })
+            .0
+    }
+}
+
+/// Id of the request handled by the current task, within the routes & catchers
+pub fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Fairing echoing the request id in the response
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_raw_header(REQUEST_ID_HEADER, RequestId::of(request).to_string());
+    }
+}
+
+/// Route handler running the guards & the controller with the request id & trace context
+#[derive(Clone)]
+struct ScopedHandler(Box<dyn route::Handler>);
+
+#[rocket::async_trait]
+impl route::Handler for ScopedHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let handled = self
+            .0
+            .handle(request, data)
+            .with_context(request_context(request));
+        CURRENT_REQUEST_ID
+            .scope(RequestId::of(request).to_string(), handled)
+            .await
+    }
+}
+
+/// Catcher running with the request id & trace context
+#[derive(Clone)]
+struct ScopedCatcher(Box<dyn catcher::Handler>);
+
+#[rocket::async_trait]
+impl catcher::Handler for ScopedCatcher {
+    async fn handle<'r>(&self, status: Status, request: &'r Request<'_>) -> catcher::Result<'r> {
+        let handled = self
+            .0
+            .handle(status, request)
+            .with_context(request_context(request));
+        CURRENT_REQUEST_ID
+            .scope(RequestId::of(request).to_string(), handled)
+            .await
+    }
+}
+
+/// Run the routes with their request id & within their request span
+pub fn scoped(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(ScopedHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// Run the catchers with their request id & within their request span
+pub fn scoped_catchers(catchers: Vec<Catcher>) -> Vec<Catcher> {
+    catchers
+        .into_iter()
+        .map(|mut catcher| {
+            catcher.handler = Box::new(ScopedCatcher(catcher.handler));
+            catcher
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::Header, local::blocking::Client};
+
+    /// Id the route ran with
+    #[get("/")]
+    fn request_id() -> String {
+        current().unwrap_or_default()
+    }
+
+    /// Request id echoed & seen by the route for the given header value
+    fn send(id: &str) -> (String, String) {
+        let rocket = rocket::build()
+            .attach(RequestIdFairing)
+            .mount("/", scoped(routes![request_id]));
+        let client = Client::tracked(rocket).unwrap();
+        let response = client
+            .get("/")
+            .header(Header::new(REQUEST_ID_HEADER, id.to_string()))
+            .dispatch();
+        let echoed = response
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .unwrap()
+            .to_string();
+        (echoed, response.into_string().unwrap())
+    }
+
+    #[test]
+    fn keeps_a_valid_request_id() {
+        let (echoed, seen) = send("req-42_a.b:c");
+        assert_eq!(echoed, "req-42_a.b:c");
+        assert_eq!(seen, echoed);
+    }
+
+    #[test]
+    fn replaces_an_invalid_request_id() {
+        let oversized = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for id in [
+            oversized.as_str(),
+            "req\u{7}42",
+            "req\t42",
+            "req 42",
+            "req42\r\nSet-Cookie: a=b",
+            "req-é",
+            "",
+        ] {
+            let (echoed, seen) = send(id);
+            assert_ne!(echoed, id);
+            // a generated uuid, the same for the logs & the response
+            assert!(uuid::Uuid::parse_str(&echoed).is_ok(), "{:?}", echoed);
+            assert_eq!(seen, echoed);
+        }
+        assert!(is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN)));
+    }
+}
@@ -0,0 +1,136 @@
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        self,
+        signal::unix::{signal, SignalKind},
+        time::{sleep, timeout},
+    },
+    Ignite, Rocket,
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use super::{access_log::AccessLog, config::ShutdownConfig, systemd};
+use crate::db::{usage::UsageTracker, MongodbBackend};
+
+/// Shutdown on SIGTERM/SIGINT, after a drain period failing the readiness
+#[derive(Clone)]
+pub struct GracefulShutdown {
+    drain: Duration,
+    flush_timeout: Duration,
+    draining: Arc<AtomicBool>,
+}
+
+/// Wait for SIGTERM or SIGINT, returning its name
+async fn termination_signal() -> &'static str {
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => tokio::select! {
+            _ = sigterm.recv() => "SIGTERM",
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+        },
+        Err(e) => {
+            log::warn!("could not listen to SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            "SIGINT"
+        }
+    }
+}
+
+impl GracefulShutdown {
+    pub fn new(config: &ShutdownConfig) -> Self {
+        Self {
+            drain: Duration::from_secs(config.drain_secs),
+            flush_timeout: Duration::from_secs(config.flush_timeout_secs),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether a shutdown was requested; the server still serves the requests
+    /// until the end of the drain period
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Fairing waiting for the termination signal: the readiness fails at once,
+    /// and the server stops once the drain period is over or at a second signal
+    pub fn fairing(self) -> AdHoc {
+        AdHoc::on_liftoff("Graceful shutdown", move |rocket| {
+            let shutdown = rocket.shutdown();
+            Box::pin(async move {
+                tokio::spawn(async move {
+                    let received = termination_signal().await;
+                    self.draining.store(true, Ordering::Relaxed);
+                    systemd::notify("STOPPING=1");
+                    log::warn!(
+                        "received {}, draining the requests for {}s",
+                        received,
+                        self.drain.as_secs()
+                    );
+                    tokio::select! {
+                        _ = sleep(self.drain) => {}
+                        received = termination_signal() => {
+                            log::warn!("received {} again, shutting down now", received);
+                        }
+                    }
+                    shutdown.notify();
+                });
+            })
+        })
+    }
+
+    /// Write what the server still buffers & close the backend, once the server stopped
+    pub async fn flush(rocket: &Rocket<Ignite>) {
+        let flush_timeout = rocket
+            .state::<GracefulShutdown>()
+            .map(|shutdown| shutdown.flush_timeout)
+            .unwrap_or_default();
+
+        if let Some(backend) = rocket.state::<MongodbBackend>() {
+            // the last used time & request counts of the keys
+            if let Some(tracker) = rocket.state::<UsageTracker>() {
+                if timeout(flush_timeout, tracker.flush(backend))
+                    .await
+                    .is_err()
+                {
+                    log::warn!(
+                        "could not write the key usage within {}s",
+                        flush_timeout.as_secs()
+                    );
+                }
+                if tracker.pending_keys() > 0 {
+                    log::warn!(
+                        "the usage of {} keys since the last flush is lost",
+                        tracker.pending_keys()
+                    );
+                }
+            }
+            backend.close().await;
+        }
+
+        // the access log lines still queued
+        if let Some(access_log) = rocket.state::<AccessLog>().cloned() {
+            let close = tokio::task::spawn_blocking(move || access_log.close());
+            if timeout(flush_timeout, close).await.is_err() {
+                log::warn!(
+                    "could not write the access log within {}s",
+                    flush_timeout.as_secs()
+                );
+            }
+        }
+
+        // the spans not exported yet
+        if let Some(provider) = rocket.state::<SdkTracerProvider>().cloned() {
+            match tokio::task::spawn_blocking(move || provider.shutdown()).await {
+                Ok(Err(e)) => log::warn!("could not export the pending spans: {}", e),
+                Err(e) => log::warn!("could not export the pending spans: {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    error::{OTelSdkError, OTelSdkResult},
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, SpanData, SpanExporter},
+    Resource,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::HeaderMap,
+    serde::json::json,
+    Data, Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::config::TracingConfig;
+use crate::{error::Error, secure::ip::client_ip, Result};
+
+/// Name of the tracer of the server's spans
+const TRACER_NAME: &str = "rocketapi";
+/// W3C trace context headers
+const TRACE_CONTEXT_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+/// Where the spans are exported
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceExporter {
+    /// No spans are exported
+    #[default]
+    None,
+    /// OTLP over HTTP (protobuf), e.g. to a local collector
+    Otlp,
+    /// One JSON line per span appended to a file, for development & tests
+    File,
+}
+
+/// Exports the spans to a file, one JSON object per line
+#[derive(Debug)]
+struct FileExporter {
+    file: Mutex<File>,
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+}
+
+impl SpanExporter for FileExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        for span in batch {
+            let attributes: HashMap<&str, String> = span
+                .attributes
+                .iter()
+                .map(|attribute| (attribute.key.as_str(), attribute.value.to_string()))
+                .collect();
+            let status = match &span.status {
+                Status::Unset => None,
+                Status::Ok => Some("ok".to_string()),
+                Status::Error { description } => Some(format!("error: {}", description)),
+            };
+            let line = json!({
+                "trace_id": span.span_context.trace_id().to_string(),
+                "span_id": span.span_context.span_id().to_string(),
+                "parent_span_id": span.parent_span_id.to_string(),
+                "name": span.name,
+                "kind": format!("{:?}", span.span_kind),
+                "start_time_unix_nano": unix_nanos(span.start_time),
+                "end_time_unix_nano": unix_nanos(span.end_time),
+                "attributes": attributes,
+                "status": status,
+            });
+            writeln!(file, "{}", line).map_err(|e| OTelSdkError::InternalFailure(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Set up the export of the spans & the W3C trace context propagation.
+///
+/// Returns the tracer provider to flush at shutdown, None when tracing is disabled.
+pub fn init(config: &TracingConfig) -> Result<Option<SdkTracerProvider>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let resource = Resource::builder()
+        .with_service_name(config.service_name.clone())
+        .build();
+    let provider = match config.exporter {
+        TraceExporter::None => return Ok(None),
+        TraceExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&config.otlp_endpoint)
+                .build()
+                .map_err(|e| Error::ConfigurationError(format!("otlp exporter: {}", e)))?;
+            SdkTracerProvider::builder().with_batch_exporter(exporter)
+        }
+        TraceExporter::File => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.file)?;
+            let exporter = FileExporter {
+                file: Mutex::new(file),
+            };
+            SdkTracerProvider::builder().with_batch_exporter(exporter)
+        }
+    }
+    .with_resource(resource)
+    .build();
+
+    global::set_tracer_provider(provider.clone());
+    Ok(Some(provider))
+}
+
+/// Start a span, child of the span of the current context
+pub fn start_span(name: &'static str, attributes: Vec<KeyValue>) -> Context {
+    let tracer = global::tracer(TRACER_NAME);
+    let parent = Context::current();
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    parent.with_span(span)
+}
+
+/// End the span of the context, marked as failed with the error if any
+pub fn end_span<T>(context: &Context, result: &std::result::Result<T, Error>) {
+    let span = context.span();
+    if let Err(e) = result {
+        span.set_status(Status::error(e.to_string()));
+    }
+    span.end();
+}
+
+/// Reads the trace context of the request headers
+struct HeaderExtractor<'a>(&'a HeaderMap<'a>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    // only the W3C trace context propagator is installed
+    fn keys(&self) -> Vec<&str> {
+        TRACE_CONTEXT_HEADERS
+            .into_iter()
+            .filter(|key| self.0.contains(*key))
+            .collect()
+    }
+}
+
+/// Context of the server span of the request
+struct RequestTrace(Context);
+
+/// Trace context of the request, empty until the request span is started
+pub(super) fn request_context(request: &Request<'_>) -> Context {
+    request
+        .local_cache(|| RequestTrace(Context::new()))
+        .0
+        .clone()
+}
+
+/// Fairing starting a server span per request, child of the `traceparent` of the caller
+pub struct TracingFairing;
+
+#[rocket::async_trait]
+impl Fairing for TracingFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "OpenTelemetry tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        let tracer = global::tracer(TRACER_NAME);
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", request.method().as_str()),
+            KeyValue::new("url.path", request.uri().path().to_string()),
+        ];
+        if let Some(ip) = client_ip(request) {
+            attributes.push(KeyValue::new("client.address", ip.to_string()));
+        }
+        let span = tracer
+            .span_builder(request.method().as_str())
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(&tracer, &parent);
+        request.local_cache(|| RequestTrace(parent.with_span(span)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let context = request_context(request);
+        let span = context.span();
+        // named after the route, not the path, to keep the span names bounded
+        if let Some(route) = request.route() {
+            span.update_name(format!("{} {}", request.method(), route.uri.as_str()));
+            span.set_attribute(KeyValue::new("http.route", route.uri.as_str().to_string()));
+        }
+        let status = response.status().code;
+        span.set_attribute(KeyValue::new("http.response.status_code", status as i64));
+        if status >= 500 {
+            span.set_status(Status::error(response.status().to_string()));
+        }
+        span.end();
+    }
+}
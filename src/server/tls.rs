@@ -0,0 +1,310 @@
+use openssl::{pkey::PKey, x509::X509};
+use rocket::{
+    fairing::AdHoc,
+    tokio::{
+        self, io,
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    },
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::Duration,
+};
+use tokio_rustls::{
+    rustls::{
+        server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+            NoClientAuth, ResolvesServerCert,
+        },
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+use super::{
+    config::SslConfig,
+    proxy_protocol::{read_peer, ProxiedPeers},
+};
+use crate::{error::Error, secure::ip::ClientIpResolver, Result};
+
+/// Time given to the client to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate & key presented by the TLS listener, swapped when the settings are reloaded.
+///
+/// Cloning shares the certificate.
+#[derive(Clone)]
+pub struct CertificateResolver(Arc<RwLock<Arc<CertifiedKey>>>);
+
+impl std::fmt::Debug for CertificateResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateResolver")
+            .finish_non_exhaustive()
+    }
+}
+
+/// The pem certificate chain & private key, as used by rustls
+pub fn certified_key(pem_certificate: &[u8], pem_private_key: &[u8]) -> Result<CertifiedKey> {
+    let chain = X509::stack_from_pem(pem_certificate)
+        .and_then(|chain| {
+            chain
+                .iter()
+                .map(|cert| cert.to_der().map(Certificate))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        })
+        .map_err(|_| Error::SslCertificateError)?;
+    if chain.is_empty() {
+        return Err(Error::SslCertificateError);
+    }
+    let key = PKey::private_key_from_pem(pem_private_key)
+        .and_then(|key| key.private_key_to_pkcs8())
+        .map_err(|_| Error::SslCertificateError)?;
+    let key = sign::any_supported_type(&PrivateKey(key)).map_err(|e| {
+        Error::ConfigurationError(format!("the TLS private key is not supported: {}", e))
+    })?;
+    Ok(CertifiedKey::new(chain, key))
+}
+
+impl CertificateResolver {
+    pub fn new(pem_certificate: &[u8], pem_private_key: &[u8]) -> Result<Self> {
+        let key = certified_key(pem_certificate, pem_private_key)?;
+        Ok(Self(Arc::new(RwLock::new(Arc::new(key)))))
+    }
+
+    /// Present this certificate to the next handshakes
+    pub fn set(&self, pem_certificate: &[u8], pem_private_key: &[u8]) -> Result<()> {
+        let key = certified_key(pem_certificate, pem_private_key)?;
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.0
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        )
+    }
+}
+
+/// Client certificate chains (DER) of the connections relayed to the Rocket listener,
+/// keyed by the local address of the relaying connection.
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertificates(Arc<Mutex<HashMap<SocketAddr, Vec<Vec<u8>>>>>);
+
+impl ClientCertificates {
+    /// Get the certificate chain of a relayed connection, the client's first
+    pub fn get(&self, relay: &SocketAddr) -> Option<Vec<Vec<u8>>> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(relay)
+            .cloned()
+    }
+
+    fn insert(&self, relay: SocketAddr, chain: Vec<Vec<u8>>) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(relay, chain);
+    }
+
+    fn remove(&self, relay: &SocketAddr) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(relay);
+    }
+}
+
+/// Public listener terminating TLS, PROXY protocol header first when enabled
+/// (from the trusted proxies only).
+///
+/// The connections are relayed to the Rocket server listening on the loopback
+/// interface; the certificate comes from a [`CertificateResolver`] so a renewed
+/// one is used without a restart.
+pub struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    proxy_protocol: bool,
+    peers: ProxiedPeers,
+    certificates: ClientCertificates,
+}
+
+impl TlsListener {
+    pub async fn bind(
+        addr: SocketAddr,
+        ssl: &SslConfig,
+        resolver: CertificateResolver,
+        proxy_protocol: bool,
+    ) -> Result<Self> {
+        //! Bind the public address of the server
+        //!
+        //! ## Example usage
+        //! ```ignore
+        //! let resolver = CertificateResolver::new(&pem_certificate, &pem_private_key)?;
+        //! TlsListener::bind("0.0.0.0:8443".parse().unwrap(), &ssl_cfg, resolver, false).await?;
+        //! ```
+        let client_auth = match &ssl.pem_client_ca {
+            Some(pem) => {
+                let mut roots = RootCertStore::empty();
+                for cert in X509::stack_from_pem(pem).map_err(|_| Error::SslCertificateError)? {
+                    let der = cert.to_der().map_err(|_| Error::SslCertificateError)?;
+                    roots
+                        .add(&Certificate(der))
+                        .map_err(|_| Error::SslCertificateError)?;
+                }
+                if ssl.client_cert_mandatory {
+                    AllowAnyAuthenticatedClient::new(roots)
+                } else {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                }
+            }
+            None => NoClientAuth::new(),
+        };
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_auth)
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            proxy_protocol,
+            peers: ProxiedPeers::default(),
+            certificates: ClientCertificates::default(),
+        })
+    }
+
+    /// Real peers of the relayed connections
+    pub fn peers(&self) -> ProxiedPeers {
+        self.peers.clone()
+    }
+
+    /// Client certificates of the relayed connections
+    pub fn certificates(&self) -> ClientCertificates {
+        self.certificates.clone()
+    }
+
+    /// Fairing starting the relay once Rocket is listening, the PROXY header
+    /// being only accepted from the proxies trusted by the resolver
+    pub fn fairing(self, trusted: ClientIpResolver) -> AdHoc {
+        AdHoc::on_liftoff("TLS listener", move |rocket| {
+            Box::pin(async move {
+                let upstream = SocketAddr::new(rocket.config().address, rocket.config().port);
+                tokio::spawn(self.serve(upstream, trusted));
+            })
+        })
+    }
+
+    async fn serve(self, upstream: SocketAddr, trusted: ClientIpResolver) {
+        loop {
+            let (stream, remote) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::warn!("TLS: accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            let connection = Relay {
+                acceptor: self.acceptor.clone(),
+                proxy_protocol: self.proxy_protocol,
+                trusted: trusted.clone(),
+                peers: self.peers.clone(),
+                certificates: self.certificates.clone(),
+            };
+            tokio::spawn(async move {
+                if let Err(e) = connection.relay(stream, remote, upstream).await {
+                    log::warn!("TLS: connection from {} dropped: {}", remote, e);
+                }
+            });
+        }
+    }
+}
+
+/// What a relayed connection needs from the listener
+struct Relay {
+    acceptor: TlsAcceptor,
+    proxy_protocol: bool,
+    trusted: ClientIpResolver,
+    peers: ProxiedPeers,
+    certificates: ClientCertificates,
+}
+
+impl Relay {
+    async fn relay(
+        self,
+        mut stream: TcpStream,
+        remote: SocketAddr,
+        upstream: SocketAddr,
+    ) -> io::Result<()> {
+        let peer = if self.proxy_protocol {
+            read_peer(&mut stream, remote, &self.trusted).await?
+        } else {
+            remote
+        };
+        let mut stream = timeout(HANDSHAKE_TIMEOUT, self.acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no TLS handshake"))??;
+        let chain = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|chain| chain.iter().map(|cert| cert.0.clone()).collect::<Vec<_>>());
+
+        let mut upstream = TcpStream::connect(upstream).await?;
+        upstream.set_nodelay(true)?;
+        let relay = upstream.local_addr()?;
+
+        // registered before any byte is relayed, so the request can always see them
+        self.peers.insert(relay, peer);
+        if let Some(chain) = chain {
+            self.certificates.insert(relay, chain);
+        }
+        // errors past this point are the usual resets from either side
+        let _ = io::copy_bidirectional(&mut stream, &mut upstream).await;
+        self.peers.remove(&relay);
+        self.certificates.remove(&relay);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secure::cert::{generate_cert, ResultCert};
+
+    #[test]
+    fn swaps_the_certificate() {
+        let first = generate_cert();
+        let second = generate_cert();
+        let pem = |certs: &ResultCert| {
+            (
+                certs.x509_certificate.to_pem().unwrap(),
+                certs.private_key.private_key_to_pem_pkcs8().unwrap(),
+            )
+        };
+        let (cert, key) = pem(&first);
+        let resolver = CertificateResolver::new(&cert, &key).unwrap();
+        let presented = || resolver.0.read().unwrap().cert[0].0.clone();
+        assert_eq!(presented(), first.x509_certificate.to_der().unwrap());
+
+        let (cert, key) = pem(&second);
+        resolver.set(&cert, &key).unwrap();
+        assert_eq!(presented(), second.x509_certificate.to_der().unwrap());
+
+        // an unreadable pair leaves the current one
+        assert!(resolver.set(b"not a certificate", &key).is_err());
+        assert_eq!(presented(), second.x509_certificate.to_der().unwrap());
+    }
+}